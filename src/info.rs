@@ -0,0 +1,610 @@
+use std::fmt::Write;
+
+use jpeg_rust::jpeg::color::ColorSpace;
+use jpeg_rust::jpeg::decoder::ZIGZAG_INDICES;
+use jpeg_rust::jpeg::jpeg::*;
+use jpeg_rust::jpeg::probe;
+use jpeg_rust::jpeg::segments::{self, SegmentKind};
+
+/// A value of the report, which is printed either as text or as JSON.
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Value>),
+    Object(Vec<(&'static str, Value)>),
+}
+
+impl Value {
+    fn is_scalar(&self) -> bool {
+        !matches!(*self, Value::List(_) | Value::Object(_))
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(*self, Value::Int(_) | Value::Float(_))
+    }
+
+    fn write_scalar(&self, out: &mut String) {
+        match *self {
+            Value::Int(n) => write!(out, "{}", n).unwrap(),
+            Value::Float(x) => write!(out, "{}", x).unwrap(),
+            Value::Str(ref s) => out.push_str(s),
+            _ => {}
+        }
+    }
+
+    /// Indented lines of `key: value`. Lists of numbers are on one line,
+    /// lists of lists are rows of a table, and strings and objects with only
+    /// scalars in a list are on one line each.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        if let Value::Object(ref fields) = *self {
+            write_text_fields(fields, 0, &mut out);
+        }
+        out
+    }
+
+    /// The value as JSON, on one line.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match *self {
+            // JSON has no NaN or infinity.
+            Value::Float(x) if !x.is_finite() => out.push_str("null"),
+            Value::Str(ref s) => write_json_string(s, out),
+            Value::List(ref values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    value.write_json(out);
+                }
+                out.push(']');
+            }
+            Value::Object(ref fields) => {
+                out.push('{');
+                for (i, &(key, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_json_string(key, out);
+                    out.push(':');
+                    value.write_json(out);
+                }
+                out.push('}');
+            }
+            _ => self.write_scalar(out),
+        }
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_text_fields(fields: &[(&'static str, Value)], depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    for &(key, ref value) in fields {
+        write!(out, "{}{}:", indent, key).unwrap();
+        match *value {
+            Value::Object(ref fields) => {
+                out.push('\n');
+                write_text_fields(fields, depth + 1, out);
+            }
+            Value::List(ref values) if values.iter().all(Value::is_number) => {
+                for value in values {
+                    out.push(' ');
+                    value.write_scalar(out);
+                }
+                out.push('\n');
+            }
+            Value::List(ref values) => {
+                out.push('\n');
+                for value in values {
+                    write_text_item(value, depth + 1, out);
+                }
+            }
+            ref value => {
+                out.push(' ');
+                value.write_scalar(out);
+                out.push('\n');
+            }
+        }
+    }
+}
+
+fn write_text_item(value: &Value, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    match *value {
+        Value::List(ref values) => {
+            out.push_str(&indent);
+            for value in values {
+                let mut scalar = String::new();
+                value.write_scalar(&mut scalar);
+                write!(out, "{:>4}", scalar).unwrap();
+            }
+            out.push('\n');
+        }
+        Value::Object(ref fields) if fields.iter().all(|(_, value)| value.is_scalar()) => {
+            out.push_str(&indent);
+            for (i, &(key, ref value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write!(out, "{}: ", key).unwrap();
+                value.write_scalar(out);
+            }
+            out.push('\n');
+        }
+        Value::Object(ref fields) => {
+            // The first field goes on the line of the dash.
+            let mut item = String::new();
+            write_text_fields(fields, depth + 1, &mut item);
+            write!(out, "{}- {}", indent, &item[indent.len() + 2..]).unwrap();
+        }
+        ref value => {
+            // Each line of a string is indented, and a final line break is left out.
+            let mut scalar = String::new();
+            value.write_scalar(&mut scalar);
+            for line in scalar.trim_end_matches(['\n', '\r']).split('\n') {
+                writeln!(out, "{}{}", indent, line.trim_end_matches('\r')).unwrap();
+            }
+        }
+    }
+}
+
+fn string(s: &str) -> Value {
+    Value::Str(s.to_string())
+}
+
+fn int<T: Into<i64>>(n: T) -> Value {
+    Value::Int(n.into())
+}
+
+fn pair<T: Into<i64>>(a: T, b: T) -> Value {
+    Value::List(vec![int(a), int(b)])
+}
+
+/// The identifier at the start of an APPn payload, like `Exif` or
+/// `ICC_PROFILE`, if it is printable.
+fn identifier(payload: &[u8]) -> Option<String> {
+    let printable = |&&b: &&u8| b == b' ' || b.is_ascii_graphic();
+    let length = payload.iter().take(40).take_while(printable).count();
+    if length < 3 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&payload[..length]).into_owned())
+}
+
+fn segment_list(data: &[u8]) -> Value {
+    let mut list = Vec::new();
+    for segment in segments::segments(data) {
+        let segment = match segment {
+            Ok(segment) => segment,
+            Err(error) => {
+                list.push(Value::Object(vec![("error", Value::Str(error))]));
+                break;
+            }
+        };
+        let name = match segment.kind {
+            SegmentKind::Marker(marker) | SegmentKind::StandaloneMarker(marker) => {
+                segments::marker_name(marker)
+            }
+            SegmentKind::Fill => "fill".to_string(),
+            SegmentKind::EntropyCodedData => "entropy coded data".to_string(),
+            SegmentKind::Garbage => "garbage".to_string(),
+        };
+        let mut fields = vec![("name", Value::Str(name)),
+                              ("offset", Value::Int(segment.offset as i64)),
+                              ("length", Value::Int(segment.length as i64))];
+        if let Some(0xe0..=0xef) = segment.marker() {
+            if let Some(identifier) = identifier(segment.payload) {
+                fields.push(("identifier", Value::Str(identifier)));
+            }
+        }
+        list.push(Value::Object(fields));
+    }
+    Value::List(list)
+}
+
+fn frame_type_name(frame_type: FrameType) -> String {
+    match frame_type {
+        FrameType::BaselineDct => "baseline DCT".to_string(),
+        FrameType::ExtendedSequentialDct => "extended sequential DCT".to_string(),
+        FrameType::ProgressiveDct => "progressive DCT".to_string(),
+        FrameType::Lossless => "lossless".to_string(),
+        FrameType::Other(marker) => segments::marker_name(marker),
+    }
+}
+
+/// The usual J:a:b name of the chroma subsampling of a YCbCr frame.
+fn subsampling(frame_header: &FrameHeader) -> Option<&'static str> {
+    let components = frame_header.frame_components();
+    if components.len() != 3 ||
+       components[1..].iter().any(|c| {
+        c.horizontal_sampling_factor != 1 || c.vertical_sampling_factor != 1
+    }) {
+        return None;
+    }
+    match (components[0].horizontal_sampling_factor, components[0].vertical_sampling_factor) {
+        (1, 1) => Some("4:4:4"),
+        (2, 1) => Some("4:2:2"),
+        (1, 2) => Some("4:4:0"),
+        (2, 2) => Some("4:2:0"),
+        (4, 1) => Some("4:1:1"),
+        _ => None,
+    }
+}
+
+fn frame(frame_header: &FrameHeader, image: Option<&JPEGImage>) -> Value {
+    // With a DNL segment, the frame header has a height of 0.
+    let height = match image {
+        Some(image) if frame_header.num_lines() == 0 => image.height() as i64,
+        _ => frame_header.num_lines() as i64,
+    };
+    let components = frame_header.frame_components()
+        .iter()
+        .map(|c| {
+            Value::Object(vec![("id", int(c.component_id)),
+                               ("horizontal_sampling", int(c.horizontal_sampling_factor)),
+                               ("vertical_sampling", int(c.vertical_sampling_factor)),
+                               ("quantization_table", int(c.quantization_selector))])
+        })
+        .collect();
+    let mut fields = vec![("type", Value::Str(frame_type_name(frame_header.frame_type()))),
+                          ("precision", int(frame_header.sample_precision())),
+                          ("width", int(frame_header.samples_per_line())),
+                          ("height", Value::Int(height)),
+                          ("components", Value::List(components))];
+    if let Some(subsampling) = subsampling(frame_header) {
+        fields.push(("subsampling", string(subsampling)));
+    }
+    if let Some(color_space) = image.and_then(|image| image.color_space()) {
+        let name = match color_space {
+            ColorSpace::Gray => "gray",
+            ColorSpace::YCbCr => "YCbCr",
+            ColorSpace::Rgb => "RGB",
+            ColorSpace::Cmyk => "CMYK",
+            ColorSpace::Ycck => "YCCK",
+        };
+        fields.push(("color_space", string(name)));
+    }
+    Value::Object(fields)
+}
+
+fn scans(image: &JPEGImage) -> Value {
+    Value::List(image.scan_headers()
+        .iter()
+        .map(|scan| {
+            let components = scan.scan_components()
+                .iter()
+                .map(|c| {
+                    Value::Object(vec![("id", int(c.component_id)),
+                                       ("dc_table", int(c.dc_table_selector)),
+                                       ("ac_table", int(c.ac_table_selector))])
+                })
+                .collect();
+            let (start, end) = scan.spectral_selection();
+            let (high, low) = scan.successive_approximation();
+            Value::Object(vec![("components", Value::List(components)),
+                               ("spectral_selection", pair(start, end)),
+                               ("successive_approximation", pair(high, low))])
+        })
+        .collect())
+}
+
+/// Quantization table `id` as 8 rows of 8 values.
+fn quantization_grid(table: &[u16]) -> Value {
+    let mut natural = [0; 64];
+    for (i, &value) in table.iter().enumerate().take(64) {
+        natural[ZIGZAG_INDICES[i]] = value;
+    }
+    Value::List(natural.chunks(8)
+        .map(|row| Value::List(row.iter().map(|&value| int(value)).collect()))
+        .collect())
+}
+
+// The example tables of JPEG K.1, for luminance and chrominance, which
+// most encoders scale to their quality setting.
+const LUMINANCE_TABLE: [u16; 64] =
+    [16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69,
+     56, 14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81,
+     104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99];
+const CHROMINANCE_TABLE: [u16; 64] =
+    [17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99,
+     99, 47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+     99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99];
+
+/// The IJG quality, from 1 to 100, that scales the example table to
+/// `table`, on average. Tables of other encoders give a rough estimate.
+fn estimate_quality(table: &[u16], example: &[u16; 64]) -> i64 {
+    let sum = |table: &[u16]| table.iter().map(|&value| value as f64).sum::<f64>();
+    let scale = 100.0 * sum(table) / sum(example);
+    let quality = if scale <= 100.0 {
+        (200.0 - scale) / 2.0
+    } else {
+        5000.0 / scale
+    };
+    (quality.round() as i64).clamp(1, 100)
+}
+
+fn tables(image: &JPEGImage, fields: &mut Vec<(&'static str, Value)>) {
+    let mut quantization_tables = Vec::new();
+    for id in 0..4 {
+        if let Some(table) = image.quantization_table(id) {
+            quantization_tables.push(Value::Object(vec![("id", int(id)),
+                                                        ("values", quantization_grid(table))]));
+        }
+    }
+    fields.push(("quantization_tables", Value::List(quantization_tables)));
+
+    let mut huffman_tables = Vec::new();
+    for id in 0..4 {
+        let classes = [("DC", image.huffman_dc_table(id)), ("AC", image.huffman_ac_table(id))];
+        for &(class, table) in &classes {
+            if let Some(table) = table {
+                let codes = table.values().len() as i64;
+                let bits = table.bits().iter().map(|&n| int(n)).collect();
+                huffman_tables.push(Value::Object(vec![("class", string(class)),
+                                                       ("id", int(id)),
+                                                       ("codes", Value::Int(codes)),
+                                                       ("codes_of_length", Value::List(bits))]));
+            }
+        }
+    }
+    fields.push(("huffman_tables", Value::List(huffman_tables)));
+
+    // Luminance, or the only component, uses the table of the first component.
+    let components = image.frame_header().map(|header| header.frame_components()).unwrap_or(&[]);
+    let table_of = |i: usize| {
+        components.get(i).and_then(|c| image.quantization_table(c.quantization_selector))
+    };
+    let example = if components.len() == 4 {
+        // CMYK has no luminance.
+        None
+    } else {
+        table_of(0)
+            .map(|table| (table, &LUMINANCE_TABLE))
+            .or_else(|| table_of(1).map(|table| (table, &CHROMINANCE_TABLE)))
+    };
+    if let Some((table, example)) = example {
+        fields.push(("estimated_quality", Value::Int(estimate_quality(table, example))));
+    }
+}
+
+fn jfif(image: &JPEGImage) -> Option<Value> {
+    let version = match image.jfif_version()? {
+        JFIFVersion::V1_01 => "1.01".to_string(),
+        JFIFVersion::V1_02 => "1.02".to_string(),
+        JFIFVersion::Unknown(major, minor) => format!("{}.{:02}", major, minor),
+    };
+    let units = match image.density_units() {
+        JFIFUnits::NoUnits => "none".to_string(),
+        JFIFUnits::DotsPerInch => "dots per inch".to_string(),
+        JFIFUnits::DotsPerCm => "dots per cm".to_string(),
+        JFIFUnits::Unknown(units) => format!("unknown ({})", units),
+    };
+    let (x, y) = image.pixel_density();
+    let mut fields = vec![("version", Value::Str(version)),
+                          ("density_units", Value::Str(units)),
+                          ("density", pair(x, y))];
+    if let Some(thumbnail) = image.thumbnail() {
+        fields.push(("thumbnail",
+                     Value::List(vec![Value::Int(thumbnail.width as i64),
+                                      Value::Int(thumbnail.height as i64)])));
+    }
+    Some(Value::Object(fields))
+}
+
+fn exif(image: &JPEGImage) -> Option<Value> {
+    let exif = image.exif()?;
+    let mut fields = Vec::new();
+    let mut text = |key, value: Option<&str>| {
+        if let Some(value) = value {
+            fields.push((key, string(value.trim_end_matches('\0'))));
+        }
+    };
+    text("make", exif.make());
+    text("model", exif.model());
+    text("date_time", exif.date_time());
+    text("date_time_original", exif.date_time_original());
+    if let Some(orientation) = exif.orientation() {
+        fields.push(("orientation", int(orientation)));
+    }
+    if let Some((numerator, denominator)) = exif.exposure_time() {
+        fields.push(("exposure_time", Value::Str(format!("{}/{}", numerator, denominator))));
+    }
+    if let Some(f_number) = exif.f_number() {
+        fields.push(("f_number", Value::Float(f_number)));
+    }
+    if let Some(iso) = exif.iso_speed() {
+        fields.push(("iso", int(iso)));
+    }
+    if let Some(focal_length) = exif.focal_length() {
+        fields.push(("focal_length", Value::Float(focal_length)));
+    }
+    if let Some(gps) = exif.gps_position() {
+        let mut position = vec![("latitude", Value::Float(gps.latitude)),
+                                ("longitude", Value::Float(gps.longitude))];
+        if let Some(altitude) = gps.altitude {
+            position.push(("altitude", Value::Float(altitude)));
+        }
+        fields.push(("gps", Value::Object(position)));
+    }
+    if let Some(thumbnail) = exif.thumbnail() {
+        fields.push(("thumbnail_length", Value::Int(thumbnail.len() as i64)));
+    }
+    fields.push(("entries", Value::Int(exif.entries().len() as i64)));
+    Some(Value::Object(fields))
+}
+
+fn metadata(image: &JPEGImage, fields: &mut Vec<(&'static str, Value)>) {
+    if let Some(jfif) = jfif(image) {
+        fields.push(("jfif", jfif));
+    }
+    if let Some(exif) = exif(image) {
+        fields.push(("exif", exif));
+    }
+    if let Some(profile) = image.icc_profile() {
+        fields.push(("icc_profile_length", Value::Int(profile.len() as i64)));
+    }
+    if let Some(xmp) = image.xmp() {
+        fields.push(("xmp_length", Value::Int(xmp.len() as i64)));
+    }
+    if let Some(iptc) = image.iptc() {
+        fields.push(("iptc_datasets", Value::Int(iptc.datasets().len() as i64)));
+    }
+    if let Some(adobe) = image.adobe() {
+        let transform = match adobe.transform {
+            AdobeColorTransform::RgbOrCmyk => "none".to_string(),
+            AdobeColorTransform::YCbCr => "YCbCr".to_string(),
+            AdobeColorTransform::Ycck => "YCCK".to_string(),
+            AdobeColorTransform::Unknown(transform) => format!("unknown ({})", transform),
+        };
+        fields.push(("adobe",
+                     Value::Object(vec![("version", int(adobe.version)),
+                                        ("transform", Value::Str(transform))])));
+    }
+    if let Some(quality) = image.ducky_quality() {
+        fields.push(("ducky_quality", int(quality)));
+    }
+    if !image.mp_images().is_empty() {
+        fields.push(("mp_images", Value::Int(image.mp_images().len() as i64)));
+    }
+    if !image.comments().is_empty() {
+        let comments = image.comments().iter().map(|comment| string(comment)).collect();
+        fields.push(("comments", Value::List(comments)));
+    }
+}
+
+/// Which metadata segments `probe` found, for a file whose segments
+/// cannot all be parsed.
+fn probed_metadata(metadata: &probe::Metadata) -> Value {
+    let found = [(metadata.has_jfif, "JFIF"),
+                 (metadata.has_exif, "Exif"),
+                 (metadata.has_xmp, "XMP"),
+                 (metadata.has_icc_profile, "ICC profile"),
+                 (metadata.has_mpf, "MPF"),
+                 (metadata.has_photoshop, "Photoshop"),
+                 (metadata.has_adobe, "Adobe"),
+                 (metadata.has_comment, "comment")];
+    Value::List(found.iter().filter(|&&(has, _)| has).map(|&(_, name)| string(name)).collect())
+}
+
+/// The structure of a JPEG file: its segments, metadata, headers and tables.
+///
+/// The scans are not decoded, so the report is the same for progressive
+/// and oversized images, and for corrupt entropy coded data. If a segment
+/// cannot be parsed, the report has the segments, what `probe` finds
+/// before the first scan, and the error.
+pub fn report(path: &str, data: Vec<u8>) -> Value {
+    let mut fields = vec![("file", string(path)),
+                          ("file_size", Value::Int(data.len() as i64)),
+                          ("segments", segment_list(&data))];
+    let probed = probe::probe(&mut &data[..]);
+    let options = DecodeOptions::new().headers_only(true);
+    match JPEGImage::parse_with_options(data, &options) {
+        Ok(image) => {
+            metadata(&image, &mut fields);
+            match (image.frame_header(), probed) {
+                (Some(frame_header), _) => {
+                    fields.push(("frame", frame(frame_header, Some(&image))));
+                }
+                // Data that is not a JPEG file parses to an empty image.
+                (None, Err(error)) => fields.push(("error", Value::Str(error))),
+                (None, Ok(_)) => {}
+            }
+            fields.push(("restart_interval", int(image.restart_interval())));
+            fields.push(("scans", scans(&image)));
+            tables(&image, &mut fields);
+        }
+        Err(error) => {
+            if let Ok(info) = probed {
+                fields.push(("metadata", probed_metadata(&info.metadata)));
+                fields.push(("frame", frame(&info.frame_header, None)));
+            }
+            fields.push(("error", Value::Str(error)));
+        }
+    }
+    Value::Object(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIMPLE: &[u8] = include_bytes!("../working-jpegs/huff_simple0.jpg");
+
+    /// `SIMPLE` with COM segments after SOI.
+    fn with_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = SIMPLE[..2].to_vec();
+        for comment in comments {
+            let length = comment.len() + 2;
+            data.extend_from_slice(&[0xff, 0xfe, (length >> 8) as u8, length as u8]);
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data.extend_from_slice(&SIMPLE[2..]);
+        data
+    }
+
+    #[test]
+    fn text_report() {
+        let text = report("simple.jpg", with_comments(&["first\r\n", "second"])).to_text();
+        assert!(text.starts_with("file: simple.jpg\nfile_size: 674\nsegments:\n  name: SOI"));
+        assert!(text.contains("\ncomments:\n  first\n  second\nframe:\n  type: baseline DCT\n"));
+        assert!(text.contains("\nscans:\n  - components:\n      id: 1, dc_table: 0, ac_table: 0"));
+        assert!(text.contains("\n  - id: 0\n    values:\n         2   2   2   2   3   4   5"));
+        assert!(!text.contains("error"));
+    }
+
+    #[test]
+    fn json_report() {
+        let json = report("simple.jpg", with_comments(&["first\n", "\"second\""])).to_json();
+        assert!(json.starts_with("{\"file\":\"simple.jpg\",\"file_size\":675,\"segments\":["));
+        assert!(json.contains(",\"comments\":[\"first\\n\",\"\\\"second\\\"\"],"));
+        assert!(json.contains("\"width\":16,\"height\":8,"));
+        assert!(json.ends_with("}"));
+    }
+
+    #[test]
+    fn progressive_report() {
+        let mut data = SIMPLE.to_vec();
+        let sof = data.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        data[sof + 1] = 0xc2;
+        let json = report("progressive.jpg", data).to_json();
+        assert!(json.contains("\"jfif\":{\"version\":\"1.02\""));
+        assert!(json.contains("\"type\":\"progressive DCT\""));
+        assert!(json.contains("\"quantization_tables\":[{\"id\":0,"));
+        assert!(json.contains("\"huffman_tables\":[{\"class\":\"DC\""));
+        assert!(!json.contains("error"));
+    }
+
+    #[test]
+    fn broken_table_report() {
+        let mut data = SIMPLE.to_vec();
+        let dqt = data.windows(2).position(|w| w == [0xff, 0xdb]).unwrap();
+        data[dqt + 4] = 0x05;
+        let json = report("broken.jpg", data).to_json();
+        assert!(json.contains("{\"name\":\"DQT\",\"offset\":55,\"length\":134}"));
+        assert!(json.contains("\"metadata\":[\"JFIF\",\"Adobe\"],"));
+        assert!(json.contains("\"type\":\"baseline DCT\""));
+        assert!(json.ends_with("\"error\":\"Quantization table id 5 is out of range\"}"));
+    }
+}
@@ -0,0 +1,211 @@
+/// Layout of the pixel data produced when decoding an image.
+///
+/// All formats are packed, with one byte per channel, and pixels
+/// laid out line by line from the top left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    /// Red, green, blue
+    Rgb,
+    /// Red, green, blue, and an opaque alpha channel
+    Rgba,
+    /// Blue, green, red
+    Bgr,
+    /// Blue, green, red, and an opaque alpha channel
+    Bgra,
+    /// Luma only. For YCbCr images, the chroma components are not decoded.
+    Gray,
+    /// Y, Cb, Cr, without any color conversion
+    YCbCr,
+    /// Cyan, magenta, yellow, black, where 0 is no ink.
+    /// Adobe CMYK and YCCK images are inverted back to this convention.
+    Cmyk,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgb | PixelFormat::Bgr | PixelFormat::YCbCr => 3,
+            PixelFormat::Rgba | PixelFormat::Bgra | PixelFormat::Cmyk => 4,
+        }
+    }
+}
+
+/// The color space of the decoded components of an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Gray,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    /// YCbCr encoded CMY, and K, as written by Adobe applications
+    Ycck,
+}
+
+/// Pack component planes into pixels of the given format.
+///
+/// `planes` contains one full size plane of samples for each component.
+/// When `format` is `PixelFormat::Gray` and the image is YCbCr, only the
+/// first plane is read, so the chroma planes may be left empty.
+///
+/// If `inverted` is set, CMYK and YCCK samples are stored inverted, as
+/// Adobe applications do.
+pub fn pack_pixels(planes: &[Vec<u8>],
+                   color_space: ColorSpace,
+                   inverted: bool,
+                   format: PixelFormat)
+                   -> Vec<u8> {
+    let num_pixels = planes[0].len();
+    let mut data = Vec::with_capacity(num_pixels * format.bytes_per_pixel());
+
+    if format == PixelFormat::Gray &&
+       (color_space == ColorSpace::Gray || color_space == ColorSpace::YCbCr) {
+        data.extend_from_slice(&planes[0]);
+        return data;
+    }
+
+    for i in 0..num_pixels {
+        match format {
+            PixelFormat::Gray => {
+                let (r, g, b) = rgb_at(planes, color_space, inverted, i);
+                data.push(rgb_to_y_cb_cr(r, g, b).0);
+            }
+            PixelFormat::YCbCr => {
+                let (y, cb, cr) = y_cb_cr_at(planes, color_space, inverted, i);
+                data.extend_from_slice(&[y, cb, cr]);
+            }
+            PixelFormat::Cmyk => {
+                let (c, m, y, k) = cmyk_at(planes, color_space, inverted, i);
+                data.extend_from_slice(&[c, m, y, k]);
+            }
+            _ => {
+                let (r, g, b) = rgb_at(planes, color_space, inverted, i);
+                match format {
+                    PixelFormat::Rgb => data.extend_from_slice(&[r, g, b]),
+                    PixelFormat::Rgba => data.extend_from_slice(&[r, g, b, 255]),
+                    PixelFormat::Bgr => data.extend_from_slice(&[b, g, r]),
+                    PixelFormat::Bgra => data.extend_from_slice(&[b, g, r, 255]),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+    data
+}
+
+/// The YCbCr value of pixel `i`.
+fn y_cb_cr_at(planes: &[Vec<u8>],
+              color_space: ColorSpace,
+              inverted: bool,
+              i: usize)
+              -> (u8, u8, u8) {
+    match color_space {
+        ColorSpace::Gray => (planes[0][i], 128, 128),
+        ColorSpace::YCbCr => (planes[0][i], planes[1][i], planes[2][i]),
+        _ => {
+            let (r, g, b) = rgb_at(planes, color_space, inverted, i);
+            rgb_to_y_cb_cr(r, g, b)
+        }
+    }
+}
+
+/// The RGB value of pixel `i`.
+fn rgb_at(planes: &[Vec<u8>], color_space: ColorSpace, inverted: bool, i: usize) -> (u8, u8, u8) {
+    match color_space {
+        ColorSpace::Gray => (planes[0][i], planes[0][i], planes[0][i]),
+        ColorSpace::YCbCr => y_cb_cr_to_rgb(planes[0][i], planes[1][i], planes[2][i]),
+        ColorSpace::Rgb => (planes[0][i], planes[1][i], planes[2][i]),
+        ColorSpace::Cmyk | ColorSpace::Ycck => {
+            let (c, m, y, k) = cmyk_at(planes, color_space, inverted, i);
+            cmyk_to_rgb(c, m, y, k)
+        }
+    }
+}
+
+/// The CMYK value of pixel `i`, where 0 is no ink.
+fn cmyk_at(planes: &[Vec<u8>],
+           color_space: ColorSpace,
+           inverted: bool,
+           i: usize)
+           -> (u8, u8, u8, u8) {
+    let (c, m, y, k) = match color_space {
+        ColorSpace::Cmyk => (planes[0][i], planes[1][i], planes[2][i], planes[3][i]),
+        ColorSpace::Ycck => {
+            // The YCbCr part decodes to the complement of the stored CMY.
+            // See Adobe Technical Note #5116.
+            let (r, g, b) = y_cb_cr_to_rgb(planes[0][i], planes[1][i], planes[2][i]);
+            (255 - r, 255 - g, 255 - b, planes[3][i])
+        }
+        _ => {
+            let (r, g, b) = rgb_at(planes, color_space, inverted, i);
+            return rgb_to_cmyk(r, g, b);
+        }
+    };
+    if inverted {
+        (255 - c, 255 - m, 255 - y, 255 - k)
+    } else {
+        (c, m, y, k)
+    }
+}
+
+/// Truncate and clamp a sample to `0..256`.
+pub fn f32_to_u8(n: f32) -> u8 {
+    if n < 0.0 {
+        0
+    } else if n > 255.0 {
+        255
+    } else {
+        n as u8
+    }
+}
+
+/// See JFIF, section "Conversion to and from RGB"
+fn y_cb_cr_to_rgb(y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
+    let c_red: f32 = 0.299;
+    let c_green: f32 = 0.587;
+    let c_blue: f32 = 0.114;
+
+    let y = y as f32;
+    let cb = cb as f32 - 128.0;
+    let cr = cr as f32 - 128.0;
+
+    let r = cr * (2.0 - 2.0 * c_red) + y;
+    let b = cb * (2.0 - 2.0 * c_blue) + y;
+    let g = (y - c_blue * b - c_red * r) / c_green;
+
+    (f32_to_u8(r), f32_to_u8(g), f32_to_u8(b))
+}
+
+/// See JFIF, section "Conversion to and from RGB"
+fn rgb_to_y_cb_cr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let r = r as f32;
+    let g = g as f32;
+    let b = b as f32;
+
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let cb = -0.1687 * r - 0.3313 * g + 0.5 * b + 128.0;
+    let cr = 0.5 * r - 0.4187 * g - 0.0813 * b + 128.0;
+
+    (f32_to_u8(y), f32_to_u8(cb), f32_to_u8(cr))
+}
+
+/// Naive conversion, without any color management.
+fn cmyk_to_rgb(c: u8, m: u8, y: u8, k: u8) -> (u8, u8, u8) {
+    let white = 255 - k as u32;
+    let r = (255 - c as u32) * white / 255;
+    let g = (255 - m as u32) * white / 255;
+    let b = (255 - y as u32) * white / 255;
+    (r as u8, g as u8, b as u8)
+}
+
+/// Naive conversion, without any color management.
+fn rgb_to_cmyk(r: u8, g: u8, b: u8) -> (u8, u8, u8, u8) {
+    let white = *[r, g, b].iter().max().unwrap() as u32;
+    if white == 0 {
+        return (0, 0, 0, 255);
+    }
+    let c = (white - r as u32) * 255 / white;
+    let m = (white - g as u32) * 255 / white;
+    let y = (white - b as u32) * 255 / white;
+    (c as u8, m as u8, y as u8, 255 - white as u8)
+}
@@ -1,18 +1,24 @@
-use std::iter::repeat;
-
-use jpeg::jpeg::{FrameHeader, ScanHeader};
+use jpeg::jpeg::{Concealment, FrameHeader, Incident, ScanHeader};
 use jpeg::huffman;
+use jpeg::color::f32_to_u8;
 use ::transform;
 
 type QuantizationTable = Vec<u16>;
-type Block = Vec<f32>;
+/// The coefficients of an 8x8 block, in zigzag order.
+pub(crate) type Block = Vec<i32>;
 
 /// Struct to hold state of JPEG decoding.
 /// Instantiate it, and pass in AC/DC tables, quantization
 /// tables, sampling factors, data, etc. as it is available,
 /// or updated.
 ///
-/// Call `JPEGDecoder::decode()` to start reading from `data`.
+/// Call `JPEGDecoder::decode()` to start reading from `data`, which is the
+/// entropy coded data of the scan, as it is in the file. With `resync`,
+/// `data` may run on to the end of the file, see `JPEGDecoder::end`.
+///
+/// The scans of a progressive frame are read with
+/// `JPEGDecoder::decode_progressive()` instead, into coefficients that are
+/// kept across the scans, and transformed with `JPEGDecoder::transform()`.
 pub struct JPEGDecoder<'a> {
     /// Encoded image data
     data: &'a [u8],
@@ -26,6 +32,34 @@ pub struct JPEGDecoder<'a> {
     component_fields: Vec<JPEGDecoderComponentFields>,
     /// Image dimensions
     dimensions: (usize, usize),
+    /// Largest horizontal and vertical sampling factors in the frame
+    max_sampling_factors: (usize, usize),
+    /// Id of the first component in the frame
+    luma_component: u8,
+    /// Only transform the first component of the frame into samples
+    luma_only: bool,
+    /// Number of MCUs in each restart interval, or 0 without restart markers
+    restart_interval: u16,
+    /// First and last coefficient of the scan, in zigzag order
+    spectral_selection: (u8, u8),
+    /// Successive approximation bit positions of the scan, Ah and Al
+    successive_approximation: (u8, u8),
+    /// How to fill MCUs lost to corrupt data, or `None` to fail on it
+    concealment: Option<Concealment>,
+    /// Whether to skip markers other than RSTn in the data, see `end`
+    resync: bool,
+    /// Offset of `data` in the file, to report errors with
+    offset: usize,
+    /// Corrupt data that was concealed
+    incidents: Vec<Incident>,
+    /// Whether `data` is cut off by the end of the file
+    truncated: bool,
+    /// Whether `data` ran out before the end of the scan
+    incomplete: bool,
+    /// Offset in `data` of the marker after the entropy coded data that was read
+    end: usize,
+    /// Number of lines that may be read if the height is not known
+    max_lines: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -49,14 +83,28 @@ struct JPEGDecoderComponentFields {
 }
 
 impl<'a> JPEGDecoder<'a> {
-    pub fn new(data: &'a [u8]) -> JPEGDecoder {
+    pub fn new(data: &'a [u8]) -> JPEGDecoder<'a> {
         JPEGDecoder {
-            data: data,
+            data,
             huffman_ac_tables: [None, None, None, None],
             huffman_dc_tables: [None, None, None, None],
             quantization_tables: [None, None, None, None],
             component_fields: Vec::new(),
             dimensions: (0, 0),
+            max_sampling_factors: (1, 1),
+            luma_component: 0,
+            luma_only: false,
+            restart_interval: 0,
+            spectral_selection: (0, 63),
+            successive_approximation: (0, 0),
+            concealment: None,
+            resync: false,
+            offset: 0,
+            incidents: Vec::new(),
+            truncated: false,
+            incomplete: false,
+            end: 0,
+            max_lines: 0xffff,
         }
     }
 
@@ -78,6 +126,19 @@ impl<'a> JPEGDecoder<'a> {
     }
 
     pub fn frame_header(mut self, frame_header: FrameHeader) -> JPEGDecoder<'a> {
+        self.max_sampling_factors = (frame_header.frame_components
+                                         .iter()
+                                         .map(|c| c.horizontal_sampling_factor as usize)
+                                         .max()
+                                         .unwrap_or(1),
+                                     frame_header.frame_components
+                                         .iter()
+                                         .map(|c| c.vertical_sampling_factor as usize)
+                                         .max()
+                                         .unwrap_or(1));
+        if let Some(component) = frame_header.frame_components.first() {
+            self.luma_component = component.component_id;
+        }
         for frame_component in &frame_header.frame_components {
             // Update horiz/vert sampling factor, and quant selector.
             let was_none = self.component_fields
@@ -108,6 +169,8 @@ impl<'a> JPEGDecoder<'a> {
     }
 
     pub fn scan_header(mut self, scan_header: ScanHeader) -> JPEGDecoder<'a> {
+        self.spectral_selection = scan_header.spectral_selection();
+        self.successive_approximation = scan_header.successive_approximation();
         for scan_component in &scan_header.scan_components {
             // Update horiz/vert sampling factor, and quant selector.
             let was_none = self.component_fields
@@ -148,241 +211,703 @@ impl<'a> JPEGDecoder<'a> {
         self
     }
 
-    fn ac_table(&'a self, id: u8) -> &'a huffman::HuffmanTable {
-        self.huffman_ac_tables[id as usize].as_ref().unwrap()
+    pub fn restart_interval(mut self, restart_interval: u16) -> JPEGDecoder<'a> {
+        self.restart_interval = restart_interval;
+        self
     }
 
-    fn dc_table(&'a self, id: u8) -> &'a huffman::HuffmanTable {
-        self.huffman_dc_tables[id as usize].as_ref().unwrap()
+    pub fn concealment(mut self, concealment: Option<Concealment>) -> JPEGDecoder<'a> {
+        self.concealment = concealment;
+        self
     }
 
-    pub fn decode(&mut self) -> (Vec<(u8, u8, u8)>, usize) {
-        // Number of blocks in x and y direction
-        let num_blocks_x = (self.dimensions.0 + 7) / 8;
-        let num_blocks_y = (self.dimensions.1 + 7) / 8;
-        let num_blocks = num_blocks_x * num_blocks_y;
+    /// Skip markers other than RSTn in corrupt data, with a concealment.
+    pub fn resync(mut self, resync: bool) -> JPEGDecoder<'a> {
+        self.resync = resync;
+        self
+    }
 
-        let num_components = self.component_fields.len();
+    pub fn offset(mut self, offset: usize) -> JPEGDecoder<'a> {
+        self.offset = offset;
+        self
+    }
 
-        // 2D vector, one vector for each component.
-        let mut blocks: Vec<Vec<Block>> = (0..self.component_fields.len())
-            .map(|_| vec![])
-            .collect();
-        let mut previous_dc: Vec<f32> = repeat(0.0).take(self.component_fields.len()).collect();
+    /// Running out of data cut off by the end of the file is not an error.
+    /// The rows that were not read completely are filled in instead.
+    pub fn truncated(mut self, truncated: bool) -> JPEGDecoder<'a> {
+        self.truncated = truncated;
+        self
+    }
 
-        let max_block_hori_scale = self.component_fields
-            .iter()
-            .map(|c| c.horizontal_sampling_factor)
-            .max()
-            .unwrap_or(1) as usize;
+    /// Fail if a scan of unknown height has more than `max_lines` lines.
+    pub fn max_lines(mut self, max_lines: usize) -> JPEGDecoder<'a> {
+        self.max_lines = max_lines;
+        self
+    }
 
-        let max_block_vert_scale = self.component_fields
+    /// Whether `decode` filled in rows of a truncated scan.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    /// Corrupt data that `decode` concealed.
+    pub fn incidents(&self) -> &[Incident] {
+        &self.incidents
+    }
+
+    /// Offset in `data` of the marker where the scan ends, after `decode`.
+    ///
+    /// Without `resync`, this is the first marker that is not RSTn. With it,
+    /// other markers in corrupt data are skipped to the next RSTn, so that
+    /// more intervals can be read after them. The scan then ends
+    /// after the last interval that was read, or at EOI if MCUs were lost
+    /// up to the end of the scan.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// The image height, or the number of lines read if the height was 0
+    /// before `decode`.
+    pub fn height(&self) -> usize {
+        self.dimensions.1
+    }
+
+    /// Only produce samples for the first component of the frame.
+    /// The other components are still read from the entropy coded data,
+    /// as they may be interleaved with the first, but they are neither
+    /// dequantized nor transformed.
+    pub fn luma_only(mut self, luma_only: bool) -> JPEGDecoder<'a> {
+        self.luma_only = luma_only;
+        self
+    }
+
+    fn ac_table(&self, id: u8) -> Result<&huffman::HuffmanTable, String> {
+        self.huffman_ac_tables
+            .get(id as usize)
+            .and_then(|table| table.as_ref())
+            .ok_or(format!("No AC Huffman table {}", id))
+    }
+
+    fn dc_table(&self, id: u8) -> Result<&huffman::HuffmanTable, String> {
+        self.huffman_dc_tables
+            .get(id as usize)
+            .and_then(|table| table.as_ref())
+            .ok_or(format!("No DC Huffman table {}", id))
+    }
+
+    /// Read the next block of `component_i` from `huffman_decoder`, and
+    /// apply DC correction.
+    fn next_block(&self,
+                  huffman_decoder: &mut huffman::HuffmanDecoder,
+                  component_i: usize,
+                  previous_dc: &mut [i32])
+                  -> Result<Block, String> {
+        let component = &self.component_fields[component_i];
+        let ac_table = self.ac_table(component.ac_table_id)?;
+        let dc_table = self.dc_table(component.dc_table_id)?;
+
+        let mut block: Block = huffman_decoder.next_block(ac_table, dc_table)?
             .iter()
-            .map(|c| c.vertical_sampling_factor)
-            .max()
-            .unwrap_or(1) as usize;
+            .map(|&i| i as i32)
+            .collect();
 
-        let block_factor = max_block_hori_scale * max_block_vert_scale;
+        // DC correction
+        block[0] += previous_dc[component_i];
+        previous_dc[component_i] = block[0];
+        Ok(block)
+    }
 
-        let mut huffman_decoder = huffman::HuffmanDecoder::new(&self.data);
+    /// The blocks of the MCU at `x` in `row`, as component index and index
+    /// in the blocks of the component. In non-interleaved scans, an MCU is
+    /// a single block. See JPEG A.2.
+    fn mcu_blocks(&self, row: usize, x: usize, line_blocks: &[usize]) -> Vec<(usize, usize)> {
+        if self.component_fields.len() == 1 {
+            return vec![(0, row * line_blocks[0] + x)];
+        }
+        let mut positions = Vec::new();
+        for (component_i, component) in self.component_fields.iter().enumerate() {
+            let hsf = component.horizontal_sampling_factor as usize;
+            let vsf = component.vertical_sampling_factor as usize;
+            for v in 0..vsf {
+                for h in 0..hsf {
+                    positions.push((component_i,
+                                    (row * vsf + v) * line_blocks[component_i] + x * hsf + h));
+                }
+            }
+        }
+        positions
+    }
+
+    /// Whether the interval after `interval_i` starts with the RSTn marker
+    /// expected before `mcu`. Fails with a message, and the offset in the scan
+    /// data of the marker found instead, or of the end of the data.
+    fn check_restart(&self,
+                     intervals: &[Interval],
+                     interval_i: usize,
+                     mcu: usize)
+                     -> Option<(String, usize)> {
+        let expected = ((mcu / self.restart_interval as usize - 1) % 8) as u8;
+        let found = intervals.get(interval_i + 1).and_then(|interval| interval.restart_number);
+        if found == Some(expected) {
+            return None;
+        }
+        let message = format!("Expected RST{}, found {}",
+                              expected,
+                              found.map(|n| format!("RST{}", n)).unwrap_or("none".to_string()));
+        let offset = intervals.get(interval_i + 1)
+            .map(|interval| interval.offset - 2)
+            .unwrap_or(self.data.len());
+        Some((message, offset))
+    }
+
+    /// Decode the scan.
+    ///
+    /// Returns one plane of samples for each component, in the order of the
+    /// scan header, upsampled to the image dimensions. If `luma_only` is set,
+    /// the planes of all components but the first component of the frame are
+    /// empty.
+    ///
+    /// Fails on corrupt entropy coded data, unless a concealment is set.
+    pub fn decode(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        let (width, mut height) = self.dimensions;
+        let num_components = self.component_fields.len();
+        if width == 0 || num_components == 0 {
+            return Err("Scan has no samples".to_string());
+        }
+
+        let (max_block_hori_scale, max_block_vert_scale) = self.max_sampling_factors;
+
+        // Number of MCUs in x and y direction. See JPEG A.2.
+        let num_mcus_x = div_ceil(width, 8 * max_block_hori_scale);
+        let mut num_mcus_y = div_ceil(height, 8 * max_block_vert_scale);
+
+        // Number of blocks in x direction for each component,
+        // including the blocks padding the image to a whole number of MCUs.
+        let line_blocks: Vec<usize> = self.component_fields
+            .iter()
+            .map(|c| num_mcus_x * c.horizontal_sampling_factor as usize)
+            .collect();
+
+        // 2D vector, one vector of blocks, in raster order, for each component.
+        // It grows by whole MCU rows as they are read.
+        let mut blocks: Vec<Vec<Block>> = vec![Vec::new(); num_components];
+        let component_fields = &self.component_fields;
+        let grow = |blocks: &mut Vec<Vec<Block>>, num_mcus_y: usize| {
+            for (component_i, component) in component_fields.iter().enumerate() {
+                let length = line_blocks[component_i] * num_mcus_y *
+                             component.vertical_sampling_factor as usize;
+                if blocks[component_i].len() < length {
+                    blocks[component_i].resize(length, Vec::new());
+                }
+            }
+        };
+
+        // Non-interleaved scans are not padded to whole MCUs, but
+        // only to whole blocks. See JPEG A.2.2.
+        let interleaved = num_components > 1;
+        let hsf = self.component_fields[0].horizontal_sampling_factor as usize;
+        let vsf = self.component_fields[0].vertical_sampling_factor as usize;
+        let (mcus_per_row, rows) = if interleaved {
+            (num_mcus_x, num_mcus_y)
+        } else {
+            (div_ceil(div_ceil(width * hsf, max_block_hori_scale), 8),
+             div_ceil(div_ceil(height * vsf, max_block_vert_scale), 8))
+        };
+        // Number of lines covered by a number of MCU rows.
+        let lines = |rows: usize| if interleaved {
+            rows * 8 * max_block_vert_scale
+        } else {
+            rows * 8 * max_block_vert_scale / vsf
+        };
+
+        // A height of 0 is defined by a DNL segment after the first scan.
+        // See JPEG B.2.5. Until then, rows are read as long as there is data,
+        // up to `max_lines`.
+        let unknown_height = height == 0;
+
+        let resync = self.resync && self.concealment.is_some();
+        let (intervals, data_end) = split_intervals(self.data, resync);
+        let restart_interval = self.restart_interval as usize;
+        // The interval being read, and the MCU it starts at.
+        let mut interval_i = 0;
+        let mut interval_start = 0;
+        let mut huffman_decoder = huffman::HuffmanDecoder::new(&intervals[0].data);
+        let mut previous_dc: Vec<i32> = vec![0; num_components];
+        // While MCUs are lost, the incident, and the MCU where the next interval
+        // starts, if there is one.
+        let mut lost: Option<(Incident, Option<usize>)> = None;
+
+        let mut mcu = 0;
+        loop {
+            let (row, x) = (mcu / mcus_per_row, mcu % mcus_per_row);
+            if x == 0 {
+                let more_rows = if unknown_height {
+                    let more_rows = match lost {
+                        Some((_, resume)) => resume.is_some(),
+                        None => {
+                            !huffman_decoder.is_exhausted() || interval_i + 1 < intervals.len()
+                        }
+                    };
+                    if more_rows && lines(row) >= self.max_lines {
+                        return Err(format!("Scan has more than {} lines", self.max_lines));
+                    }
+                    more_rows
+                } else {
+                    row < rows
+                };
+                if !more_rows {
+                    break;
+                }
+                grow(&mut blocks, if interleaved { row + 1 } else { row / vsf + 1 });
+            }
 
-        // Step 1: Read encoded data
-        for line in 0..num_blocks_y / max_block_vert_scale {
-            for _ in 0..num_blocks_x / max_block_hori_scale {
-                for (component_i, component) in self.component_fields.iter().enumerate() {
-                    let vsf = component.vertical_sampling_factor as usize;
-                    if line % vsf != (vsf - 1) {
-                        continue;
+            // Each restart interval starts after an RSTn marker, with n counting
+            // modulo 8, and resets the DC predictions. See JPEG E.2.4.
+            let mut error = None;
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                let restart = match lost {
+                    Some((_, resume)) => resume == Some(mcu),
+                    None => {
+                        error = self.check_restart(&intervals, interval_i, mcu);
+                        error.is_none()
                     }
-                    let ac_table = self.ac_table(component.ac_table_id);
-                    let dc_table = self.dc_table(component.dc_table_id);
-
-                    for _ in 0..component.horizontal_sampling_factor {
-                        let mut decoded_block: Vec<f32> =
-                            huffman_decoder.next_block(ac_table, dc_table)
-                                .iter()
-                                .map(|&i| i as f32)
-                                .collect();
-
-                        // DC correction
-                        let encoded = decoded_block[0];
-                        decoded_block[0] = encoded + previous_dc[component_i];
-                        previous_dc[component_i] = decoded_block[0];
-
-                        blocks[component_i].push(decoded_block);
+                };
+                if restart {
+                    if let Some((incident, _)) = lost.take() {
+                        self.incidents.push(incident);
                     }
+                    interval_i += 1;
+                    interval_start = mcu;
+                    huffman_decoder = huffman::HuffmanDecoder::new(&intervals[interval_i].data);
+                    previous_dc = vec![0; num_components];
                 }
             }
-        }
 
-        // Step 2: get color data
-        // Now all decoded blocks are in `blocks`.
-        // For each block, do dequantization, reverse zigzag, and inverse DCT.
-        for (component_i, component) in self.component_fields.iter().enumerate() {
-            let quant_table = self.quantization_tables[component.quantization_id as usize]
-                .as_ref()
-                .expect(&format!("Did not find quantization table for {}",
-                                 component.quantization_id));
-
-            let component_blocks: Vec<Vec<f32>> = blocks[component_i]
-                .iter()
-                .map(|block| {
-                    zigzag_inverse(block.iter()
-                        .zip(quant_table.iter())
-                        .map(|(&n, &q)| n * q as f32))
-                })
-                .map(|block| transform::discrete_cosine_transform_inverse(&block))
-                .collect();
-
-            // See JPEG A.1.1
-            let x_i = (self.dimensions.0 as f32 *
-                       (component.horizontal_sampling_factor as f32 / max_block_hori_scale as f32))
-                .ceil();
-
-            let y_i = (self.dimensions.1 as f32 *
-                       (component.vertical_sampling_factor as f32 / max_block_vert_scale as f32))
-                .ceil();
-
-            // Now `x_i` and `y_i` are the dimensions of the read blocks.
-            // if they are below `self.dimensions.0`, they need expansion.
-            // NOTE: might be some tricky floating conversion pitfalls here?
-
-            let x_factor = (self.dimensions.0 as f32 / x_i) as u32;
-            let y_factor = (self.dimensions.1 as f32 / y_i) as u32;
-
-            if x_factor == 1 {
-                blocks[component_i] = component_blocks;
-            } else if x_factor == 2 {
-                blocks[component_i] = component_blocks.iter()
-                    .flat_map(|block| {
-                        let (a, b) = expand_block_x_2(block);
-                        vec![a, b]
+            let positions = self.mcu_blocks(row, x, &line_blocks);
+            if lost.is_none() && error.is_none() {
+                error = positions.iter()
+                    .map(|&(component_i, index)| {
+                        blocks[component_i][index] =
+                            self.next_block(&mut huffman_decoder, component_i, &mut previous_dc)?;
+                        Ok(())
                     })
-                    .collect();
-            } else {
-                panic!("Fix expansion above factor=2");
+                    .find_map(|result: Result<(), String>| result.err())
+                    .map(|message| {
+                        let position = huffman_decoder.position() / 8;
+                        (message, intervals[interval_i].offset_of(position))
+                    });
+            }
+            // Running out of data in the last interval of a truncated scan
+            // ends the scan. Bits made up past the end can cause other errors too.
+            if error.is_some() && self.truncated && interval_i + 1 == intervals.len() &&
+               huffman_decoder.is_near_end() {
+                self.incomplete = true;
+                break;
+            }
+            if let Some((message, offset)) = error {
+                let offset = self.offset + offset;
+                if self.concealment.is_none() {
+                    return Err(format!("{} at offset {}, in MCU {}", message, offset, mcu));
+                }
+                // Skip to the interval after the current one, which starts
+                // where the number of its marker says.
+                let resume = intervals.get(interval_i + 1)
+                    .and_then(|interval| interval.restart_number)
+                    .filter(|_| restart_interval > 0)
+                    .map(|n| {
+                        let current = interval_start / restart_interval;
+                        (current + 1 + (n as usize + 8 - current % 8) % 8) * restart_interval
+                    });
+                lost = Some((Incident {
+                                 offset,
+                                 mcu,
+                                 lost_mcus: 0,
+                                 message,
+                             },
+                             resume));
             }
+            if let Some((ref mut incident, _)) = lost {
+                let concealment = self.concealment.unwrap_or(Concealment::Gray);
+                conceal(&mut blocks, &positions, &line_blocks, concealment);
+                incident.lost_mcus += 1;
+            }
+            mcu += 1;
+        }
+        self.end = match lost {
+            Some(_) => data_end,
+            None => intervals[interval_i].end,
+        };
+        if let Some((incident, _)) = lost {
+            self.incidents.push(incident);
+        }
 
-            // TODO: fix vertical expansion.
-            assert!(y_factor == 1);
+        // Only the rows that were read completely are kept.
+        let complete_rows = mcu / mcus_per_row;
+        if unknown_height {
+            height = lines(complete_rows);
+            num_mcus_y = div_ceil(height, 8 * max_block_vert_scale);
+        }
+        grow(&mut blocks, num_mcus_y);
+        self.dimensions.1 = height;
+        if self.incomplete {
+            let concealment = self.concealment.unwrap_or(Concealment::Gray);
+            for mcu in complete_rows * mcus_per_row..rows.max(complete_rows) * mcus_per_row {
+                let positions = self.mcu_blocks(mcu / mcus_per_row,
+                                                mcu % mcus_per_row,
+                                                &line_blocks);
+                conceal(&mut blocks, &positions, &line_blocks, concealment);
+            }
         }
 
-        // Step 3: Merge color data
-        let rgb_blocks: Vec<Vec<(u8, u8, u8)>> = if num_components == 3 {
-                blocks[0]
-                    .iter()
-                    .zip(blocks[1].iter())
-                    .zip(blocks[2].iter())
-                    .map(|((y_block, cb_block), cr_block)| {
-                        y_block.iter()
-                            .zip(cb_block.iter())
-                            .zip(cr_block.iter())
-                            .map(|((&y, &cb), &cr)| y_cb_cr_to_rgb(y, cb, cr))
-                            .collect()
-                    })
-                    .collect()
-            } else {
-                blocks[0]
-                    .iter()
-                    .map(|block| {
-                        block.iter()
-                            .map(|&g| (g, g, g))
-                            .collect()
-                    })
-                    .collect::<Vec<Vec<(f32, f32, f32)>>>()
+        self.transform(&blocks)
+    }
+
+    /// Dequantize and transform `blocks`, one vector of blocks for each
+    /// component in raster order, including the blocks padding the image to
+    /// a whole number of MCUs. Returns one plane of samples for each component,
+    /// upsampled to the image dimensions, like `decode`.
+    pub(crate) fn transform(&self, blocks: &[Vec<Block>]) -> Result<Vec<Vec<u8>>, String> {
+        // For each block, do dequantization, reverse zigzag, and inverse DCT,
+        // and upsample the component to the image dimensions.
+        let (width, height) = self.dimensions;
+        let (max_block_hori_scale, max_block_vert_scale) = self.max_sampling_factors;
+        let num_mcus_x = div_ceil(width, 8 * max_block_hori_scale);
+        let mut planes = Vec::with_capacity(self.component_fields.len());
+        for (component_i, component) in self.component_fields.iter().enumerate() {
+            if self.luma_only && component.component != self.luma_component {
+                planes.push(Vec::new());
+                continue;
             }
+            let quant_table = self.quantization_tables
+                .get(component.quantization_id as usize)
+                .and_then(|table| table.as_ref())
+                .ok_or(format!("Did not find quantization table for {}",
+                               component.quantization_id))?;
+
+            let line_blocks = num_mcus_x * component.horizontal_sampling_factor as usize;
+            let line_width = line_blocks * 8;
+            let mut samples = vec![0u8; blocks[component_i].len() * 64];
+            for (block_i, block) in blocks[component_i].iter().enumerate() {
+                // Padding blocks of non-interleaved scans are never read.
+                if block.is_empty() {
+                    continue;
+                }
+                let block = zigzag_inverse(block.iter()
+                    .zip(quant_table.iter())
+                    .map(|(&n, &q)| n as f32 * q as f32));
+                let block = transform::discrete_cosine_transform_inverse(&block);
+
+                let x = (block_i % line_blocks) * 8;
+                let y = (block_i / line_blocks) * 8;
+                for (in_block_index, &sample) in block.iter().enumerate() {
+                    let index = (y + in_block_index / 8) * line_width + x + in_block_index % 8;
+                    samples[index] = f32_to_u8(sample + 128.0);
+                }
+            }
+
+            // See JPEG A.1.1. Upsampling is done by replicating samples.
+            let hsf = component.horizontal_sampling_factor as usize;
+            let vsf = component.vertical_sampling_factor as usize;
+            let mut plane = Vec::with_capacity(width * height);
+            for y in 0..height {
+                let line = (y * vsf / max_block_vert_scale) * line_width;
+                for x in 0..width {
+                    plane.push(samples[line + x * hsf / max_block_hori_scale]);
+                }
+            }
+            planes.push(plane);
+        }
+
+        Ok(planes)
+    }
+
+    /// Decode a scan of a progressive frame. See JPEG G.1.2.
+    ///
+    /// `blocks` holds the coefficients of each component of the scan, in the
+    /// order of the scan header, as the earlier scans left them. It has the
+    /// blocks padding the image to a whole number of MCUs, all with 64
+    /// coefficients. The scan adds its coefficients, or bits of them, to these.
+    ///
+    /// Fails on corrupt entropy coded data, unless a concealment is set. Then
+    /// the scan ends at the corrupt data, and the blocks after it keep what
+    /// the earlier scans gave them. With `truncated`, running out of data ends
+    /// the scan too, and sets `is_incomplete`.
+    pub(crate) fn decode_progressive(&mut self, blocks: &mut [Vec<Block>]) -> Result<(), String> {
+        let (width, height) = self.dimensions;
+        let num_components = self.component_fields.len();
+        if width == 0 || height == 0 || num_components == 0 {
+            return Err("Scan has no samples".to_string());
+        }
+
+        // DC scans may be interleaved, AC scans have a single component, and
+        // the bits of a refinement scan follow those of the scan before it.
+        // See JPEG G.1.1.1.
+        let (start, end) = self.spectral_selection;
+        let (high, low) = self.successive_approximation;
+        let valid_selection = if start == 0 {
+            end == 0
+        } else {
+            start <= end && end <= 63 && num_components == 1
+        };
+        if !valid_selection || low > 13 || (high != 0 && high != low + 1) {
+            return Err(format!("Invalid progressive scan of coefficients {} to {}, with bits {} \
+                                and {}",
+                               start,
+                               end,
+                               high,
+                               low));
+        }
+
+        let (max_block_hori_scale, max_block_vert_scale) = self.max_sampling_factors;
+        let num_mcus_x = div_ceil(width, 8 * max_block_hori_scale);
+        let num_mcus_y = div_ceil(height, 8 * max_block_vert_scale);
+        let line_blocks: Vec<usize> = self.component_fields
             .iter()
-            .map(|block| {
-                block.iter()
-                    .map(|&(r, g, b)| {
-                        (f32_to_u8(r + 128.0), f32_to_u8(g + 128.0), f32_to_u8(b + 128.0))
-                    })
-                    .collect()
-            })
+            .map(|c| num_mcus_x * c.horizontal_sampling_factor as usize)
             .collect();
+        let too_short = self.component_fields
+            .iter()
+            .zip(&line_blocks)
+            .zip(blocks.iter())
+            .any(|((c, &line_blocks), blocks)| {
+                blocks.len() < line_blocks * num_mcus_y * c.vertical_sampling_factor as usize
+            });
+        if blocks.len() != num_components || too_short {
+            return Err("Coefficients do not match the scan".to_string());
+        }
 
-        let mut image_data: Vec<(u8, u8, u8)> = Vec::with_capacity(num_blocks * 64);
-        for block_y in 0..num_blocks_y {
-            for line in 0..8 {
-                for block_x in 0..num_blocks_x {
-                    let block_index = num_blocks_x * block_y + block_x;
-                    let ref block = rgb_blocks[block_index];
-                    for row in 0..8 {
-                        let in_block_index = 8 * line + row;
-                        image_data.push(block[in_block_index]);
-                    }
+        // Non-interleaved scans are not padded to whole MCUs, but
+        // only to whole blocks. See JPEG A.2.2.
+        let hsf = self.component_fields[0].horizontal_sampling_factor as usize;
+        let vsf = self.component_fields[0].vertical_sampling_factor as usize;
+        let (mcus_per_row, rows) = if num_components > 1 {
+            (num_mcus_x, num_mcus_y)
+        } else {
+            (div_ceil(div_ceil(width * hsf, max_block_hori_scale), 8),
+             div_ceil(div_ceil(height * vsf, max_block_vert_scale), 8))
+        };
+        let num_mcus = mcus_per_row * rows;
+
+        let (intervals, _) = split_intervals(self.data, false);
+        let restart_interval = self.restart_interval as usize;
+        let mut interval_i = 0;
+        let mut huffman_decoder = huffman::HuffmanDecoder::new(&intervals[0].data);
+        let mut previous_dc: Vec<i32> = vec![0; num_components];
+        // Number of blocks left that end before the coefficients of the scan.
+        let mut eob_run = 0;
+
+        for mcu in 0..num_mcus {
+            // Restart intervals reset the end of block runs too. See JPEG G.1.2.2.
+            let mut error = None;
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                error = self.check_restart(&intervals, interval_i, mcu);
+                if error.is_none() {
+                    interval_i += 1;
+                    huffman_decoder = huffman::HuffmanDecoder::new(&intervals[interval_i].data);
+                    previous_dc = vec![0; num_components];
+                    eob_run = 0;
+                }
+            }
+
+            if error.is_none() {
+                let positions = self.mcu_blocks(mcu / mcus_per_row,
+                                                mcu % mcus_per_row,
+                                                &line_blocks);
+                error = positions.iter()
+                    .map(|&(component_i, index)| {
+                        self.next_progressive_block(&mut huffman_decoder,
+                                                    component_i,
+                                                    &mut blocks[component_i][index],
+                                                    &mut previous_dc,
+                                                    &mut eob_run)
+                    })
+                    .find_map(|result| result.err())
+                    .map(|message| {
+                        let position = huffman_decoder.position() / 8;
+                        (message, intervals[interval_i].offset_of(position))
+                    });
+            }
+            if error.is_some() && self.truncated && interval_i + 1 == intervals.len() &&
+               huffman_decoder.is_near_end() {
+                self.incomplete = true;
+                break;
+            }
+            if let Some((message, offset)) = error {
+                let offset = self.offset + offset;
+                if self.concealment.is_none() {
+                    return Err(format!("{} at offset {}, in MCU {}", message, offset, mcu));
                 }
+                self.incidents.push(Incident {
+                    offset,
+                    mcu,
+                    lost_mcus: num_mcus - mcu,
+                    message,
+                });
+                break;
             }
         }
+        self.end = intervals[interval_i].end;
+        Ok(())
+    }
 
-        // A scan must end on a byte boundary. If we are into the next byte,
-        // increment by one. Subtract `4` for the four bytes taht are
-        // shifted into `current`.
-        let bytes_read = if huffman_decoder.bits_read() > 0 {
-            huffman_decoder.next_index() + 1
+    /// Read what a progressive scan holds of the next block of `component_i`
+    /// from `huffman_decoder`, into `block`.
+    fn next_progressive_block(&self,
+                              huffman_decoder: &mut huffman::HuffmanDecoder,
+                              component_i: usize,
+                              block: &mut Block,
+                              previous_dc: &mut [i32],
+                              eob_run: &mut u32)
+                              -> Result<(), String> {
+        let component = &self.component_fields[component_i];
+        let (start, end) = self.spectral_selection;
+        let (high, low) = self.successive_approximation;
+        if start == 0 {
+            // The DC coefficients are predicted like in sequential scans, and
+            // refined a bit at a time. See JPEG G.1.2.1.
+            if high == 0 {
+                let dc_table = self.dc_table(component.dc_table_id)?;
+                previous_dc[component_i] += huffman_decoder.next_dc_difference(dc_table)? as i32;
+                block[0] = previous_dc[component_i] << low;
+            } else if huffman_decoder.next_bit() {
+                block[0] |= 1 << low;
+            }
         } else {
-            huffman_decoder.next_index()
-        } - 4;
-
-        (image_data, bytes_read)
+            let ac_table = self.ac_table(component.ac_table_id)?;
+            let (start, end) = (start as usize, end as usize);
+            if high == 0 {
+                huffman_decoder.next_ac_first(ac_table, block, start, end, low, eob_run)?;
+            } else {
+                huffman_decoder.next_ac_refinement(ac_table, block, start, end, low, eob_run)?;
+            }
+        }
+        if huffman_decoder.is_past_end() {
+            return Err("Entropy coded data ends within a block".to_string());
+        }
+        Ok(())
     }
 }
 
-fn f32_to_u8(n: f32) -> u8 {
-    if n < 0.0 {
-        0
-    } else if n > 255.0 {
-        255
-    } else {
-        n as u8
+/// `a / b`, rounded up.
+pub(crate) fn div_ceil(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
+/// Fill the blocks of a lost MCU.
+fn conceal(blocks: &mut [Vec<Block>],
+           positions: &[(usize, usize)],
+           line_blocks: &[usize],
+           concealment: Concealment) {
+    for &(component_i, index) in positions {
+        let line_blocks = line_blocks[component_i];
+        let above = match concealment {
+            Concealment::CopyAbove if index >= line_blocks => {
+                blocks[component_i][index - line_blocks].clone()
+            }
+            _ => Vec::new(),
+        };
+        // All coefficients 0 is the middle value, after the level shift.
+        blocks[component_i][index] = if above.is_empty() { vec![0; 64] } else { above };
     }
 }
 
-fn y_cb_cr_to_rgb(y: f32, cb: f32, cr: f32) -> (f32, f32, f32) {
-    let c_red: f32 = 0.299;
-    let c_green: f32 = 0.587;
-    let c_blue: f32 = 0.114;
+/// Entropy coded data between restart markers. See JPEG E.1.4.
+struct Interval {
+    /// Offset of the first byte in the scan data
+    offset: usize,
+    /// Offset in the scan data of the marker after it, or of the end of the data
+    end: usize,
+    /// Number of the RSTn marker before it, or `None` for the first interval
+    restart_number: Option<u8>,
+    /// The data, without the stuffed zero bytes
+    data: Vec<u8>,
+    /// Indices in `data` of the X’FF’ bytes that were followed by a stuffed zero byte
+    stuffed: Vec<usize>,
+}
 
-    let r = cr * (2.0 - 2.0 * c_red) + y;
-    let b = cb * (2.0 - 2.0 * c_blue) + y;
-    let g = (y - c_blue * b - c_red * r) / c_green;
+impl Interval {
+    fn new(offset: usize, restart_number: Option<u8>) -> Interval {
+        Interval {
+            offset,
+            end: offset,
+            restart_number,
+            data: Vec::new(),
+            stuffed: Vec::new(),
+        }
+    }
 
-    (r, g, b)
+    /// Offset in the scan data of byte `index` of `data`.
+    fn offset_of(&self, index: usize) -> usize {
+        let index = index.min(self.data.len());
+        self.offset + index + self.stuffed.iter().take_while(|&&i| i < index).count()
+    }
 }
 
-fn expand_block_x_2(block: &Block) -> (Block, Block) {
-    // Expand a block along the x axis:
-    //
-    //  |1 2|      |1 1| |2 2|
-    //  |3 4|  --> |3 3| |4 4|
-
-    // Assume 8x8 block:
-    assert!(block.len() == 64,
-            "Implement me properly! (len={})",
-            block.len());
-
-    let mut block_a = Vec::new();
-    let mut block_b = Vec::new();
-    for (i, &n) in block.iter().enumerate() {
-        let is_block_a = i % 8 < 4;
-        if is_block_a {
-            block_a.push(n);
-            block_a.push(n);
-        } else {
-            block_b.push(n);
-            block_b.push(n);
+/// Split the entropy coded data of a scan at its RSTn markers, and remove
+/// the stuffed zero bytes. The data ends at any other marker, unless
+/// `resync` is set: then anything up to the next RSTn is skipped, and the
+/// data ends at EOI. Returns the intervals, and the offset where the data ends.
+fn split_intervals(data: &[u8], resync: bool) -> (Vec<Interval>, usize) {
+    let mut intervals = vec![Interval::new(0, None)];
+    // Whether the bytes are skipped, after a marker that is not RSTn
+    let mut skipping = false;
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0xff && i + 1 < data.len() {
+            match data[i + 1] {
+                0xd9 => break,
+                0xd0..=0xd7 => {
+                    intervals.last_mut().unwrap().end = i;
+                    intervals.push(Interval::new(i + 2, Some(data[i + 1] - 0xd0)));
+                    skipping = false;
+                    i += 2;
+                    continue;
+                }
+                _ if skipping => {
+                    i += 1;
+                    continue;
+                }
+                0x00 => {
+                    let interval = intervals.last_mut().unwrap();
+                    interval.stuffed.push(interval.data.len());
+                    interval.data.push(0xff);
+                    i += 2;
+                    continue;
+                }
+                // Fill byte
+                0xff => {
+                    i += 1;
+                    continue;
+                }
+                _ if resync => {
+                    intervals.last_mut().unwrap().end = i;
+                    skipping = true;
+                    i += 2;
+                    continue;
+                }
+                _ => break,
+            }
         }
+        if !skipping {
+            intervals.last_mut().unwrap().data.push(data[i]);
+        }
+        i += 1;
+    }
+    let end = i.min(data.len());
+    if !skipping {
+        intervals.last_mut().unwrap().end = end;
     }
-    (block_a, block_b)
+    (intervals, end)
 }
 
-// hardcode dis shit lol
-const ZIGZAG_INDICES: [usize; 64] =
+/// Index in row by row order of each coefficient in zigzag order,
+/// the order of coefficients in DQT segments and scans. See JPEG figure A.6.
+pub const ZIGZAG_INDICES: [usize; 64] =
     [0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
      20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
      59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63];
 #[allow(dead_code)]
-fn zigzag<T>(vec: &Vec<T>) -> Vec<T>
+fn zigzag<T>(vec: &[T]) -> Vec<T>
     where T: Copy
 {
     if vec.len() != 64 {
@@ -404,7 +929,7 @@ fn zigzag_inverse<I>(iter: I) -> Vec<I::Item>
           I::Item: Default,
           I::Item: Debug
 {
-    let mut res: Vec<I::Item> = repeat(Default::default()).take(64).collect();
+    let mut res: Vec<I::Item> = std::iter::repeat(Default::default()).take(64).collect();
     for (zig_index, number) in iter.enumerate() {
         let original_index = ZIGZAG_INDICES[zig_index];
         res[original_index] = number;
@@ -0,0 +1,1273 @@
+use std::collections::HashSet;
+use std::str;
+
+/// Tags used by the typed accessors of `Exif`.
+/// See the Exif 2.3 specification, section 4.6.
+pub mod tags {
+    pub const MAKE: u16 = 0x010f;
+    pub const MODEL: u16 = 0x0110;
+    pub const ORIENTATION: u16 = 0x0112;
+    pub const DATE_TIME: u16 = 0x0132;
+    pub const JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+    pub const JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+    pub const EXPOSURE_TIME: u16 = 0x829a;
+    pub const F_NUMBER: u16 = 0x829d;
+    pub const EXIF_IFD_POINTER: u16 = 0x8769;
+    pub const GPS_IFD_POINTER: u16 = 0x8825;
+    pub const ISO_SPEED_RATINGS: u16 = 0x8827;
+    pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+    pub const FOCAL_LENGTH: u16 = 0x920a;
+    pub const MAKER_NOTE: u16 = 0x927c;
+    pub const INTEROPERABILITY_IFD_POINTER: u16 = 0xa005;
+    pub const BODY_SERIAL_NUMBER: u16 = 0xa431;
+    pub const LENS_SERIAL_NUMBER: u16 = 0xa435;
+
+    pub const GPS_LATITUDE_REF: u16 = 0x0001;
+    pub const GPS_LATITUDE: u16 = 0x0002;
+    pub const GPS_LONGITUDE_REF: u16 = 0x0003;
+    pub const GPS_LONGITUDE: u16 = 0x0004;
+    pub const GPS_ALTITUDE_REF: u16 = 0x0005;
+    pub const GPS_ALTITUDE: u16 = 0x0006;
+}
+
+/// Byte order of the TIFF structure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    /// "II"
+    LittleEndian,
+    /// "MM"
+    BigEndian,
+}
+
+impl ByteOrder {
+    pub(crate) fn u16(&self, bytes: &[u8]) -> u16 {
+        match *self {
+            ByteOrder::LittleEndian => (bytes[1] as u16) << 8 | bytes[0] as u16,
+            ByteOrder::BigEndian => (bytes[0] as u16) << 8 | bytes[1] as u16,
+        }
+    }
+
+    pub(crate) fn u32(&self, bytes: &[u8]) -> u32 {
+        match *self {
+            ByteOrder::LittleEndian => {
+                (self.u16(&bytes[2..]) as u32) << 16 | self.u16(bytes) as u32
+            }
+            ByteOrder::BigEndian => (self.u16(bytes) as u32) << 16 | self.u16(&bytes[2..]) as u32,
+        }
+    }
+
+    fn u16_bytes(&self, n: u16) -> [u8; 2] {
+        match *self {
+            ByteOrder::LittleEndian => n.to_le_bytes(),
+            ByteOrder::BigEndian => n.to_be_bytes(),
+        }
+    }
+
+    pub(crate) fn u32_bytes(&self, n: u32) -> [u8; 4] {
+        match *self {
+            ByteOrder::LittleEndian => n.to_le_bytes(),
+            ByteOrder::BigEndian => n.to_be_bytes(),
+        }
+    }
+
+    fn u64_bytes(&self, n: u64) -> [u8; 8] {
+        match *self {
+            ByteOrder::LittleEndian => n.to_le_bytes(),
+            ByteOrder::BigEndian => n.to_be_bytes(),
+        }
+    }
+
+    fn u64(&self, bytes: &[u8]) -> u64 {
+        match *self {
+            ByteOrder::LittleEndian => {
+                (self.u32(&bytes[4..]) as u64) << 32 | self.u32(bytes) as u64
+            }
+            ByteOrder::BigEndian => (self.u32(bytes) as u64) << 32 | self.u32(&bytes[4..]) as u64,
+        }
+    }
+}
+
+/// The IFD an entry belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ifd {
+    /// IFD0, describing the primary image
+    Primary,
+    /// Exif private tags, pointed to from IFD0
+    Exif,
+    /// GPS tags, pointed to from IFD0
+    Gps,
+    /// Interoperability tags, pointed to from the Exif IFD
+    Interop,
+    /// IFD1, describing the thumbnail
+    Thumbnail,
+}
+
+/// The value of an IFD entry, decoded according to its field type.
+/// See TIFF 6.0, section 2.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(Vec<u8>),
+    /// NUL terminated string, as written, with the terminator and any
+    /// padding after it. See `as_str` for the text.
+    Ascii(Vec<u8>),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    /// Numerator and denominator
+    Rational(Vec<(u32, u32)>),
+    SByte(Vec<i8>),
+    Undefined(Vec<u8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    /// Numerator and denominator
+    SRational(Vec<(i32, i32)>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    /// A field type not defined by TIFF 6.0, with its raw bytes. Only the
+    /// IFD type and the 8 byte types of BigTIFF have a known size, for other
+    /// types the bytes are those of the entry itself, and can not be written.
+    Unknown(u16, Vec<u8>),
+}
+
+impl Value {
+    /// Size in bytes of a single value of the given field type.
+    fn type_size(field_type: u16) -> Option<usize> {
+        match field_type {
+            1 | 2 | 6 | 7 => Some(1),
+            3 | 8 => Some(2),
+            4 | 9 | 11 | 13 => Some(4),
+            5 | 10 | 12 | 16 | 17 | 18 => Some(8),
+            _ => None,
+        }
+    }
+
+    fn decode(field_type: u16, count: usize, bytes: &[u8], byte_order: ByteOrder) -> Value {
+        let chunks = |size: usize| bytes.chunks(size).take(count);
+        match field_type {
+            1 => Value::Byte(bytes.to_vec()),
+            2 => Value::Ascii(bytes.to_vec()),
+            3 => Value::Short(chunks(2).map(|b| byte_order.u16(b)).collect()),
+            4 => Value::Long(chunks(4).map(|b| byte_order.u32(b)).collect()),
+            5 => {
+                Value::Rational(chunks(8)
+                    .map(|b| (byte_order.u32(b), byte_order.u32(&b[4..])))
+                    .collect())
+            }
+            6 => Value::SByte(bytes.iter().map(|&b| b as i8).collect()),
+            7 => Value::Undefined(bytes.to_vec()),
+            8 => Value::SShort(chunks(2).map(|b| byte_order.u16(b) as i16).collect()),
+            9 => Value::SLong(chunks(4).map(|b| byte_order.u32(b) as i32).collect()),
+            10 => {
+                Value::SRational(chunks(8)
+                    .map(|b| (byte_order.u32(b) as i32, byte_order.u32(&b[4..]) as i32))
+                    .collect())
+            }
+            11 => Value::Float(chunks(4).map(|b| f32::from_bits(byte_order.u32(b))).collect()),
+            12 => Value::Double(chunks(8).map(|b| f64::from_bits(byte_order.u64(b))).collect()),
+            _ => Value::Unknown(field_type, bytes.to_vec()),
+        }
+    }
+
+    /// The TIFF field type of the value.
+    pub fn field_type(&self) -> u16 {
+        match *self {
+            Value::Byte(_) => 1,
+            Value::Ascii(_) => 2,
+            Value::Short(_) => 3,
+            Value::Long(_) => 4,
+            Value::Rational(_) => 5,
+            Value::SByte(_) => 6,
+            Value::Undefined(_) => 7,
+            Value::SShort(_) => 8,
+            Value::SLong(_) => 9,
+            Value::SRational(_) => 10,
+            Value::Float(_) => 11,
+            Value::Double(_) => 12,
+            Value::Unknown(field_type, _) => field_type,
+        }
+    }
+
+    /// A string value, with its NUL terminator.
+    pub fn ascii(s: &str) -> Value {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        Value::Ascii(bytes)
+    }
+
+    /// Number of values, as written in the IFD entry.
+    pub fn count(&self) -> u32 {
+        let count = match *self {
+            Value::Byte(ref v) |
+            Value::Ascii(ref v) |
+            Value::Undefined(ref v) => v.len(),
+            Value::Unknown(field_type, ref v) => {
+                v.len() / Value::type_size(field_type).unwrap_or(1)
+            }
+            Value::Short(ref v) => v.len(),
+            Value::Long(ref v) => v.len(),
+            Value::Rational(ref v) => v.len(),
+            Value::SByte(ref v) => v.len(),
+            Value::SShort(ref v) => v.len(),
+            Value::SLong(ref v) => v.len(),
+            Value::SRational(ref v) => v.len(),
+            Value::Float(ref v) => v.len(),
+            Value::Double(ref v) => v.len(),
+        };
+        count as u32
+    }
+
+    fn encode(&self, byte_order: ByteOrder) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match *self {
+            Value::Byte(ref v) |
+            Value::Ascii(ref v) |
+            Value::Undefined(ref v) |
+            Value::Unknown(_, ref v) => bytes.extend_from_slice(v),
+            Value::Short(ref v) => {
+                for &n in v {
+                    bytes.extend_from_slice(&byte_order.u16_bytes(n));
+                }
+            }
+            Value::Long(ref v) => {
+                for &n in v {
+                    bytes.extend_from_slice(&byte_order.u32_bytes(n));
+                }
+            }
+            Value::Rational(ref v) => {
+                for &(n, d) in v {
+                    bytes.extend_from_slice(&byte_order.u32_bytes(n));
+                    bytes.extend_from_slice(&byte_order.u32_bytes(d));
+                }
+            }
+            Value::SByte(ref v) => bytes.extend(v.iter().map(|&n| n as u8)),
+            Value::SShort(ref v) => {
+                for &n in v {
+                    bytes.extend_from_slice(&byte_order.u16_bytes(n as u16));
+                }
+            }
+            Value::SLong(ref v) => {
+                for &n in v {
+                    bytes.extend_from_slice(&byte_order.u32_bytes(n as u32));
+                }
+            }
+            Value::SRational(ref v) => {
+                for &(n, d) in v {
+                    bytes.extend_from_slice(&byte_order.u32_bytes(n as u32));
+                    bytes.extend_from_slice(&byte_order.u32_bytes(d as u32));
+                }
+            }
+            Value::Float(ref v) => {
+                for &n in v {
+                    bytes.extend_from_slice(&byte_order.u32_bytes(n.to_bits()));
+                }
+            }
+            Value::Double(ref v) => {
+                for &n in v {
+                    bytes.extend_from_slice(&byte_order.u64_bytes(n.to_bits()));
+                }
+            }
+        }
+        bytes
+    }
+
+    /// The `index`th value as an unsigned integer, for integer types.
+    pub fn get_u32(&self, index: usize) -> Option<u32> {
+        match *self {
+            Value::Byte(ref v) |
+            Value::Undefined(ref v) => v.get(index).map(|&n| n as u32),
+            Value::Short(ref v) => v.get(index).map(|&n| n as u32),
+            Value::Long(ref v) => v.get(index).cloned(),
+            _ => None,
+        }
+    }
+
+    /// The `index`th value as a float, for numeric types.
+    pub fn get_f64(&self, index: usize) -> Option<f64> {
+        match *self {
+            Value::Rational(ref v) => v.get(index).map(|&(n, d)| n as f64 / d as f64),
+            Value::SRational(ref v) => v.get(index).map(|&(n, d)| n as f64 / d as f64),
+            Value::SByte(ref v) => v.get(index).map(|&n| n as f64),
+            Value::SShort(ref v) => v.get(index).map(|&n| n as f64),
+            Value::SLong(ref v) => v.get(index).map(|&n| n as f64),
+            Value::Float(ref v) => v.get(index).map(|&n| n as f64),
+            Value::Double(ref v) => v.get(index).cloned(),
+            _ => self.get_u32(index).map(|n| n as f64),
+        }
+    }
+
+    /// The text of a string value, up to the first NUL, if it is UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::Ascii(ref v) => {
+                let end = v.iter().position(|&b| b == 0).unwrap_or(v.len());
+                str::from_utf8(&v[..end]).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A single IFD entry.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The IFD the entry was found in
+    pub ifd: Ifd,
+    pub tag: u16,
+    /// TIFF field type, see `Value`
+    pub field_type: u16,
+    /// Number of values, not bytes
+    pub count: u32,
+    pub value: Value,
+}
+
+/// GPS position, in degrees and meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsPosition {
+    /// Positive north of the equator
+    pub latitude: f64,
+    /// Positive east of the prime meridian
+    pub longitude: f64,
+    /// Positive above sea level
+    pub altitude: Option<f64>,
+}
+
+/// Where an offset in a written TIFF structure points to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Ifd(Ifd),
+    Thumbnail,
+    MakerNote,
+}
+
+/// An entry to write, with its value as bytes, or as the offset of a target.
+struct Field {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    bytes: Vec<u8>,
+    target: Option<Target>,
+}
+
+/// Whether the entry holds an offset that is rewritten when writing.
+fn is_offset(ifd: Ifd, tag: u16) -> bool {
+    match ifd {
+        Ifd::Primary => tag == tags::EXIF_IFD_POINTER || tag == tags::GPS_IFD_POINTER,
+        Ifd::Exif => tag == tags::INTEROPERABILITY_IFD_POINTER,
+        Ifd::Thumbnail => {
+            tag == tags::JPEG_INTERCHANGE_FORMAT || tag == tags::JPEG_INTERCHANGE_FORMAT_LENGTH
+        }
+        _ => false,
+    }
+}
+
+/// Whether the entry holds an offset into the old layout that `to_bytes`
+/// does not rewrite, like SubIFDs, StripOffsets or the JPEG tables of
+/// TIFF 6.0 section 22, or an IFD pointer in an IFD it does not belong to.
+fn is_stale_offset(entry: &Entry) -> bool {
+    // IFD and IFD8
+    if entry.value.field_type() == 13 || entry.value.field_type() == 18 {
+        return true;
+    }
+    let offset_tags = [0x0111,
+                       0x0120,
+                       0x0144,
+                       0x014a,
+                       0x0207,
+                       0x0208,
+                       0x0209,
+                       tags::JPEG_INTERCHANGE_FORMAT,
+                       tags::EXIF_IFD_POINTER,
+                       tags::GPS_IFD_POINTER,
+                       tags::INTEROPERABILITY_IFD_POINTER];
+    offset_tags.contains(&entry.tag) && !is_offset(entry.ifd, entry.tag)
+}
+
+/// Size of an IFD with `fields`, as written by `Exif::write_ifd`.
+fn ifd_size(fields: &[Field]) -> usize {
+    let values: usize = fields.iter()
+        .filter(|field| field.target.is_none() && field.bytes.len() > 4)
+        .map(|field| (field.bytes.len() + 1) & !1)
+        .sum();
+    2 + fields.len() * 12 + 4 + values
+}
+
+/// Exif data, as found in APP1 after the `Exif\0\0` identifier.
+#[derive(Debug, Clone)]
+pub struct Exif {
+    /// The TIFF structure, starting at the byte order mark
+    data: Vec<u8>,
+    byte_order: ByteOrder,
+    /// All entries of all IFDs, in the order they were read
+    entries: Vec<Entry>,
+    /// Offset of the maker note in `data`, if it is not in its entry
+    maker_note_offset: Option<usize>,
+    /// Offsets of the IFDs that were read, in `data`
+    ifd_offsets: Vec<(Ifd, usize)>,
+    /// Why IFDs other than IFD0 were skipped
+    errors: Vec<String>,
+}
+
+impl Exif {
+    /// Parse the TIFF structure in `data`, starting with the byte order mark.
+    pub fn parse(data: &[u8]) -> Result<Exif, String> {
+        if data.len() < 8 {
+            return Err("Exif data is too short for a TIFF header".to_string());
+        }
+        let byte_order = match &data[0..2] {
+            b"II" => ByteOrder::LittleEndian,
+            b"MM" => ByteOrder::BigEndian,
+            _ => return Err(format!("Unknown TIFF byte order: {:?}", &data[0..2])),
+        };
+        if byte_order.u16(&data[2..]) != 42 {
+            return Err("Missing TIFF magic number".to_string());
+        }
+
+        let mut exif = Exif {
+            data: data.to_vec(),
+            byte_order,
+            entries: Vec::new(),
+            maker_note_offset: None,
+            ifd_offsets: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        // IFDs may point back to an IFD that is already read. Only read each once.
+        let mut visited = HashSet::new();
+        let ifd0_offset = byte_order.u32(&data[4..]) as usize;
+        let ifd1_offset = exif.read_ifd(Ifd::Primary, ifd0_offset, &mut visited)?;
+
+        // A broken IFD pointed to from another is skipped, so the rest can still be used.
+        let sub_ifds = [(Ifd::Primary, tags::EXIF_IFD_POINTER, Ifd::Exif),
+                        (Ifd::Primary, tags::GPS_IFD_POINTER, Ifd::Gps),
+                        (Ifd::Exif, tags::INTEROPERABILITY_IFD_POINTER, Ifd::Interop)];
+        for &(parent, tag, ifd) in sub_ifds.iter() {
+            let offset = exif.get(parent, tag).and_then(|entry| entry.value.get_u32(0));
+            if let Some(offset) = offset {
+                if let Err(error) = exif.read_ifd(ifd, offset as usize, &mut visited) {
+                    exif.errors.push(error);
+                }
+            }
+        }
+
+        if ifd1_offset != 0 {
+            if let Err(error) = exif.read_ifd(Ifd::Thumbnail, ifd1_offset, &mut visited) {
+                exif.errors.push(error);
+            }
+        }
+
+        Ok(exif)
+    }
+
+    /// Read the entries of the IFD at `offset`, and return the offset of the next IFD.
+    /// Nothing is added if the IFD is broken.
+    fn read_ifd(&mut self,
+                ifd: Ifd,
+                offset: usize,
+                visited: &mut HashSet<usize>)
+                -> Result<usize, String> {
+        if !visited.insert(offset) {
+            return Ok(0);
+        }
+        let data = &self.data;
+        let byte_order = self.byte_order;
+        let mut maker_note_offset = None;
+        let mut entries = Vec::new();
+        if offset + 2 > data.len() {
+            return Err(format!("{:?} IFD offset {} is out of bounds", ifd, offset));
+        }
+        let num_entries = byte_order.u16(&data[offset..]) as usize;
+        let entries_end = offset + 2 + num_entries * 12;
+        if entries_end + 4 > data.len() {
+            return Err(format!("{:?} IFD at {} has too many entries", ifd, offset));
+        }
+
+        for entry_offset in (offset + 2..entries_end).step_by(12) {
+            let entry = &data[entry_offset..entry_offset + 12];
+            let tag = byte_order.u16(entry);
+            let field_type = byte_order.u16(&entry[2..]);
+            let count = byte_order.u32(&entry[4..]);
+
+            let value = match Value::type_size(field_type) {
+                Some(size) => {
+                    let length = size as u64 * count as u64;
+                    let value_offset = if length <= 4 {
+                        entry_offset as u64 + 8
+                    } else {
+                        byte_order.u32(&entry[8..]) as u64
+                    };
+                    if value_offset + length > data.len() as u64 {
+                        return Err(format!("Value of tag {:#06x} in {:?} IFD is out of bounds",
+                                           tag,
+                                           ifd));
+                    }
+                    if ifd == Ifd::Exif && tag == tags::MAKER_NOTE && length > 4 {
+                        maker_note_offset = Some(value_offset as usize);
+                    }
+                    let bytes = &data[value_offset as usize..(value_offset + length) as usize];
+                    Value::decode(field_type, count as usize, bytes, byte_order)
+                }
+                // The size of unknown types is unknown, so only the four
+                // bytes of the entry itself are kept. They may be an offset.
+                None => Value::Unknown(field_type, entry[8..12].to_vec()),
+            };
+
+            entries.push(Entry {
+                ifd,
+                tag,
+                field_type,
+                count,
+                value,
+            });
+        }
+
+        self.entries.extend(entries);
+        self.ifd_offsets.push((ifd, offset));
+        if maker_note_offset.is_some() {
+            self.maker_note_offset = maker_note_offset;
+        }
+        Ok(byte_order.u32(&data[entries_end..]) as usize)
+    }
+
+    /// The TIFF structure, starting at the byte order mark.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Why IFDs other than IFD0, like the GPS IFD or IFD1, were skipped.
+    /// Their entries are missing, but the other IFDs are read as usual.
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// All entries, including the ones without typed accessors.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Find the entry with `tag` in `ifd`.
+    pub fn get(&self, ifd: Ifd, tag: u16) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.ifd == ifd && entry.tag == tag)
+    }
+
+    /// Set the value of the entry with `tag` in `ifd`, adding the entry if there is none.
+    pub fn set(&mut self, ifd: Ifd, tag: u16, value: Value) {
+        let field_type = value.field_type();
+        let count = value.count();
+        match self.entries.iter_mut().find(|entry| entry.ifd == ifd && entry.tag == tag) {
+            Some(entry) => {
+                entry.field_type = field_type;
+                entry.count = count;
+                entry.value = value;
+            }
+            None => {
+                self.entries.push(Entry {
+                    ifd,
+                    tag,
+                    field_type,
+                    count,
+                    value,
+                })
+            }
+        }
+    }
+
+    /// Remove the entry with `tag` in `ifd`, and return it.
+    pub fn remove(&mut self, ifd: Ifd, tag: u16) -> Option<Entry> {
+        let index = self.entries.iter().position(|entry| entry.ifd == ifd && entry.tag == tag)?;
+        Some(self.entries.remove(index))
+    }
+
+    /// Remove all entries of `ifd`. An IFD without entries is not written.
+    pub fn remove_ifd(&mut self, ifd: Ifd) {
+        self.entries.retain(|entry| entry.ifd != ifd);
+    }
+
+    /// Remove the GPS position, and everything else in the GPS IFD.
+    pub fn remove_gps(&mut self) {
+        self.remove_ifd(Ifd::Gps);
+    }
+
+    /// Remove the serial numbers of the camera body and lens.
+    pub fn remove_serial_numbers(&mut self) {
+        self.remove(Ifd::Exif, tags::BODY_SERIAL_NUMBER);
+        self.remove(Ifd::Exif, tags::LENS_SERIAL_NUMBER);
+    }
+
+    pub fn set_orientation(&mut self, orientation: u16) {
+        self.set(Ifd::Primary, tags::ORIENTATION, Value::Short(vec![orientation]));
+    }
+
+    /// Set the date and time the picture was taken, as "YYYY:MM:DD HH:MM:SS".
+    pub fn set_date_time_original(&mut self, date_time: &str) {
+        self.set(Ifd::Exif, tags::DATE_TIME_ORIGINAL, Value::ascii(date_time));
+    }
+
+    fn get_str(&self, ifd: Ifd, tag: u16) -> Option<&str> {
+        self.get(ifd, tag).and_then(|entry| entry.value.as_str())
+    }
+
+    /// Orientation of the stored pixels, from 1 to 8. See the Exif specification, 4.6.4.
+    pub fn orientation(&self) -> Option<u16> {
+        self.get(Ifd::Primary, tags::ORIENTATION)
+            .and_then(|entry| entry.value.get_u32(0))
+            .map(|n| n as u16)
+    }
+
+    /// Date and time the file was changed, as "YYYY:MM:DD HH:MM:SS".
+    pub fn date_time(&self) -> Option<&str> {
+        self.get_str(Ifd::Primary, tags::DATE_TIME)
+    }
+
+    /// Date and time the picture was taken, as "YYYY:MM:DD HH:MM:SS".
+    pub fn date_time_original(&self) -> Option<&str> {
+        self.get_str(Ifd::Exif, tags::DATE_TIME_ORIGINAL)
+    }
+
+    /// Camera manufacturer
+    pub fn make(&self) -> Option<&str> {
+        self.get_str(Ifd::Primary, tags::MAKE)
+    }
+
+    /// Camera model
+    pub fn model(&self) -> Option<&str> {
+        self.get_str(Ifd::Primary, tags::MODEL)
+    }
+
+    /// Exposure time in seconds, as numerator and denominator.
+    pub fn exposure_time(&self) -> Option<(u32, u32)> {
+        match self.get(Ifd::Exif, tags::EXPOSURE_TIME).map(|entry| &entry.value) {
+            Some(Value::Rational(v)) => v.first().cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn f_number(&self) -> Option<f64> {
+        self.get(Ifd::Exif, tags::F_NUMBER).and_then(|entry| entry.value.get_f64(0))
+    }
+
+    pub fn iso_speed(&self) -> Option<u32> {
+        self.get(Ifd::Exif, tags::ISO_SPEED_RATINGS).and_then(|entry| entry.value.get_u32(0))
+    }
+
+    /// Focal length in millimeters
+    pub fn focal_length(&self) -> Option<f64> {
+        self.get(Ifd::Exif, tags::FOCAL_LENGTH).and_then(|entry| entry.value.get_f64(0))
+    }
+
+    /// The JPEG thumbnail in IFD1, as stored. See the Exif specification, 4.5.5.
+    /// Returns `None` if it is missing, or if it does not fit in the Exif data.
+    pub fn thumbnail(&self) -> Option<&[u8]> {
+        let tag = |tag: u16| {
+            self.get(Ifd::Thumbnail, tag)
+                .and_then(|entry| entry.value.get_u32(0))
+                .map(|n| n as usize)
+        };
+        let offset = tag(tags::JPEG_INTERCHANGE_FORMAT)?;
+        let length = tag(tags::JPEG_INTERCHANGE_FORMAT_LENGTH).filter(|&n| n > 0)?;
+        self.data.get(offset..offset.checked_add(length)?)
+    }
+
+    pub fn gps_position(&self) -> Option<GpsPosition> {
+        // Degrees, minutes and seconds, with a reference of N/S or E/W.
+        let coordinate = |tag: u16, ref_tag: u16, negative: &str| -> Option<f64> {
+            let value = &self.get(Ifd::Gps, tag)?.value;
+            let degrees = value.get_f64(0)? + value.get_f64(1).unwrap_or(0.0) / 60.0 +
+                          value.get_f64(2).unwrap_or(0.0) / 3600.0;
+            match self.get_str(Ifd::Gps, ref_tag) {
+                Some(r) if r == negative => Some(-degrees),
+                _ => Some(degrees),
+            }
+        };
+        let latitude = coordinate(tags::GPS_LATITUDE, tags::GPS_LATITUDE_REF, "S")?;
+        let longitude = coordinate(tags::GPS_LONGITUDE, tags::GPS_LONGITUDE_REF, "W")?;
+        let altitude = self.get(Ifd::Gps, tags::GPS_ALTITUDE)
+            .and_then(|entry| entry.value.get_f64(0))
+            .map(|altitude| {
+                // Reference 1 means below sea level
+                match self.get(Ifd::Gps, tags::GPS_ALTITUDE_REF)
+                    .and_then(|entry| entry.value.get_u32(0)) {
+                    Some(1) => -altitude,
+                    _ => altitude,
+                }
+            });
+        Some(GpsPosition {
+            latitude,
+            longitude,
+            altitude,
+        })
+    }
+
+    /// Write the TIFF structure, in the same byte order, with all offsets
+    /// rewritten for the new layout. The thumbnail is copied along.
+    ///
+    /// Maker notes often hold offsets of their own, which can not be rewritten,
+    /// so the maker note is always kept at the same offset. IFDs that were
+    /// after it, or that no longer fit before it, are written after it.
+    /// Entries with a field type of unknown size, and offsets that are not
+    /// rewritten, like SubIFDs, are left out.
+    ///
+    /// Returns an error if the maker note overlaps the TIFF header.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let byte_order = self.byte_order;
+        let mut out = match byte_order {
+            ByteOrder::LittleEndian => b"II".to_vec(),
+            ByteOrder::BigEndian => b"MM".to_vec(),
+        };
+        out.extend_from_slice(&byte_order.u16_bytes(42));
+        out.extend_from_slice(&[0; 4]);
+
+        // Offsets are rewritten or left out, as are values of unknown size,
+        // which may be offsets into the old layout.
+        let is_copied = |entry: &Entry| {
+            !is_offset(entry.ifd, entry.tag) && !is_stale_offset(entry) &&
+            Value::type_size(entry.value.field_type()).is_some()
+        };
+        // An IFD is written if it has entries, or if an IFD it points to is written.
+        let has_entries = |ifd: Ifd| {
+            self.entries.iter().any(|entry| entry.ifd == ifd && is_copied(entry))
+        };
+        let thumbnail = self.thumbnail();
+        let interop = has_entries(Ifd::Interop);
+        let gps = has_entries(Ifd::Gps);
+        let exif = has_entries(Ifd::Exif) || interop;
+        let thumbnail_ifd = has_entries(Ifd::Thumbnail) || thumbnail.is_some();
+
+        let maker_note = self.get(Ifd::Exif, tags::MAKER_NOTE)
+            .map(|entry| entry.value.encode(byte_order))
+            .filter(|bytes| bytes.len() > 4);
+        if let (Some(_), Some(offset)) = (&maker_note, self.maker_note_offset) {
+            if offset < out.len() {
+                return Err(format!("Maker note at {} overlaps the TIFF header", offset));
+            }
+        }
+
+        // Offsets of the targets, and where offsets pointing to them are written
+        let mut targets: Vec<(Target, usize)> = Vec::new();
+        let mut fixups: Vec<(usize, Target)> = vec![(4, Target::Ifd(Ifd::Primary))];
+        let long = |tag: u16, n: u32, target: Option<Target>| {
+            Field {
+                tag,
+                field_type: 4,
+                count: 1,
+                bytes: byte_order.u32_bytes(n).to_vec(),
+                target,
+            }
+        };
+
+        let after_maker_note = |ifd: Ifd| {
+            let offset = self.ifd_offsets.iter().find(|&&(i, _)| i == ifd).map(|&(_, o)| o);
+            match (self.maker_note_offset, offset) {
+                (Some(maker_note_offset), Some(offset)) => offset > maker_note_offset,
+                _ => false,
+            }
+        };
+        let ifds = [(Ifd::Primary, true),
+                    (Ifd::Exif, exif),
+                    (Ifd::Interop, interop),
+                    (Ifd::Gps, gps),
+                    (Ifd::Thumbnail, thumbnail_ifd)];
+        let before = ifds.iter().filter(|&&(ifd, _)| !after_maker_note(ifd));
+        let after = ifds.iter().filter(|&&(ifd, _)| after_maker_note(ifd));
+        let has_maker_note = maker_note.is_some();
+        let mut maker_note = maker_note;
+        for &(ifd, written) in before.chain(after) {
+            if !written {
+                continue;
+            }
+            let mut fields: Vec<Field> = self.entries
+                .iter()
+                .filter(|entry| entry.ifd == ifd && is_copied(entry))
+                .map(|entry| {
+                    let is_maker_note = ifd == Ifd::Exif && entry.tag == tags::MAKER_NOTE &&
+                                        has_maker_note;
+                    Field {
+                        tag: entry.tag,
+                        field_type: entry.value.field_type(),
+                        count: entry.value.count(),
+                        bytes: if is_maker_note {
+                            vec![0; 4]
+                        } else {
+                            entry.value.encode(byte_order)
+                        },
+                        target: if is_maker_note {
+                            Some(Target::MakerNote)
+                        } else {
+                            None
+                        },
+                    }
+                })
+                .collect();
+            match ifd {
+                Ifd::Primary => {
+                    if exif {
+                        fields.push(long(tags::EXIF_IFD_POINTER, 0, Some(Target::Ifd(Ifd::Exif))));
+                    }
+                    if gps {
+                        fields.push(long(tags::GPS_IFD_POINTER, 0, Some(Target::Ifd(Ifd::Gps))));
+                    }
+                }
+                Ifd::Exif if interop => {
+                    fields.push(long(tags::INTEROPERABILITY_IFD_POINTER,
+                                     0,
+                                     Some(Target::Ifd(Ifd::Interop))));
+                }
+                Ifd::Thumbnail => {
+                    if let Some(thumbnail) = thumbnail {
+                        fields.push(long(tags::JPEG_INTERCHANGE_FORMAT,
+                                         0,
+                                         Some(Target::Thumbnail)));
+                        fields.push(long(tags::JPEG_INTERCHANGE_FORMAT_LENGTH,
+                                         thumbnail.len() as u32,
+                                         None));
+                    }
+                }
+                _ => {}
+            }
+            // TIFF 6.0, section 2: entries are sorted by tag.
+            fields.sort_by_key(|field| field.tag);
+
+            let fits_before_maker_note = match self.maker_note_offset {
+                Some(offset) => out.len() + ifd_size(&fields) <= offset,
+                None => true,
+            };
+            if after_maker_note(ifd) || !fits_before_maker_note {
+                if let Some(maker_note) = maker_note.take() {
+                    self.write_maker_note(&mut out, &maker_note, &mut targets);
+                }
+            }
+
+            targets.push((Target::Ifd(ifd), out.len()));
+            let next = if ifd == Ifd::Primary && thumbnail_ifd {
+                Some(Target::Ifd(Ifd::Thumbnail))
+            } else {
+                None
+            };
+            self.write_ifd(&mut out, &fields, next, &mut fixups);
+        }
+
+        if let Some(maker_note) = maker_note {
+            self.write_maker_note(&mut out, &maker_note, &mut targets);
+        }
+        if let Some(thumbnail) = thumbnail {
+            targets.push((Target::Thumbnail, out.len()));
+            out.extend_from_slice(thumbnail);
+        }
+
+        for (position, target) in fixups {
+            if let Some(&(_, offset)) = targets.iter().find(|&&(t, _)| t == target) {
+                out[position..position + 4].copy_from_slice(&byte_order.u32_bytes(offset as u32));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Write the maker note at its old offset, which must not be before the
+    /// end of `out`, or at the end of `out` if it is new. What follows it
+    /// starts at a word boundary.
+    fn write_maker_note(&self,
+                        out: &mut Vec<u8>,
+                        maker_note: &[u8],
+                        targets: &mut Vec<(Target, usize)>) {
+        let offset = self.maker_note_offset.unwrap_or(out.len());
+        out.resize(offset, 0);
+        targets.push((Target::MakerNote, offset));
+        out.extend_from_slice(maker_note);
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+
+    /// Write an IFD at the end of `out`, followed by the values that do not fit
+    /// in the entries. Offsets to targets are left as 0, and added to `fixups`.
+    fn write_ifd(&self,
+                 out: &mut Vec<u8>,
+                 fields: &[Field],
+                 next: Option<Target>,
+                 fixups: &mut Vec<(usize, Target)>) {
+        let byte_order = self.byte_order;
+        let values_start = out.len() + 2 + fields.len() * 12 + 4;
+        let mut values = Vec::new();
+
+        out.extend_from_slice(&byte_order.u16_bytes(fields.len() as u16));
+        for field in fields {
+            out.extend_from_slice(&byte_order.u16_bytes(field.tag));
+            out.extend_from_slice(&byte_order.u16_bytes(field.field_type));
+            out.extend_from_slice(&byte_order.u32_bytes(field.count));
+            if let Some(target) = field.target {
+                fixups.push((out.len(), target));
+                out.extend_from_slice(&[0; 4]);
+            } else if field.bytes.len() <= 4 {
+                out.extend_from_slice(&field.bytes);
+                out.resize(out.len() + 4 - field.bytes.len(), 0);
+            } else {
+                let offset = values_start + values.len();
+                out.extend_from_slice(&byte_order.u32_bytes(offset as u32));
+                values.extend_from_slice(&field.bytes);
+                // Values start at word boundaries.
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+            }
+        }
+        if let Some(next) = next {
+            fixups.push((out.len(), next));
+        }
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&values);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TIFF structure with Make and Orientation in IFD0, DateTimeOriginal
+    /// in the Exif IFD, and GPSLatitudeRef in the GPS IFD at `gps_offset`.
+    fn tiff(byte_order: ByteOrder, gps_offset: u32) -> Vec<u8> {
+        let mut data = header(byte_order);
+        let entry = |data: &mut Vec<u8>, tag: u16, field_type: u16, count: u32, value: [u8; 4]| {
+            push_entry(data, byte_order, tag, field_type, count, value);
+        };
+
+        // IFD0 at 8, with its values at 62
+        data.extend_from_slice(&byte_order.u16_bytes(4));
+        entry(&mut data, tags::MAKE, 2, 6, byte_order.u32_bytes(62));
+        let mut orientation = [0; 4];
+        orientation[..2].copy_from_slice(&byte_order.u16_bytes(6));
+        entry(&mut data, tags::ORIENTATION, 3, 1, orientation);
+        entry(&mut data, tags::EXIF_IFD_POINTER, 4, 1, byte_order.u32_bytes(68));
+        entry(&mut data, tags::GPS_IFD_POINTER, 4, 1, byte_order.u32_bytes(gps_offset));
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"Canon\0");
+
+        // Exif IFD at 68, with its value at 86
+        data.extend_from_slice(&byte_order.u16_bytes(1));
+        entry(&mut data, tags::DATE_TIME_ORIGINAL, 2, 20, byte_order.u32_bytes(86));
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"2020:01:02 03:04:05\0");
+
+        // GPS IFD at 106
+        data.extend_from_slice(&byte_order.u16_bytes(1));
+        entry(&mut data, tags::GPS_LATITUDE_REF, 2, 2, *b"N\0\0\0");
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    /// A TIFF header, with IFD0 at 8.
+    fn header(byte_order: ByteOrder) -> Vec<u8> {
+        let mut data = match byte_order {
+            ByteOrder::LittleEndian => b"II".to_vec(),
+            ByteOrder::BigEndian => b"MM".to_vec(),
+        };
+        data.extend_from_slice(&byte_order.u16_bytes(42));
+        data.extend_from_slice(&byte_order.u32_bytes(8));
+        data
+    }
+
+    fn push_entry(data: &mut Vec<u8>,
+                  byte_order: ByteOrder,
+                  tag: u16,
+                  field_type: u16,
+                  count: u32,
+                  value: [u8; 4]) {
+        data.extend_from_slice(&byte_order.u16_bytes(tag));
+        data.extend_from_slice(&byte_order.u16_bytes(field_type));
+        data.extend_from_slice(&byte_order.u32_bytes(count));
+        data.extend_from_slice(&value);
+    }
+
+    /// IFD0 at 8, the Exif IFD at 38, the maker note at 56, and the GPS IFD at 64.
+    fn tiff_with_maker_note(byte_order: ByteOrder) -> Vec<u8> {
+        let mut data = header(byte_order);
+        let entry = |data: &mut Vec<u8>, tag: u16, field_type: u16, count: u32, value: [u8; 4]| {
+            push_entry(data, byte_order, tag, field_type, count, value);
+        };
+        data.extend_from_slice(&byte_order.u16_bytes(2));
+        entry(&mut data, tags::EXIF_IFD_POINTER, 4, 1, byte_order.u32_bytes(38));
+        entry(&mut data, tags::GPS_IFD_POINTER, 4, 1, byte_order.u32_bytes(64));
+        data.extend_from_slice(&[0; 4]);
+
+        data.extend_from_slice(&byte_order.u16_bytes(1));
+        entry(&mut data, tags::MAKER_NOTE, 7, 8, byte_order.u32_bytes(56));
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"Nikon\0\x02\x10");
+
+        data.extend_from_slice(&byte_order.u16_bytes(1));
+        entry(&mut data, tags::GPS_LATITUDE_REF, 2, 2, *b"N\0\0\0");
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn maker_note_stays_put_when_ifds_grow() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let data = tiff_with_maker_note(byte_order);
+            let exif = Exif::parse(&data).unwrap();
+            assert_eq!(exif.to_bytes().unwrap(), data);
+
+            // The Exif IFD no longer fits before the maker note once IFD0
+            // grows, so it goes after it, like the GPS IFD.
+            let mut exif = exif;
+            exif.set_orientation(3);
+            let written = exif.to_bytes().unwrap();
+            assert_eq!(&written[56..64], b"Nikon\0\x02\x10");
+            let written = Exif::parse(&written).unwrap();
+            assert_eq!(written.maker_note_offset, Some(56));
+            assert_eq!(written.ifd_offsets,
+                       vec![(Ifd::Primary, 8), (Ifd::Exif, 64), (Ifd::Gps, 82)]);
+            assert_eq!(written.orientation(), Some(3));
+            assert_eq!(written.get_str(Ifd::Gps, tags::GPS_LATITUDE_REF), Some("N"));
+
+            // Even IFD0 goes after it when it no longer fits.
+            exif.set(Ifd::Primary, tags::MAKE, Value::ascii(&"Nikon".repeat(10)));
+            let written = exif.to_bytes().unwrap();
+            assert_eq!(&written[56..64], b"Nikon\0\x02\x10");
+            let written = Exif::parse(&written).unwrap();
+            assert_eq!(written.maker_note_offset, Some(56));
+            assert_eq!(written.ifd_offsets[0], (Ifd::Primary, 64));
+            assert_eq!(written.make(), Some(&*"Nikon".repeat(10)));
+            assert_eq!(written.orientation(), Some(3));
+            assert!(written.errors().is_empty());
+        }
+    }
+
+    #[test]
+    fn maker_note_in_header_is_an_error() {
+        let byte_order = ByteOrder::BigEndian;
+        let mut data = header(byte_order);
+        data.extend_from_slice(&byte_order.u16_bytes(1));
+        push_entry(&mut data, byte_order, tags::EXIF_IFD_POINTER, 4, 1, byte_order.u32_bytes(26));
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&byte_order.u16_bytes(1));
+        push_entry(&mut data, byte_order, tags::MAKER_NOTE, 7, 6, byte_order.u32_bytes(2));
+        data.extend_from_slice(&[0; 4]);
+
+        let exif = Exif::parse(&data).unwrap();
+        assert_eq!(exif.maker_note_offset, Some(2));
+        assert!(exif.to_bytes().is_err());
+    }
+
+    #[test]
+    fn offsets_that_are_not_rewritten_are_left_out() {
+        let byte_order = ByteOrder::LittleEndian;
+        let mut data = header(byte_order);
+        data.extend_from_slice(&byte_order.u16_bytes(4));
+        push_entry(&mut data, byte_order, tags::MODEL, 2, 4, *b"EOS\0");
+        // StripOffsets, SubIFDs, and a value of the IFD type
+        push_entry(&mut data, byte_order, 0x0111, 4, 1, byte_order.u32_bytes(100));
+        push_entry(&mut data, byte_order, 0x014a, 4, 1, byte_order.u32_bytes(100));
+        push_entry(&mut data, byte_order, 0xc000, 13, 1, byte_order.u32_bytes(100));
+        data.extend_from_slice(&[0; 4]);
+
+        let exif = Exif::parse(&data).unwrap();
+        assert_eq!(exif.entries().len(), 4);
+        let written = Exif::parse(&exif.to_bytes().unwrap()).unwrap();
+        assert_eq!(written.entries().len(), 1);
+        assert_eq!(written.model(), Some("EOS"));
+    }
+
+    const THUMBNAIL: &[u8] = b"\xff\xd8\xff\xd9";
+
+    /// IFD0 at 8 with Make, the Exif IFD at 56 with DateTimeOriginal and the
+    /// serial numbers, the GPS IFD at 124 with a position, and IFD1 at 226
+    /// with a thumbnail at 256.
+    fn camera_tiff(byte_order: ByteOrder) -> Vec<u8> {
+        let mut data = header(byte_order);
+        let entry = |data: &mut Vec<u8>, tag: u16, field_type: u16, count: u32, value: [u8; 4]| {
+            push_entry(data, byte_order, tag, field_type, count, value);
+        };
+        let u32_bytes = |n: u32| byte_order.u32_bytes(n);
+
+        data.extend_from_slice(&byte_order.u16_bytes(3));
+        entry(&mut data, tags::MAKE, 2, 6, u32_bytes(50));
+        entry(&mut data, tags::EXIF_IFD_POINTER, 4, 1, u32_bytes(56));
+        entry(&mut data, tags::GPS_IFD_POINTER, 4, 1, u32_bytes(124));
+        data.extend_from_slice(&u32_bytes(226));
+        data.extend_from_slice(b"Canon\0");
+
+        data.extend_from_slice(&byte_order.u16_bytes(3));
+        entry(&mut data, tags::DATE_TIME_ORIGINAL, 2, 20, u32_bytes(98));
+        entry(&mut data, tags::BODY_SERIAL_NUMBER, 2, 6, u32_bytes(118));
+        entry(&mut data, tags::LENS_SERIAL_NUMBER, 2, 4, *b"678\0");
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"2020:01:02 03:04:05\0");
+        data.extend_from_slice(b"12345\0");
+
+        data.extend_from_slice(&byte_order.u16_bytes(4));
+        entry(&mut data, tags::GPS_LATITUDE_REF, 2, 2, *b"S\0\0\0");
+        entry(&mut data, tags::GPS_LATITUDE, 5, 3, u32_bytes(178));
+        entry(&mut data, tags::GPS_LONGITUDE_REF, 2, 2, *b"E\0\0\0");
+        entry(&mut data, tags::GPS_LONGITUDE, 5, 3, u32_bytes(202));
+        data.extend_from_slice(&[0; 4]);
+        for &(n, d) in &[(48, 1), (30, 1), (0, 1), (2, 1), (15, 1), (36, 1)] {
+            data.extend_from_slice(&u32_bytes(n));
+            data.extend_from_slice(&u32_bytes(d));
+        }
+
+        data.extend_from_slice(&byte_order.u16_bytes(2));
+        entry(&mut data, tags::JPEG_INTERCHANGE_FORMAT, 4, 1, u32_bytes(256));
+        entry(&mut data,
+              tags::JPEG_INTERCHANGE_FORMAT_LENGTH,
+              4,
+              1,
+              u32_bytes(THUMBNAIL.len() as u32));
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(THUMBNAIL);
+        data
+    }
+
+    /// The position in `camera_tiff`, 48°30'S 2°15'36"E
+    const POSITION: Option<(f64, f64)> = Some((-48.5, 2.0 + 15.0 / 60.0 + 36.0 / 3600.0));
+
+    fn position(exif: &Exif) -> Option<(f64, f64)> {
+        exif.gps_position().map(|gps| (gps.latitude, gps.longitude))
+    }
+
+    /// Write `exif` and parse it again, checking that every IFD and the
+    /// thumbnail can still be found.
+    fn rewrite(exif: &Exif) -> Exif {
+        let written = Exif::parse(&exif.to_bytes().unwrap()).unwrap();
+        assert!(written.errors().is_empty(), "{:?}", written.errors());
+        assert_eq!(written.make(), Some("Canon"));
+        assert_eq!(written.thumbnail(), Some(THUMBNAIL));
+        let ifds: HashSet<Ifd> = exif.entries().iter().map(|entry| entry.ifd).collect();
+        let written_ifds: HashSet<Ifd> = written.ifd_offsets.iter().map(|&(ifd, _)| ifd).collect();
+        assert_eq!(written_ifds, ifds);
+        written
+    }
+
+    #[test]
+    fn camera_round_trip() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let data = camera_tiff(byte_order);
+            let exif = Exif::parse(&data).unwrap();
+            assert_eq!(position(&exif), POSITION);
+            assert_eq!(exif.thumbnail(), Some(THUMBNAIL));
+            assert_eq!(exif.to_bytes().unwrap(), data);
+            rewrite(&exif);
+        }
+    }
+
+    #[test]
+    fn remove_gps() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut exif = Exif::parse(&camera_tiff(byte_order)).unwrap();
+            exif.remove_gps();
+            let written = rewrite(&exif);
+            assert_eq!(written.gps_position(), None);
+            assert!(written.get(Ifd::Primary, tags::GPS_IFD_POINTER).is_none());
+            assert_eq!(written.date_time_original(), Some("2020:01:02 03:04:05"));
+            assert_eq!(written.get_str(Ifd::Exif, tags::BODY_SERIAL_NUMBER), Some("12345"));
+        }
+    }
+
+    #[test]
+    fn remove_serial_numbers() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut exif = Exif::parse(&camera_tiff(byte_order)).unwrap();
+            exif.remove_serial_numbers();
+            let written = rewrite(&exif);
+            assert!(written.get(Ifd::Exif, tags::BODY_SERIAL_NUMBER).is_none());
+            assert!(written.get(Ifd::Exif, tags::LENS_SERIAL_NUMBER).is_none());
+            assert_eq!(written.date_time_original(), Some("2020:01:02 03:04:05"));
+            assert_eq!(position(&written), POSITION);
+
+            // The Exif IFD is left out once it is empty.
+            exif.remove(Ifd::Exif, tags::DATE_TIME_ORIGINAL);
+            let written = rewrite(&exif);
+            assert!(written.get(Ifd::Primary, tags::EXIF_IFD_POINTER).is_none());
+            assert_eq!(position(&written), POSITION);
+        }
+    }
+
+    #[test]
+    fn set_date_time_original() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut exif = Exif::parse(&camera_tiff(byte_order)).unwrap();
+            exif.set_date_time_original("2021:12:31 23:59:58");
+            let written = rewrite(&exif);
+            assert_eq!(written.date_time_original(), Some("2021:12:31 23:59:58"));
+            assert_eq!(written.get_str(Ifd::Exif, tags::LENS_SERIAL_NUMBER), Some("678"));
+            assert_eq!(position(&written), POSITION);
+
+            // An Exif IFD is added if there is none.
+            exif.remove_ifd(Ifd::Exif);
+            exif.set_date_time_original("2022:01:01 00:00:00");
+            let written = rewrite(&exif);
+            assert_eq!(written.date_time_original(), Some("2022:01:01 00:00:00"));
+            assert_eq!(written.get(Ifd::Exif, tags::DATE_TIME_ORIGINAL).unwrap().count, 20);
+        }
+    }
+
+    #[test]
+    fn values_are_kept_byte_exact() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut data = header(byte_order);
+            data.extend_from_slice(&byte_order.u16_bytes(3));
+            // Padded after the terminator, and not UTF-8
+            push_entry(&mut data, byte_order, tags::MAKE, 2, 8, byte_order.u32_bytes(50));
+            push_entry(&mut data, byte_order, tags::MODEL, 2, 4, *b"Ca\xe9\0");
+            // A BigTIFF LONG8, out of the entry
+            push_entry(&mut data, byte_order, 0x8000, 16, 1, byte_order.u32_bytes(58));
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(b"Canon\0\0\0");
+            data.extend_from_slice(&byte_order.u64_bytes(1 << 40));
+
+            let exif = Exif::parse(&data).unwrap();
+            assert_eq!(exif.make(), Some("Canon"));
+            assert_eq!(exif.model(), None);
+            assert_eq!(exif.get(Ifd::Primary, 0x8000).unwrap().value,
+                       Value::Unknown(16, byte_order.u64_bytes(1 << 40).to_vec()));
+            assert_eq!(exif.to_bytes().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn unknown_types_are_left_out() {
+        let byte_order = ByteOrder::BigEndian;
+        let mut data = header(byte_order);
+        data.extend_from_slice(&byte_order.u16_bytes(2));
+        push_entry(&mut data, byte_order, tags::MODEL, 2, 4, *b"EOS\0");
+        push_entry(&mut data, byte_order, 0x8000, 99, 100, byte_order.u32_bytes(1000));
+        data.extend_from_slice(&[0; 4]);
+
+        let exif = Exif::parse(&data).unwrap();
+        assert_eq!(exif.get(Ifd::Primary, 0x8000).unwrap().count, 100);
+        let written = Exif::parse(&exif.to_bytes().unwrap()).unwrap();
+        assert_eq!(written.model(), Some("EOS"));
+        assert!(written.get(Ifd::Primary, 0x8000).is_none());
+    }
+
+    #[test]
+    fn parse_both_byte_orders() {
+        for &byte_order in &[ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let exif = Exif::parse(&tiff(byte_order, 106)).unwrap();
+            assert_eq!(exif.byte_order(), byte_order);
+            assert_eq!(exif.make(), Some("Canon"));
+            assert_eq!(exif.orientation(), Some(6));
+            assert_eq!(exif.date_time_original(), Some("2020:01:02 03:04:05"));
+            assert_eq!(exif.get_str(Ifd::Gps, tags::GPS_LATITUDE_REF), Some("N"));
+            assert!(exif.errors().is_empty());
+        }
+    }
+
+    #[test]
+    fn broken_sub_ifd_is_skipped() {
+        let exif = Exif::parse(&tiff(ByteOrder::BigEndian, 5000)).unwrap();
+        assert_eq!(exif.make(), Some("Canon"));
+        assert_eq!(exif.orientation(), Some(6));
+        assert_eq!(exif.date_time_original(), Some("2020:01:02 03:04:05"));
+        assert!(exif.entries().iter().all(|entry| entry.ifd != Ifd::Gps));
+        assert_eq!(exif.errors().len(), 1);
+    }
+
+    #[test]
+    fn broken_ifd0_fails() {
+        let mut data = tiff(ByteOrder::LittleEndian, 106);
+        data[4..8].copy_from_slice(&ByteOrder::LittleEndian.u32_bytes(5000));
+        assert!(Exif::parse(&data).is_err());
+    }
+}
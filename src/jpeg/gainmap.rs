@@ -0,0 +1,339 @@
+use jpeg::icc;
+
+/// Parameters of a gain map, from the `hdrgm` namespace of its XMP.
+/// See the Ultra HDR Image Format, version 1.0, section "Gain map metadata".
+///
+/// Per channel values are given for red, green and blue. Values that are
+/// given only once apply to all three. Gains and capacities are in log2.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GainMapParams {
+    pub gain_map_min: [f32; 3],
+    pub gain_map_max: [f32; 3],
+    pub gamma: [f32; 3],
+    pub offset_sdr: [f32; 3],
+    pub offset_hdr: [f32; 3],
+    pub hdr_capacity_min: f32,
+    pub hdr_capacity_max: f32,
+    /// Whether the gain map maps the HDR base image to SDR, instead of the opposite
+    pub base_rendition_is_hdr: bool,
+}
+
+/// Find the value of the `hdrgm` property `name`, either as an attribute,
+/// or as an element with a single value or an `rdf:Seq` of them.
+fn property<'a>(xmp: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    let qualified = format!("hdrgm:{}", name);
+    let mut search = 0;
+    while let Some(found) = xmp[search..].find(&qualified) {
+        let rest = &xmp[search + found + qualified.len()..];
+        search += found + qualified.len();
+
+        let after_name = rest.trim_start();
+        if let Some(value) = after_name.strip_prefix('=') {
+            let value = value.trim_start();
+            // Attribute values are quoted with ASCII quotes only.
+            let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+            let value = &value[1..];
+            return value.find(quote).map(|end| vec![value[..end].trim()]);
+        }
+        if let Some(content) = rest.strip_prefix('>') {
+            let content = &content[..content.find(&format!("</{}", qualified))?];
+            if !content.contains("<rdf:li") {
+                return Some(vec![content.trim()]);
+            }
+            return Some(content.split("<rdf:li")
+                .skip(1)
+                .filter_map(|item| {
+                    let item = &item[item.find('>')? + 1..];
+                    Some(item[..item.find('<')?].trim())
+                })
+                .collect());
+        }
+    }
+    None
+}
+
+/// A per channel value, or `default` if it is missing.
+fn channels(xmp: &str, name: &str, default: f32) -> Option<[f32; 3]> {
+    let values = match property(xmp, name) {
+        Some(values) => values,
+        None => return Some([default; 3]),
+    };
+    let values: Vec<f32> = values.iter().map(|v| v.parse().ok()).collect::<Option<_>>()?;
+    match values.len() {
+        1 => Some([values[0]; 3]),
+        3 => Some([values[0], values[1], values[2]]),
+        _ => None,
+    }
+}
+
+impl GainMapParams {
+    /// Parse the parameters from the XMP of the gain map image.
+    /// Returns `None` if it has no `hdrgm` metadata, or if it is invalid.
+    pub fn parse(xmp: &str) -> Option<GainMapParams> {
+        property(xmp, "Version")?;
+        let single = |name: &str, default: Option<f32>| match property(xmp, name) {
+            Some(values) => values.first()?.parse().ok(),
+            None => default,
+        };
+        let params = GainMapParams {
+            gain_map_min: channels(xmp, "GainMapMin", 0.0)?,
+            gain_map_max: match property(xmp, "GainMapMax") {
+                Some(_) => channels(xmp, "GainMapMax", 0.0)?,
+                None => return None,
+            },
+            gamma: channels(xmp, "Gamma", 1.0)?,
+            offset_sdr: channels(xmp, "OffsetSDR", 1.0 / 64.0)?,
+            offset_hdr: channels(xmp, "OffsetHDR", 1.0 / 64.0)?,
+            hdr_capacity_min: single("HDRCapacityMin", Some(0.0))?,
+            hdr_capacity_max: single("HDRCapacityMax", None)?,
+            base_rendition_is_hdr: property(xmp, "BaseRenditionIsHDR")
+                .and_then(|values| values.first().map(|v| v.eq_ignore_ascii_case("true")))
+                .unwrap_or(false),
+        };
+        let valid = params.hdr_capacity_max > params.hdr_capacity_min &&
+                    params.gamma.iter().all(|&gamma| gamma > 0.0);
+        if valid { Some(params) } else { None }
+    }
+
+    /// How much of the gain map to apply on a display that can show
+    /// `display_boost` times the SDR white, from 0 to 1.
+    fn weight(&self, display_boost: f32) -> f32 {
+        let weight = (display_boost.max(1.0).log2() - self.hdr_capacity_min) /
+                     (self.hdr_capacity_max - self.hdr_capacity_min);
+        let weight = weight.clamp(0.0, 1.0);
+        if self.base_rendition_is_hdr {
+            1.0 - weight
+        } else {
+            weight
+        }
+    }
+}
+
+/// A decoded gain map with its parameters.
+#[derive(Debug, Clone)]
+pub struct GainMap {
+    pub params: GainMapParams,
+    pub width: usize,
+    pub height: usize,
+    /// RGB samples, 3 bytes per pixel. Single channel gain maps are
+    /// the same in all three.
+    pub data: Vec<u8>,
+}
+
+impl GainMap {
+    /// The gain map sample of `channel` at the base image position `(x, y)`,
+    /// from 0 to 1, interpolated bilinearly, as the gain map is usually smaller.
+    fn sample(&self,
+              x: usize,
+              y: usize,
+              base_width: usize,
+              base_height: usize,
+              channel: usize)
+              -> f32 {
+        let position = |i: usize, base: usize, size: usize| {
+            let position = ((i as f32 + 0.5) * size as f32 / base as f32 - 0.5).max(0.0);
+            let index = (position as usize).min(size - 1);
+            (index, (index + 1).min(size - 1), position - index as f32)
+        };
+        let (x0, x1, fx) = position(x, base_width, self.width);
+        let (y0, y1, fy) = position(y, base_height, self.height);
+        let at = |x: usize, y: usize| self.data[(y * self.width + x) * 3 + channel] as f32;
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        (top * (1.0 - fy) + bottom * fy) / 255.0
+    }
+
+    /// Apply the gain map to the RGB pixels of the base image, for a display
+    /// that can show `display_boost` times the SDR white. The base image is
+    /// assumed to be sRGB encoded.
+    ///
+    /// Returns linear light RGB, 3 floats per pixel, where 1 is the SDR white.
+    pub fn apply(&self, base: &[u8], width: usize, height: usize, display_boost: f32) -> Vec<f32> {
+        let params = &self.params;
+        let weight = params.weight(display_boost);
+        let decode: Vec<f32> = (0..256).map(|v| icc::srgb_decode(v as f32 / 255.0)).collect();
+
+        let mut hdr = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                for channel in 0..3 {
+                    let sdr = decode[base[(y * width + x) * 3 + channel] as usize];
+                    let recovery = self.sample(x, y, width, height, channel)
+                        .powf(1.0 / params.gamma[channel]);
+                    let log_boost = params.gain_map_min[channel] * (1.0 - recovery) +
+                                    params.gain_map_max[channel] * recovery;
+                    hdr.push((sdr + params.offset_sdr[channel]) * (log_boost * weight).exp2() -
+                             params.offset_hdr[channel]);
+                }
+            }
+        }
+        hdr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(gain_map_max: f32, gamma: f32, offset: f32) -> GainMapParams {
+        GainMapParams {
+            gain_map_min: [0.0; 3],
+            gain_map_max: [gain_map_max; 3],
+            gamma: [gamma; 3],
+            offset_sdr: [offset; 3],
+            offset_hdr: [offset; 3],
+            hdr_capacity_min: 1.0,
+            hdr_capacity_max: 2.0,
+            base_rendition_is_hdr: false,
+        }
+    }
+
+    /// A gain map of one pixel, with the same `gain` in all channels
+    fn gain_map(params: GainMapParams, gain: u8) -> GainMap {
+        GainMap {
+            params,
+            width: 1,
+            height: 1,
+            data: vec![gain; 3],
+        }
+    }
+
+    fn linear(base: &[u8]) -> Vec<f32> {
+        base.iter().map(|&v| icc::srgb_decode(v as f32 / 255.0)).collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (&a, &e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() <= 1e-5 * e.abs().max(1.0), "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn parse_attributes() {
+        let xmp = "<rdf:Description hdrgm:Version=\"1.0\" hdrgm:GainMapMin='-0.5' \
+                   hdrgm:GainMapMax = \"2.5\" hdrgm:Gamma=\"2\" hdrgm:OffsetSDR=\"0\" \
+                   hdrgm:OffsetHDR=\"0.25\" hdrgm:HDRCapacityMin=\"0.5\" \
+                   hdrgm:HDRCapacityMax=\"3\" hdrgm:BaseRenditionIsHDR=\"True\"/>";
+        assert_eq!(GainMapParams::parse(xmp).unwrap(),
+                   GainMapParams {
+                       gain_map_min: [-0.5; 3],
+                       gain_map_max: [2.5; 3],
+                       gamma: [2.0; 3],
+                       offset_sdr: [0.0; 3],
+                       offset_hdr: [0.25; 3],
+                       hdr_capacity_min: 0.5,
+                       hdr_capacity_max: 3.0,
+                       base_rendition_is_hdr: true,
+                   });
+    }
+
+    #[test]
+    fn parse_elements() {
+        let xmp = "<rdf:Description><hdrgm:Version>1.0</hdrgm:Version>\
+                   <hdrgm:GainMapMax><rdf:Seq><rdf:li>1</rdf:li><rdf:li>2.5</rdf:li>\
+                   <rdf:li>3</rdf:li></rdf:Seq></hdrgm:GainMapMax>\
+                   <hdrgm:Gamma> 1.5 </hdrgm:Gamma>\
+                   <hdrgm:HDRCapacityMax>2</hdrgm:HDRCapacityMax></rdf:Description>";
+        // Everything that is missing has its default value.
+        assert_eq!(GainMapParams::parse(xmp).unwrap(),
+                   GainMapParams {
+                       gain_map_min: [0.0; 3],
+                       gain_map_max: [1.0, 2.5, 3.0],
+                       gamma: [1.5; 3],
+                       offset_sdr: [1.0 / 64.0; 3],
+                       offset_hdr: [1.0 / 64.0; 3],
+                       hdr_capacity_min: 0.0,
+                       hdr_capacity_max: 2.0,
+                       base_rendition_is_hdr: false,
+                   });
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let valid = "hdrgm:Version=\"1.0\" hdrgm:GainMapMax=\"2\" hdrgm:HDRCapacityMax=\"2\"";
+        assert!(GainMapParams::parse(valid).is_some());
+        // Missing required values
+        assert!(GainMapParams::parse("hdrgm:GainMapMax=\"2\" hdrgm:HDRCapacityMax=\"2\"")
+            .is_none());
+        assert!(GainMapParams::parse("hdrgm:Version=\"1.0\" hdrgm:HDRCapacityMax=\"2\"")
+            .is_none());
+        assert!(GainMapParams::parse("hdrgm:Version=\"1.0\" hdrgm:GainMapMax=\"2\"").is_none());
+        // Two values per channel, a capacity range that is empty, and a gamma of 0
+        let two = format!("{} <hdrgm:Gamma><rdf:Seq><rdf:li>1</rdf:li><rdf:li>2</rdf:li>\
+                           </rdf:Seq></hdrgm:Gamma>",
+                          valid);
+        assert!(GainMapParams::parse(&two).is_none());
+        assert!(GainMapParams::parse(&format!("{} hdrgm:HDRCapacityMin=\"2\"", valid))
+            .is_none());
+        assert!(GainMapParams::parse(&format!("{} hdrgm:Gamma=\"0\"", valid)).is_none());
+        assert!(GainMapParams::parse(&format!("{} hdrgm:Gamma=\"x\"", valid)).is_none());
+    }
+
+    #[test]
+    fn parse_unquoted_multibyte_value() {
+        assert!(GainMapParams::parse("hdrgm:Version=\u{e9}1.0\u{e9} hdrgm:GainMapMax=\"2\"")
+            .is_none());
+        assert!(property("hdrgm:Version=\u{1f600}", "Version").is_none());
+        assert!(property("hdrgm:Version=", "Version").is_none());
+    }
+
+    #[test]
+    fn zero_gain_keeps_base() {
+        let base = [0, 64, 255];
+        let map = gain_map(params(3.0, 1.0, 1.0 / 64.0), 0);
+        assert_close(&map.apply(&base, 1, 1, 4.0), &linear(&base));
+    }
+
+    #[test]
+    fn full_gain_at_full_boost() {
+        let base = [0, 64, 255];
+        let boosted: Vec<f32> = linear(&base).iter().map(|&v| v * 8.0).collect();
+        // Any display boost over 2^HDRCapacityMax is the full boost.
+        for &display_boost in [4.0, 100.0].iter() {
+            let map = gain_map(params(3.0, 2.0, 0.0), 255);
+            assert_close(&map.apply(&base, 1, 1, display_boost), &boosted);
+        }
+
+        // Half the gain map in log2, with a gamma of 2
+        let map = gain_map(params(4.0, 2.0, 0.0), 64);
+        let log_boost = 4.0 * (64.0f32 / 255.0).sqrt();
+        let expected: Vec<f32> = linear(&base).iter().map(|&v| v * log_boost.exp2()).collect();
+        assert_close(&map.apply(&base, 1, 1, 4.0), &expected);
+    }
+
+    #[test]
+    fn weight_is_clamped() {
+        let base = [10, 128, 250];
+        let map = gain_map(params(3.0, 1.0, 0.0), 255);
+        // Display boosts below 2^HDRCapacityMin apply none of the gain map.
+        for &display_boost in [0.5, 1.0, 1.5, 2.0].iter() {
+            assert_close(&map.apply(&base, 1, 1, display_boost), &linear(&base));
+        }
+        // Halfway between the capacities in log2
+        let expected: Vec<f32> = linear(&base).iter().map(|&v| v * 1.5f32.exp2()).collect();
+        assert_close(&map.apply(&base, 1, 1, 8.0f32.sqrt()), &expected);
+
+        // An HDR base rendition is shown as it is at full boost.
+        let mut hdr_base = params(3.0, 1.0, 0.0);
+        hdr_base.base_rendition_is_hdr = true;
+        let map = gain_map(hdr_base, 255);
+        assert_close(&map.apply(&base, 1, 1, 4.0), &linear(&base));
+        let expected: Vec<f32> = linear(&base).iter().map(|&v| v * 8.0).collect();
+        assert_close(&map.apply(&base, 1, 1, 1.0), &expected);
+    }
+
+    #[test]
+    fn gain_map_is_interpolated() {
+        let map = GainMap {
+            params: params(1.0, 1.0, 0.0),
+            width: 2,
+            height: 1,
+            data: vec![0, 0, 0, 255, 255, 255],
+        };
+        assert_eq!(map.sample(0, 0, 4, 1, 0), 0.0);
+        assert_eq!(map.sample(1, 0, 4, 1, 1), 0.25);
+        assert_eq!(map.sample(2, 0, 4, 1, 2), 0.75);
+        assert_eq!(map.sample(3, 0, 4, 1, 0), 1.0);
+    }
+}
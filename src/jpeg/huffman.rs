@@ -47,14 +47,30 @@ impl HuffmanTable {
             .zip(code_table.iter())
             .map(|((&value, &length), &code)| {
                 HuffmanCode {
-                    length: length,
-                    code: code,
-                    value: value,
+                    length,
+                    code,
+                    value,
                 }
             })
             .collect();
 
-        HuffmanTable { codes: codes }
+        HuffmanTable { codes }
+    }
+
+    /// The number of codes of each length, from 1 to 16 bits.
+    /// This is BITS in JPEG C, or `size_data` of `from_size_data_tables`.
+    pub fn bits(&self) -> [u8; 16] {
+        let mut bits = [0; 16];
+        for code in &self.codes {
+            bits[code.length as usize - 1] += 1;
+        }
+        bits
+    }
+
+    /// The values of the codes, in order of increasing code length.
+    /// This is HUFFVAL in JPEG C, or `data_table` of `from_size_data_tables`.
+    pub fn values(&self) -> Vec<u8> {
+        self.codes.iter().map(|code| code.value).collect()
     }
 
     pub fn codes_of_length(&self, len: usize) -> &[HuffmanCode] {
@@ -100,7 +116,7 @@ impl HuffmanTable {
 
 impl Clone for HuffmanTable {
     fn clone(&self) -> HuffmanTable {
-        HuffmanTable { codes: self.codes.iter().cloned().collect() }
+        HuffmanTable { codes: self.codes.to_vec() }
     }
 }
 
@@ -124,13 +140,14 @@ impl<'a> HuffmanDecoder<'a> {
     pub fn new(data: &'a [u8]) -> HuffmanDecoder<'a> {
         // TODO: Revisit this: is it weird to read from `data` in
         // the constructor?
-        let current = ((data[0] as u32) << 24) | ((data[1] as u32) << 16) |
-                      ((data[2] as u32) << 8) | ((data[3] as u32) << 0);
+        // Short data is extended like at the end, see `shift_and_fix_current`.
+        let byte = |i: usize| *data.get(i).unwrap_or(&0xaa) as u32;
+        let current = (byte(0) << 24) | (byte(1) << 16) | (byte(2) << 8) | byte(3);
         HuffmanDecoder {
-            data: data,
+            data,
             next_index: 4,
             bits_read: 0,
-            current: current,
+            current,
         }
     }
 
@@ -142,24 +159,53 @@ impl<'a> HuffmanDecoder<'a> {
         self.next_index
     }
 
-    /// Read the next 8x8 block
-    pub fn next_block(&mut self, ac_table: &HuffmanTable, dc_table: &HuffmanTable) -> Vec<i16> {
-        // First we read the DC coefficient, which is encoded as
-        // `(num_bits)(value)`, where `value` is _not_ huffman encoded,
-        // but `num_bits` is.
-        // TODO: remove `expect`
-        let num_bits = self.next_code(dc_table);// .expect("Could not infer next code") as usize;
-        if num_bits.is_none() {
-            println!("DC lookup fail");
-            println!("current_16 = {:016b}", (self.current >> 16) & 0xffff);
+    /// Number of bits read from the data stream.
+    pub fn position(&self) -> usize {
+        (self.next_index - 4) * 8 + self.bits_read
+    }
+
+    /// Whether more bits were read than there are. Past the end, the bits
+    /// are made up by `shift_and_fix_current`.
+    pub fn is_past_end(&self) -> bool {
+        self.position() > self.data.len() * 8
+    }
+
+    /// Whether the next code is looked up in bits past the end of the data.
+    /// Codes take up to 16 bits, so they may be made up if the data is cut off.
+    pub fn is_near_end(&self) -> bool {
+        self.position() + 16 > self.data.len() * 8
+    }
+
+    /// Whether all data is read, except for the 1-bits padding the last byte.
+    /// See JPEG F.1.2.3.
+    pub fn is_exhausted(&self) -> bool {
+        let position = self.position();
+        let end = self.data.len() * 8;
+        if position >= end {
+            return true;
         }
-        let num_bits = num_bits.unwrap() as usize;
-        let dc_coef = HuffmanDecoder::value_correction(self.read_n_bits(num_bits), num_bits);
+        let remaining = end - position;
+        if remaining >= 8 {
+            return false;
+        }
+        // Codes of only 1-bits are not used, so these bits can not be data.
+        let mask = (1u8 << remaining) - 1;
+        self.data[self.data.len() - 1] & mask == mask
+    }
+
+    /// Read the next 8x8 block. Fails on data that can not be decoded, which
+    /// includes a block that does not end before the data does.
+    pub fn next_block(&mut self,
+                      ac_table: &HuffmanTable,
+                      dc_table: &HuffmanTable)
+                      -> Result<Vec<i16>, String> {
+        let dc_coef = self.next_dc_difference(dc_table)?;
 
         let mut block: Vec<i16> = vec![dc_coef];
 
         while block.len() < 64 {
-            let next_code = self.next_code(ac_table).expect("ILLEGAL STATE!");
+            let next_code = self.next_code(ac_table)
+                .ok_or(format!("No AC code matches {:016b}", (self.current >> 16) & 0xffff))?;
             match next_code {
                 0x00 => {
                     // End. Fill rest of `block` with `0`
@@ -182,16 +228,157 @@ impl<'a> HuffmanDecoder<'a> {
             // The tuple is huffman encoded. `code` is not.
             let prepending_zeroes = ((next_code & 0xf0) >> 4) as usize;
             let num_bits = (next_code & 0xf) as usize;
+            if block.len() + prepending_zeroes >= 64 {
+                return Err(format!("AC coefficient at index {}",
+                                   block.len() + prepending_zeroes));
+            }
             let num = self.read_n_bits(num_bits);
             let number = HuffmanDecoder::value_correction(num, num_bits);
-            let zeroes_to_push = min(prepending_zeroes, 64 - block.len() - 1);
-            block.extend(repeat(0).take(zeroes_to_push));
+            block.extend(repeat(0).take(prepending_zeroes));
             block.push(number);
         }
 
         assert!(block.len() == 64);
 
-        block
+        if self.is_past_end() {
+            return Err("Entropy coded data ends within a block".to_string());
+        }
+
+        Ok(block)
+    }
+
+    /// Read the difference of the next DC coefficient to the one before it.
+    /// It is encoded as `(num_bits)(value)`, where `value` is _not_ huffman
+    /// encoded, but `num_bits` is. See JPEG F.1.2.1.
+    pub fn next_dc_difference(&mut self, dc_table: &HuffmanTable) -> Result<i16, String> {
+        let num_bits = self.next_code(dc_table)
+            .ok_or(format!("No DC code matches {:016b}", (self.current >> 16) & 0xffff))?
+            as usize;
+        if num_bits > 16 {
+            return Err(format!("DC coefficient of {} bits", num_bits));
+        }
+        Ok(HuffmanDecoder::value_correction(self.read_n_bits(num_bits), num_bits))
+    }
+
+    /// Read a single bit, as in the refinement scans of progressive frames.
+    pub fn next_bit(&mut self) -> bool {
+        self.read_n_bits(1) == 1
+    }
+
+    /// Read the coefficients `start..=end` of a block, in zigzag order, in the
+    /// first scan of these coefficients of a progressive frame. They are
+    /// scaled by `1 << low_bit`. Runs of blocks without any of these
+    /// coefficients are counted down in `eob_run`. See JPEG G.1.2.2.
+    pub fn next_ac_first(&mut self,
+                         ac_table: &HuffmanTable,
+                         block: &mut [i32],
+                         start: usize,
+                         end: usize,
+                         low_bit: u8,
+                         eob_run: &mut u32)
+                         -> Result<(), String> {
+        if *eob_run > 0 {
+            *eob_run -= 1;
+            return Ok(());
+        }
+        let mut k = start;
+        while k <= end {
+            let code = self.next_code(ac_table)
+                .ok_or(format!("No AC code matches {:016b}", (self.current >> 16) & 0xffff))?;
+            let run = (code >> 4) as usize;
+            let num_bits = (code & 0xf) as usize;
+            if num_bits == 0 {
+                if run < 15 {
+                    // EOBn: this block and `2^n - 1 + bits` more end here.
+                    *eob_run = (1 << run) + self.read_n_bits(run) as u32 - 1;
+                    break;
+                }
+                // ZRL: 16 zeros
+                k += 16;
+                continue;
+            }
+            k += run;
+            if k > end {
+                return Err(format!("AC coefficient at index {}", k));
+            }
+            let value = HuffmanDecoder::value_correction(self.read_n_bits(num_bits), num_bits);
+            block[k] = (value as i32) << low_bit;
+            k += 1;
+        }
+        Ok(())
+    }
+
+    /// Read a bit of the coefficients `start..=end` of a block, in zigzag
+    /// order, in a refinement scan of a progressive frame. Coefficients that
+    /// were 0 so far may become `1 << low_bit` or `-1 << low_bit`, and the
+    /// others get bit `low_bit` of their magnitude. See JPEG G.1.2.3.
+    pub fn next_ac_refinement(&mut self,
+                              ac_table: &HuffmanTable,
+                              block: &mut [i32],
+                              start: usize,
+                         end: usize,
+                              low_bit: u8,
+                              eob_run: &mut u32)
+                              -> Result<(), String> {
+        let bit = 1i32 << low_bit;
+        let mut k = start;
+        if *eob_run == 0 {
+            while k <= end {
+                let code = self.next_code(ac_table)
+                    .ok_or(format!("No AC code matches {:016b}",
+                                   (self.current >> 16) & 0xffff))?;
+                let mut run = code >> 4;
+                let value = match code & 0xf {
+                    0 if run < 15 => {
+                        // EOBn: the rest of this block and of `2^n - 1 + bits`
+                        // more blocks only has correction bits.
+                        *eob_run = (1 << run) + self.read_n_bits(run as usize) as u32;
+                        break;
+                    }
+                    // ZRL: skip 16 coefficients that are 0 so far
+                    0 => 0,
+                    1 if self.next_bit() => bit,
+                    1 => -bit,
+                    num_bits => return Err(format!("AC refinement of {} bits", num_bits)),
+                };
+                // Skip `run` coefficients that are 0 so far, and the next one
+                // for a new coefficient. The ones that are not 0 are corrected.
+                while k <= end {
+                    if block[k] != 0 {
+                        self.correct(&mut block[k], bit);
+                    } else if run == 0 {
+                        break;
+                    } else {
+                        run -= 1;
+                    }
+                    k += 1;
+                }
+                if value != 0 {
+                    if k > end {
+                        return Err(format!("AC coefficient at index {}", k));
+                    }
+                    block[k] = value;
+                }
+                k += 1;
+            }
+        }
+        if *eob_run > 0 {
+            for coefficient in block.iter_mut().take(end + 1).skip(k) {
+                if *coefficient != 0 {
+                    self.correct(coefficient, bit);
+                }
+            }
+            *eob_run -= 1;
+        }
+        Ok(())
+    }
+
+    /// Read the correction bit of a coefficient that is not 0, and add it
+    /// to its magnitude.
+    fn correct(&mut self, coefficient: &mut i32, bit: i32) {
+        if self.next_bit() && *coefficient & bit == 0 {
+            *coefficient += if *coefficient >= 0 { bit } else { -bit };
+        }
     }
 
     /// Read `n` bits from `current`
@@ -202,7 +389,7 @@ impl<'a> HuffmanDecoder<'a> {
         assert!(n <= 16, "Should not read more than 16 bits at a time!");
         let mask = BIT_MASKS[n];
         let current_16 = (self.current >> 16) as u16;
-        let number = ((current_16 & mask) >> (16 - n)) as u16;
+        let number = (current_16 & mask) >> (16 - n);
         self.shift_and_fix_current(n);
         number
     }
@@ -213,7 +400,7 @@ impl<'a> HuffmanDecoder<'a> {
             .flat_map(|len| {
                 let mask = BIT_MASKS[len];
                 let current_16 = (self.current >> 16) as u16;
-                let bits = ((current_16 & mask) >> (16 - len)) as u16;
+                let bits = (current_16 & mask) >> (16 - len);
 
                 table.codes_of_length(len).iter()
                     // Find the code ID of length `len`, and code bits `bits`
@@ -263,7 +450,7 @@ impl<'a> HuffmanDecoder<'a> {
         if val < base {
             -2 * base + 1 + val
         } else {
-            val as i16
+            val
         }
     }
 }
@@ -0,0 +1,669 @@
+/// Identifier of APP2 segments holding an ICC profile chunk.
+pub const ICC_IDENTIFIER: &[u8] = b"ICC_PROFILE\0";
+
+/// A part of an ICC profile, as found in an APP2 segment.
+/// See ICC.1:2010, Annex B.4.
+#[derive(Debug, Clone)]
+pub struct IccChunk {
+    /// Sequence number of the chunk, starting at 1
+    pub sequence_number: u8,
+    /// Total number of chunks in the profile
+    pub num_chunks: u8,
+    pub data: Vec<u8>,
+}
+
+impl IccChunk {
+    /// Parse the APP2 segment data following the length bytes.
+    /// Returns `None` if the segment is not an ICC profile chunk.
+    pub fn parse(segment: &[u8]) -> Option<IccChunk> {
+        let header_length = ICC_IDENTIFIER.len() + 2;
+        if segment.len() < header_length || !segment.starts_with(ICC_IDENTIFIER) {
+            return None;
+        }
+        Some(IccChunk {
+            sequence_number: segment[ICC_IDENTIFIER.len()],
+            num_chunks: segment[ICC_IDENTIFIER.len() + 1],
+            data: segment[header_length..].to_vec(),
+        })
+    }
+}
+
+/// Concatenate the chunks of a profile in order of their sequence numbers.
+///
+/// All chunks must agree on the number of chunks, and every
+/// sequence number from 1 to that number must be present exactly once.
+pub fn assemble_profile(chunks: &[IccChunk]) -> Result<Vec<u8>, String> {
+    let num_chunks = match chunks.first() {
+        Some(chunk) => chunk.num_chunks,
+        None => return Err("No ICC profile chunks".to_string()),
+    };
+    if chunks.len() != num_chunks as usize {
+        return Err(format!("Found {} ICC profile chunks, expected {}",
+                           chunks.len(),
+                           num_chunks));
+    }
+
+    let mut ordered: Vec<Option<&IccChunk>> = vec![None; num_chunks as usize];
+    for chunk in chunks {
+        if chunk.num_chunks != num_chunks {
+            return Err(format!("ICC profile chunk {} says there are {} chunks, expected {}",
+                               chunk.sequence_number,
+                               chunk.num_chunks,
+                               num_chunks));
+        }
+        if chunk.sequence_number == 0 || chunk.sequence_number > num_chunks {
+            return Err(format!("ICC profile chunk number {} is out of range",
+                               chunk.sequence_number));
+        }
+        let slot = &mut ordered[chunk.sequence_number as usize - 1];
+        if slot.is_some() {
+            return Err(format!("Duplicate ICC profile chunk {}", chunk.sequence_number));
+        }
+        *slot = Some(chunk);
+    }
+
+    Ok(ordered.iter()
+        .flat_map(|chunk| chunk.unwrap().data.iter().cloned())
+        .collect())
+}
+
+/// Target of the color management done when decoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorManagement {
+    /// Leave samples in the color space of the image
+    None,
+    /// Convert to sRGB, using the embedded ICC profile
+    Srgb,
+    /// Convert to sRGB primaries with a linear transfer function,
+    /// using the embedded ICC profile
+    LinearSrgb,
+}
+
+/// A tone reproduction curve, mapping device values to linear light.
+/// Both input and output are in `0.0..1.0`.
+#[derive(Debug, Clone)]
+pub enum ToneCurve {
+    Identity,
+    Gamma(f32),
+    /// Uniformly spaced samples, to be interpolated linearly
+    Table(Vec<u16>),
+    /// Function type and parameters of a `parametricCurveType`.
+    /// See ICC.1:2010, section 10.16.
+    Parametric(u16, Vec<f32>),
+}
+
+impl ToneCurve {
+    pub fn eval(&self, x: f32) -> f32 {
+        let y = match *self {
+            ToneCurve::Identity => x,
+            ToneCurve::Gamma(gamma) => x.powf(gamma),
+            ToneCurve::Table(ref table) => {
+                let position = x * (table.len() - 1) as f32;
+                let index = (position.floor() as usize).min(table.len() - 1);
+                let next = (index + 1).min(table.len() - 1);
+                let fraction = position - index as f32;
+                (table[index] as f32 * (1.0 - fraction) + table[next] as f32 * fraction) /
+                65535.0
+            }
+            ToneCurve::Parametric(function, ref p) => {
+                let g = p[0];
+                match function {
+                    0 => x.powf(g),
+                    1 if x >= -p[2] / p[1] => (p[1] * x + p[2]).powf(g),
+                    1 => 0.0,
+                    2 if x >= -p[2] / p[1] => (p[1] * x + p[2]).powf(g) + p[3],
+                    2 => p[3],
+                    3 if x >= p[4] => (p[1] * x + p[2]).powf(g),
+                    3 => p[3] * x,
+                    4 if x >= p[4] => (p[1] * x + p[2]).powf(g) + p[5],
+                    4 => p[3] * x + p[6],
+                    _ => x,
+                }
+            }
+        };
+        y.clamp(0.0, 1.0)
+    }
+}
+
+/// The parts of an ICC profile needed for matrix/TRC color management.
+/// See ICC.1:2010, sections 7 and 8.
+#[derive(Debug, Clone)]
+pub struct IccProfile {
+    /// Major and minor version
+    pub version: (u8, u8),
+    /// Data color space signature, like `RGB ` or `GRAY`
+    pub color_space: [u8; 4],
+    /// Profile connection space signature, `XYZ ` or `Lab `
+    pub connection_space: [u8; 4],
+    /// Columns of the matrix from linear RGB to D50 XYZ: rXYZ, gXYZ and bXYZ
+    pub colorants: Option<[[f32; 3]; 3]>,
+    /// rTRC, gTRC and bTRC
+    pub rgb_curves: Option<[ToneCurve; 3]>,
+    /// kTRC
+    pub gray_curve: Option<ToneCurve>,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| (b[0] as u16) << 8 | b[1] as u16)
+}
+
+fn s15_fixed16_at(data: &[u8], offset: usize) -> Option<f32> {
+    u32_at(data, offset).map(|n| n as i32 as f32 / 65536.0)
+}
+
+impl IccProfile {
+    pub fn parse(data: &[u8]) -> Result<IccProfile, String> {
+        if data.len() < 132 || &data[36..40] != b"acsp" {
+            return Err("Not an ICC profile".to_string());
+        }
+        let mut color_space = [0; 4];
+        color_space.copy_from_slice(&data[16..20]);
+        let mut connection_space = [0; 4];
+        connection_space.copy_from_slice(&data[20..24]);
+
+        // Tag table, see ICC.1:2010, 7.3
+        let num_tags = u32_at(data, 128).unwrap() as usize;
+        let tag = |signature: &[u8]| -> Option<&[u8]> {
+            (0..num_tags)
+                .map(|i| 132 + i * 12)
+                .take_while(|&entry| entry + 12 <= data.len())
+                .find(|&entry| &data[entry..entry + 4] == signature)
+                .and_then(|entry| {
+                    let offset = u32_at(data, entry + 4)? as usize;
+                    let size = u32_at(data, entry + 8)? as usize;
+                    data.get(offset..offset.checked_add(size)?)
+                })
+        };
+
+        let xyz = |signature: &[u8]| -> Option<[f32; 3]> {
+            let tag = tag(signature)?;
+            if !tag.starts_with(b"XYZ ") {
+                return None;
+            }
+            Some([s15_fixed16_at(tag, 8)?, s15_fixed16_at(tag, 12)?, s15_fixed16_at(tag, 16)?])
+        };
+        let curve = |signature: &[u8]| tag(signature).and_then(IccProfile::parse_curve);
+
+        let colorants = match (xyz(b"rXYZ"), xyz(b"gXYZ"), xyz(b"bXYZ")) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        };
+        let rgb_curves = match (curve(b"rTRC"), curve(b"gTRC"), curve(b"bTRC")) {
+            (Some(r), Some(g), Some(b)) => Some([r, g, b]),
+            _ => None,
+        };
+
+        Ok(IccProfile {
+            version: (data[8], data[9] >> 4),
+            color_space,
+            connection_space,
+            colorants,
+            rgb_curves,
+            gray_curve: curve(b"kTRC"),
+        })
+    }
+
+    /// Parse a `curveType` or `parametricCurveType` tag.
+    fn parse_curve(tag: &[u8]) -> Option<ToneCurve> {
+        if tag.starts_with(b"curv") {
+            let count = u32_at(tag, 8)? as usize;
+            match count {
+                0 => Some(ToneCurve::Identity),
+                1 => Some(ToneCurve::Gamma(u16_at(tag, 12)? as f32 / 256.0)),
+                _ => {
+                    let table = (0..count)
+                        .map(|i| u16_at(tag, 12 + 2 * i))
+                        .collect::<Option<Vec<u16>>>()?;
+                    Some(ToneCurve::Table(table))
+                }
+            }
+        } else if tag.starts_with(b"para") {
+            let function = u16_at(tag, 8)?;
+            let num_parameters = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return None,
+            };
+            let parameters = (0..num_parameters)
+                .map(|i| s15_fixed16_at(tag, 12 + 4 * i))
+                .collect::<Option<Vec<f32>>>()?;
+            Some(ToneCurve::Parametric(function, parameters))
+        } else {
+            None
+        }
+    }
+}
+
+/// sRGB colorants, adapted to D50, as in the ICC sRGB profile.
+const SRGB_COLORANTS: [[f32; 3]; 3] = [[0.4361, 0.2225, 0.0139],
+                                       [0.3851, 0.7169, 0.0971],
+                                       [0.1431, 0.0606, 0.7141]];
+
+/// Multiply the matrices `a` and `b`, where both are given as columns.
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut product = [[0.0; 3]; 3];
+    for (column, b_column) in product.iter_mut().zip(b.iter()) {
+        for (row, value) in column.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[k][row] * b_column[k]).sum();
+        }
+    }
+    product
+}
+
+/// Invert the matrix `m`, given as columns.
+fn invert(m: &[[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    // Element at row `r` and column `c`
+    let e = |r: usize, c: usize| m[c][r];
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        e(r0, c0) * e(r1, c1) - e(r0, c1) * e(r1, c0)
+    };
+    let determinant = (0..3).map(|c| e(0, c) * cofactor(0, c)).sum::<f32>();
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+    // The inverse is the transposed cofactor matrix over the determinant,
+    // so column `c` of the inverse is row `c` of the cofactors.
+    let mut inverse = [[0.0; 3]; 3];
+    for (c, column) in inverse.iter_mut().enumerate() {
+        for (r, value) in column.iter_mut().enumerate() {
+            *value = cofactor(c, r) / determinant;
+        }
+    }
+    Some(inverse)
+}
+
+/// Encode linear light with the sRGB transfer function.
+fn srgb_encode(linear: f32) -> f32 {
+    if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decode the sRGB transfer function to linear light.
+pub(crate) fn srgb_decode(encoded: f32) -> f32 {
+    if encoded <= 0.04045 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Number of entries in the table encoding linear light to 8 bits
+const OUTPUT_TABLE_SIZE: usize = 4096;
+
+/// A transform from samples described by an ICC profile to sRGB, or linear sRGB.
+/// Only matrix/TRC profiles are supported.
+pub struct ColorTransform {
+    /// Device value to linear light, for each channel
+    input_tables: Vec<Vec<f32>>,
+    /// Linear device RGB to linear sRGB, as columns
+    matrix: [[f32; 3]; 3],
+    /// Linear light, in steps of `1 / (OUTPUT_TABLE_SIZE - 1)`, to output values
+    output_table: Vec<u8>,
+}
+
+impl ColorTransform {
+    fn input_table(curve: &ToneCurve) -> Vec<f32> {
+        (0..256).map(|n| curve.eval(n as f32 / 255.0)).collect()
+    }
+
+    fn output_table(target: ColorManagement) -> Vec<u8> {
+        (0..OUTPUT_TABLE_SIZE)
+            .map(|n| {
+                let linear = n as f32 / (OUTPUT_TABLE_SIZE - 1) as f32;
+                let encoded = match target {
+                    ColorManagement::Srgb => srgb_encode(linear),
+                    _ => linear,
+                };
+                (encoded * 255.0).round() as u8
+            })
+            .collect()
+    }
+
+    /// A transform for RGB samples, or `None` if the profile
+    /// is not an RGB matrix/TRC profile.
+    pub fn rgb(profile: &IccProfile, target: ColorManagement) -> Option<ColorTransform> {
+        if target == ColorManagement::None || &profile.color_space != b"RGB " {
+            return None;
+        }
+        let colorants = profile.colorants.as_ref()?;
+        let curves = profile.rgb_curves.as_ref()?;
+        let matrix = multiply(&invert(&SRGB_COLORANTS)?, colorants);
+        Some(ColorTransform {
+            input_tables: curves.iter().map(ColorTransform::input_table).collect(),
+            matrix,
+            output_table: ColorTransform::output_table(target),
+        })
+    }
+
+    /// A transform for grayscale samples, or `None` if the profile
+    /// is not a gray TRC profile.
+    pub fn gray(profile: &IccProfile, target: ColorManagement) -> Option<ColorTransform> {
+        if target == ColorManagement::None || &profile.color_space != b"GRAY" {
+            return None;
+        }
+        let curve = profile.gray_curve.as_ref()?;
+        Some(ColorTransform {
+            input_tables: vec![ColorTransform::input_table(curve)],
+            matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            output_table: ColorTransform::output_table(target),
+        })
+    }
+
+    fn output(&self, linear: f32) -> u8 {
+        let index = (linear.clamp(0.0, 1.0) * (OUTPUT_TABLE_SIZE - 1) as f32).round();
+        self.output_table[index as usize]
+    }
+
+    /// Transform packed RGB, or gray, samples in place.
+    pub fn apply(&self, data: &mut [u8]) {
+        if self.input_tables.len() == 1 {
+            for sample in data.iter_mut() {
+                *sample = self.output(self.input_tables[0][*sample as usize]);
+            }
+            return;
+        }
+        let m = &self.matrix;
+        for pixel in data.chunks_mut(3) {
+            let r = self.input_tables[0][pixel[0] as usize];
+            let g = self.input_tables[1][pixel[1] as usize];
+            let b = self.input_tables[2][pixel[2] as usize];
+            for (row, sample) in pixel.iter_mut().enumerate() {
+                *sample = self.output(m[0][row] * r + m[1][row] * g + m[2][row] * b);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(sequence_number: u8, num_chunks: u8, data: &[u8]) -> IccChunk {
+        let mut segment = ICC_IDENTIFIER.to_vec();
+        segment.push(sequence_number);
+        segment.push(num_chunks);
+        segment.extend_from_slice(data);
+        IccChunk::parse(&segment).unwrap()
+    }
+
+    #[test]
+    fn parse_chunk() {
+        let chunk = chunk(2, 3, b"abc");
+        assert_eq!((chunk.sequence_number, chunk.num_chunks), (2, 3));
+        assert_eq!(chunk.data, b"abc");
+        assert!(IccChunk::parse(b"ICC_PROFILE\0").is_none());
+        assert!(IccChunk::parse(b"XMP_PROFILE\0\x01\x01").is_none());
+    }
+
+    #[test]
+    fn assemble_out_of_order() {
+        let chunks = [chunk(3, 3, b"ef"), chunk(1, 3, b"ab"), chunk(2, 3, b"cd")];
+        assert_eq!(assemble_profile(&chunks).unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn assemble_broken() {
+        assert!(assemble_profile(&[]).is_err());
+        // Missing
+        assert!(assemble_profile(&[chunk(1, 2, b"ab")]).is_err());
+        // Duplicate
+        assert!(assemble_profile(&[chunk(1, 2, b"ab"), chunk(1, 2, b"cd")]).is_err());
+        // Inconsistent number of chunks
+        assert!(assemble_profile(&[chunk(1, 2, b"ab"), chunk(2, 3, b"cd")]).is_err());
+        // Out of range
+        assert!(assemble_profile(&[chunk(0, 1, b"ab")]).is_err());
+    }
+
+    fn s15_fixed16(value: f32) -> [u8; 4] {
+        let n = (value * 65536.0).round() as i32 as u32;
+        [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+    }
+
+    fn xyz_tag(xyz: [f32; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for &value in xyz.iter() {
+            tag.extend_from_slice(&s15_fixed16(value));
+        }
+        tag
+    }
+
+    fn curv_tag(values: &[u16]) -> Vec<u8> {
+        let mut tag = b"curv\0\0\0\0".to_vec();
+        tag.extend_from_slice(&[0, 0, 0, values.len() as u8]);
+        for &value in values {
+            tag.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+        }
+        tag
+    }
+
+    fn para_tag(function: u8, parameters: &[f32]) -> Vec<u8> {
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend_from_slice(&[0, function, 0, 0]);
+        for &parameter in parameters {
+            tag.extend_from_slice(&s15_fixed16(parameter));
+        }
+        tag
+    }
+
+    /// The sRGB transfer function, as a type 3 parametric curve
+    const SRGB_PARAMETERS: [f32; 5] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+    /// A version 4.3 profile with the given color space and tags.
+    fn profile(color_space: &[u8], tags: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[8] = 4;
+        data[9] = 0x30;
+        data[16..20].copy_from_slice(color_space);
+        data[20..24].copy_from_slice(b"XYZ ");
+        data[36..40].copy_from_slice(b"acsp");
+        data.extend_from_slice(&[0, 0, 0, tags.len() as u8]);
+
+        let mut offset = 132 + 12 * tags.len();
+        for &(signature, ref tag) in tags {
+            data.extend_from_slice(signature);
+            data.extend_from_slice(&[0, 0, (offset >> 8) as u8, offset as u8]);
+            data.extend_from_slice(&[0, 0, (tag.len() >> 8) as u8, tag.len() as u8]);
+            offset += tag.len();
+        }
+        for (_, tag) in tags {
+            data.extend_from_slice(tag);
+        }
+        data
+    }
+
+    fn srgb_profile() -> Vec<u8> {
+        profile(b"RGB ",
+                &[(b"rXYZ", xyz_tag(SRGB_COLORANTS[0])),
+                  (b"gXYZ", xyz_tag(SRGB_COLORANTS[1])),
+                  (b"bXYZ", xyz_tag(SRGB_COLORANTS[2])),
+                  (b"rTRC", para_tag(3, &SRGB_PARAMETERS)),
+                  (b"gTRC", para_tag(3, &SRGB_PARAMETERS)),
+                  (b"bTRC", para_tag(3, &SRGB_PARAMETERS))])
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!((actual - expected).abs() <= tolerance,
+                "{} is not within {} of {}",
+                actual,
+                tolerance,
+                expected);
+    }
+
+    #[test]
+    fn parse_profile() {
+        let profile = IccProfile::parse(&srgb_profile()).unwrap();
+        assert_eq!(profile.version, (4, 3));
+        assert_eq!(&profile.color_space, b"RGB ");
+        assert_eq!(&profile.connection_space, b"XYZ ");
+        let colorants = profile.colorants.unwrap();
+        for (column, expected) in colorants.iter().zip(SRGB_COLORANTS.iter()) {
+            for (&value, &expected) in column.iter().zip(expected.iter()) {
+                assert_close(value, expected, 1.0 / 65536.0);
+            }
+        }
+        match profile.rgb_curves.unwrap()[1] {
+            ToneCurve::Parametric(3, ref parameters) => assert_eq!(parameters.len(), 5),
+            ref curve => panic!("{:?}", curve),
+        }
+        assert!(profile.gray_curve.is_none());
+
+        assert!(IccProfile::parse(&srgb_profile()[..131]).is_err());
+        let mut data = srgb_profile();
+        data[36] = b'b';
+        assert!(IccProfile::parse(&data).is_err());
+    }
+
+    #[test]
+    fn parse_broken_tags() {
+        // A tag past the end of the data, and a tag of the wrong type
+        let mut data = profile(b"RGB ",
+                               &[(b"rXYZ", xyz_tag(SRGB_COLORANTS[0])),
+                                 (b"gXYZ", curv_tag(&[])),
+                                 (b"bXYZ", xyz_tag(SRGB_COLORANTS[2])),
+                                 (b"kTRC", curv_tag(&[256]))]);
+        let length = data.len();
+        data.truncate(length - 1);
+        let profile = IccProfile::parse(&data).unwrap();
+        assert!(profile.colorants.is_none());
+        assert!(profile.gray_curve.is_none());
+    }
+
+    #[test]
+    fn curves() {
+        let curve = |tag: Vec<u8>| IccProfile::parse_curve(&tag).unwrap();
+
+        assert_close(curve(curv_tag(&[])).eval(0.3), 0.3, 1e-6);
+        // A single value is a pure gamma, in units of 1/256.
+        let gamma = curve(curv_tag(&[0x0233]));
+        match gamma {
+            ToneCurve::Gamma(gamma) => assert_close(gamma, 563.0 / 256.0, 1e-6),
+            ref curve => panic!("{:?}", curve),
+        }
+        assert_close(gamma.eval(0.5), 0.5f32.powf(563.0 / 256.0), 1e-6);
+        assert_eq!(gamma.eval(1.0), 1.0);
+        // A table is interpolated linearly.
+        let table = curve(curv_tag(&[0, 0x4000, 0xffff]));
+        assert_close(table.eval(0.25), 0x2000 as f32 / 65535.0, 1e-6);
+        assert_close(table.eval(0.75), (0x4000 + 0xffff) as f32 / 2.0 / 65535.0, 1e-6);
+        assert_eq!(table.eval(1.0), 1.0);
+
+        assert!(IccProfile::parse_curve(b"curv\0\0\0\0\0\0\0\x02\0\0").is_none());
+        assert!(IccProfile::parse_curve(&para_tag(5, &[1.0])).is_none());
+        assert!(IccProfile::parse_curve(&para_tag(1, &[1.0, 1.0])).is_none());
+        assert!(IccProfile::parse_curve(&xyz_tag([0.0; 3])).is_none());
+    }
+
+    #[test]
+    fn parametric_curves() {
+        let curve = |function: u8, parameters: &[f32]| {
+            IccProfile::parse_curve(&para_tag(function, parameters)).unwrap()
+        };
+
+        assert_close(curve(0, &[2.0]).eval(0.5), 0.25, 1e-4);
+        // Y = (a * X + b)^g for X >= -b / a, and 0 below
+        let type1 = curve(1, &[2.0, 2.0, -0.5]);
+        assert_eq!(type1.eval(0.2), 0.0);
+        assert_close(type1.eval(0.5), 0.25, 1e-4);
+        // Y = (a * X + b)^g + c for X >= -b / a, and c below
+        let type2 = curve(2, &[1.0, 2.0, -0.5, 0.25]);
+        assert_close(type2.eval(0.2), 0.25, 1e-4);
+        assert_close(type2.eval(0.5), 0.75, 1e-4);
+        // Y = (a * X + b)^g + e for X >= d, and c * X + f below
+        let type4 = curve(4, &[1.0, 0.5, 0.25, 2.0, 0.25, 0.125, 0.0625]);
+        assert_close(type4.eval(0.125), 0.3125, 1e-4);
+        assert_close(type4.eval(0.5), 0.625, 1e-4);
+
+        let type3 = curve(3, &SRGB_PARAMETERS);
+        for &x in [0.0, 0.02, 0.04, 0.05, 0.2, 0.5, 0.8, 1.0].iter() {
+            assert_close(type3.eval(x), srgb_decode(x), 1e-4);
+        }
+    }
+
+    #[test]
+    fn matrices() {
+        let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        let m = [[2.0, 0.0, 1.0], [1.0, 1.0, 0.0], [0.0, 3.0, 1.0]];
+        // Column 0 of the product is m times column 0 of the other matrix.
+        let product = multiply(&m, &[[1.0, 2.0, 3.0], [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]]);
+        assert_eq!(product[0], [4.0, 11.0, 4.0]);
+        assert_eq!(multiply(&m, &identity), m);
+
+        for m in [m, SRGB_COLORANTS].iter() {
+            let inverse = invert(m).unwrap();
+            for product in [multiply(m, &inverse), multiply(&inverse, m)].iter() {
+                for (column, expected) in product.iter().zip(identity.iter()) {
+                    for (&value, &expected) in column.iter().zip(expected.iter()) {
+                        assert_close(value, expected, 1e-5);
+                    }
+                }
+            }
+        }
+
+        let singular = [[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 0.0]];
+        assert!(invert(&singular).is_none());
+        assert!(invert(&[[0.0; 3]; 3]).is_none());
+    }
+
+    #[test]
+    fn srgb_profile_is_identity() {
+        let profile = IccProfile::parse(&srgb_profile()).unwrap();
+        assert!(ColorTransform::gray(&profile, ColorManagement::Srgb).is_none());
+        assert!(ColorTransform::rgb(&profile, ColorManagement::None).is_none());
+        let transform = ColorTransform::rgb(&profile, ColorManagement::Srgb).unwrap();
+
+        let mut data = Vec::new();
+        for &r in [0, 1, 64, 128, 200, 255].iter() {
+            for &g in [0, 30, 128, 255].iter() {
+                for &b in [0, 10, 99, 255].iter() {
+                    data.extend_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        let mut transformed = data.clone();
+        transform.apply(&mut transformed);
+        for (&sample, &expected) in transformed.iter().zip(data.iter()) {
+            assert!((sample as i32 - expected as i32).abs() <= 1,
+                    "{} became {}",
+                    expected,
+                    sample);
+        }
+    }
+
+    #[test]
+    fn gray_profile() {
+        // Linear gray
+        let profile = IccProfile::parse(&profile(b"GRAY", &[(b"kTRC", curv_tag(&[256]))]))
+            .unwrap();
+        assert!(ColorTransform::rgb(&profile, ColorManagement::Srgb).is_none());
+
+        let data = [0, 1, 64, 128, 200, 255];
+        let mut linear = data;
+        ColorTransform::gray(&profile, ColorManagement::LinearSrgb).unwrap().apply(&mut linear);
+        assert_eq!(linear, data);
+
+        let mut encoded = data;
+        ColorTransform::gray(&profile, ColorManagement::Srgb).unwrap().apply(&mut encoded);
+        for (&sample, &value) in encoded.iter().zip(data.iter()) {
+            let expected = srgb_encode(value as f32 / 255.0) * 255.0;
+            assert!((sample as f32 - expected).abs() <= 1.0,
+                    "{} became {}, expected {}",
+                    value,
+                    sample,
+                    expected);
+        }
+        assert_eq!(encoded[2], 137);
+    }
+}
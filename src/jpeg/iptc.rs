@@ -0,0 +1,112 @@
+/// Datasets of the application record (record 2). See IPTC-IIM 4.2, chapter 6.
+pub mod datasets {
+    pub const OBJECT_NAME: u8 = 5;
+    pub const KEYWORDS: u8 = 25;
+    pub const BY_LINE: u8 = 80;
+    pub const CITY: u8 = 90;
+    pub const COUNTRY_NAME: u8 = 101;
+    pub const HEADLINE: u8 = 105;
+    pub const CREDIT: u8 = 110;
+    pub const SOURCE: u8 = 115;
+    pub const COPYRIGHT_NOTICE: u8 = 116;
+    pub const CAPTION_ABSTRACT: u8 = 120;
+}
+
+/// Record of the application datasets, like captions and keywords.
+pub const APPLICATION_RECORD: u8 = 2;
+
+/// An IPTC-IIM dataset.
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    pub record: u8,
+    pub number: u8,
+    pub data: Vec<u8>,
+}
+
+/// IPTC-IIM datasets, as found in the IPTC-NAA Photoshop image resource.
+#[derive(Debug, Clone)]
+pub struct Iptc {
+    datasets: Vec<Dataset>,
+}
+
+impl Iptc {
+    /// Parse the datasets. See IPTC-IIM 4.2, chapter 1, section 1.5.
+    ///
+    ///  X’1C’, record number, dataset number, length, data
+    pub fn parse(data: &[u8]) -> Result<Iptc, String> {
+        let mut datasets = Vec::new();
+        let mut i = 0;
+        // Photoshop pads the resource with zeros.
+        while i < data.len() && data[i] != 0 {
+            let header = data.get(i..i + 5).ok_or(format!("Dataset at {} is truncated", i))?;
+            if header[0] != 0x1c {
+                return Err(format!("Dataset at {} has no tag marker", i));
+            }
+            let mut length = (header[3] as usize) << 8 | header[4] as usize;
+            let mut start = i + 5;
+            // Extended datasets give the size of the length instead.
+            if length & 0x8000 != 0 {
+                let length_size = length & 0x7fff;
+                let bytes = data.get(start..start + length_size)
+                    .filter(|_| length_size <= 4)
+                    .ok_or(format!("Dataset at {} has a bad extended length", i))?;
+                length = bytes.iter().fold(0, |length, &b| length << 8 | b as usize);
+                start += length_size;
+            }
+            let dataset_data = data.get(start..start + length)
+                .ok_or(format!("Dataset {}:{} is truncated", header[1], header[2]))?;
+            datasets.push(Dataset {
+                record: header[1],
+                number: header[2],
+                data: dataset_data.to_vec(),
+            });
+            i = start + length;
+        }
+        Ok(Iptc { datasets })
+    }
+
+    /// All datasets, in the order they were read.
+    pub fn datasets(&self) -> &[Dataset] {
+        &self.datasets
+    }
+
+    /// The values of a repeatable application dataset, as text.
+    /// Text is assumed to be UTF-8, which is what current software writes.
+    pub fn get_all(&self, number: u8) -> Vec<String> {
+        self.datasets
+            .iter()
+            .filter(|dataset| dataset.record == APPLICATION_RECORD && dataset.number == number)
+            .map(|dataset| String::from_utf8_lossy(&dataset.data).into_owned())
+            .collect()
+    }
+
+    /// The value of an application dataset, as text.
+    pub fn get(&self, number: u8) -> Option<String> {
+        self.get_all(number).into_iter().next()
+    }
+
+    pub fn headline(&self) -> Option<String> {
+        self.get(datasets::HEADLINE)
+    }
+
+    /// Names of the creators
+    pub fn by_line(&self) -> Vec<String> {
+        self.get_all(datasets::BY_LINE)
+    }
+
+    pub fn keywords(&self) -> Vec<String> {
+        self.get_all(datasets::KEYWORDS)
+    }
+
+    pub fn caption(&self) -> Option<String> {
+        self.get(datasets::CAPTION_ABSTRACT)
+    }
+
+    pub fn credit(&self) -> Option<String> {
+        self.get(datasets::CREDIT)
+    }
+
+    pub fn copyright_notice(&self) -> Option<String> {
+        self.get(datasets::COPYRIGHT_NOTICE)
+    }
+}
@@ -1,7 +1,22 @@
 use jpeg::huffman;
-use jpeg::decoder::JPEGDecoder;
+use jpeg::decoder::{div_ceil, Block, JPEGDecoder};
+use jpeg::color::{self, ColorSpace, PixelFormat};
+use jpeg::exif::Exif;
+use jpeg::gainmap::{GainMap, GainMapParams};
+use jpeg::iptc::Iptc;
+use jpeg::icc::{self, ColorManagement, ColorTransform, IccChunk, IccProfile};
+use jpeg::mpf::{self, MpEntry};
+use jpeg::orientation;
+use jpeg::photoshop::{self, ImageResource};
+use jpeg::segments::{self, SegmentKind};
+use jpeg::xmp::{self, ExtendedXmpChunk};
 
-use std::str;
+use std::mem;
+
+/// How deep images embedded in metadata, like JPEG thumbnails, are decoded.
+/// Embedded images of embedded images are rare, and beyond this depth
+/// they are ignored rather than risk unbounded recursion.
+const MAX_NESTING_DEPTH: usize = 2;
 
 // TODO: move this?
 fn u8s_to_u16(bytes: &[u8]) -> u16 {
@@ -10,7 +25,8 @@ fn u8s_to_u16(bytes: &[u8]) -> u16 {
     (msb << 8) + lsb
 }
 
-#[derive(Debug)]
+/// Units of the JFIF pixel density.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum JFIFUnits {
     NoUnits,
     DotsPerInch,
@@ -21,15 +37,15 @@ pub enum JFIFUnits {
 impl JFIFUnits {
     pub fn from_u8(byte: u8) -> JFIFUnits {
         match byte {
-            1 => JFIFUnits::NoUnits,
-            2 => JFIFUnits::DotsPerInch,
-            3 => JFIFUnits::DotsPerCm,
+            0 => JFIFUnits::NoUnits,
+            1 => JFIFUnits::DotsPerInch,
+            2 => JFIFUnits::DotsPerCm,
             _ => JFIFUnits::Unknown(byte),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum JFIFVersion {
     V1_01,
@@ -47,28 +63,413 @@ impl JFIFVersion {
     }
 }
 
+/// A thumbnail embedded in the image metadata, decoded to RGB.
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    /// RGB pixels, 3 bytes per pixel
+    pub data: Vec<u8>,
+}
+
+/// An individual image of a Multi-Picture Format file, like one view of a
+/// stereo image. The first one is the image itself.
+#[derive(Debug, Clone)]
+pub struct MpImage {
+    /// The entry of the MP index describing the image
+    pub entry: MpEntry,
+    data: Vec<u8>,
+}
+
+impl MpImage {
+    /// The image as stored, from SOI to EOI.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn decode(&self, options: &DecodeOptions) -> Result<JPEGImage, String> {
+        JPEGImage::parse_with_options(self.data.clone(), options)
+    }
+
+    /// The main XMP packet of the image, read from the segments before
+    /// its first scan, without decoding it.
+    pub fn xmp(&self) -> Option<String> {
+        segments::segments(&self.data)
+            .map_while(Result::ok)
+            .take_while(|segment| segment.marker() != Some(0xda))
+            .find(|segment| {
+                segment.marker() == Some(0xe1) && segment.payload.starts_with(xmp::XMP_IDENTIFIER)
+            })
+            .map(|segment| {
+                let packet = &segment.payload[xmp::XMP_IDENTIFIER.len()..];
+                String::from_utf8_lossy(packet).into_owned()
+            })
+    }
+}
+
+/// An application segment the library does not interpret, kept as is.
+#[derive(Debug, Clone)]
+pub struct ApplicationSegment {
+    /// The marker, from 0xe0 for APP0 to 0xef for APP15
+    pub marker: u8,
+    /// The data following the length bytes
+    pub data: Vec<u8>,
+}
+
+/// Color transform of the Adobe APP14 segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdobeColorTransform {
+    /// RGB or CMYK, depending on the number of components
+    RgbOrCmyk,
+    YCbCr,
+    Ycck,
+    Unknown(u8),
+}
+
+impl AdobeColorTransform {
+    pub fn from_u8(byte: u8) -> AdobeColorTransform {
+        match byte {
+            0 => AdobeColorTransform::RgbOrCmyk,
+            1 => AdobeColorTransform::YCbCr,
+            2 => AdobeColorTransform::Ycck,
+            _ => AdobeColorTransform::Unknown(byte),
+        }
+    }
+}
+
+/// Data of the Adobe APP14 segment.
+/// See Adobe Technical Note #5116, "Supporting the DCT Filters in PostScript Level 2".
+#[derive(Debug, Clone)]
+pub struct AdobeSegment {
+    /// Version of the DCT encoder
+    pub version: u16,
+    pub flags0: u16,
+    pub flags1: u16,
+    /// Color transform applied to the components
+    pub transform: AdobeColorTransform,
+}
+
+/// How a lenient decode fills the MCUs lost to corrupt entropy coded data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Concealment {
+    /// Mid-gray, or the middle value of each component
+    Gray,
+    /// The blocks of the row above, or gray in the first row
+    CopyAbove,
+}
+
+/// Corrupt entropy coded data, which a lenient decode skipped to the next
+/// restart marker, or to the end of the scan.
+#[derive(Debug, Clone)]
+pub struct Incident {
+    /// Offset in the file of the byte where the data could not be decoded
+    pub offset: usize,
+    /// Index of the first lost MCU, in raster order
+    pub mcu: usize,
+    /// Number of MCUs filled in by the concealment
+    pub lost_mcus: usize,
+    pub message: String,
+}
+
+/// Limits on the resources `JPEGImage::parse_with_options` uses, to decode
+/// untrusted files safely. Each limit is checked before the memory for it is
+/// allocated, and exceeding one fails the decode.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    max_width: usize,
+    max_height: usize,
+    /// Width times height
+    max_pixels: usize,
+    /// Estimate of the memory needed to decode the frame, in bytes
+    max_allocation: usize,
+    max_scans: usize,
+    /// Marker segments with a length, so not counting RSTn in the scans
+    max_segments: usize,
+    /// Total size of the APPn and COM segments, in bytes
+    max_metadata: usize,
+}
+
+impl Limits {
+    /// Limits that allow any image of up to 2^28 pixels.
+    pub fn new() -> Limits {
+        Limits {
+            max_width: 0xffff,
+            max_height: 0xffff,
+            max_pixels: 1 << 28,
+            max_allocation: 1 << 31,
+            max_scans: 256,
+            max_segments: 16384,
+            max_metadata: 1 << 26,
+        }
+    }
+
+    pub fn max_width(mut self, max_width: usize) -> Limits {
+        self.max_width = max_width;
+        self
+    }
+
+    pub fn max_height(mut self, max_height: usize) -> Limits {
+        self.max_height = max_height;
+        self
+    }
+
+    pub fn max_pixels(mut self, max_pixels: usize) -> Limits {
+        self.max_pixels = max_pixels;
+        self
+    }
+
+    /// The estimate covers the decoded coefficients and samples, and the
+    /// pixel data, which take most of the memory. The file data is not counted.
+    pub fn max_allocation(mut self, max_allocation: usize) -> Limits {
+        self.max_allocation = max_allocation;
+        self
+    }
+
+    pub fn max_scans(mut self, max_scans: usize) -> Limits {
+        self.max_scans = max_scans;
+        self
+    }
+
+    pub fn max_segments(mut self, max_segments: usize) -> Limits {
+        self.max_segments = max_segments;
+        self
+    }
+
+    pub fn max_metadata(mut self, max_metadata: usize) -> Limits {
+        self.max_metadata = max_metadata;
+        self
+    }
+
+    /// Check the size of a frame, and the memory needed to decode it.
+    /// Returns the number of lines that may be decoded, which limits frames
+    /// whose height is only defined by a DNL segment after the first scan.
+    fn check_frame(&self,
+                   frame_header: &FrameHeader,
+                   bytes_per_pixel: usize)
+                   -> Result<usize, String> {
+        let width = frame_header.samples_per_line as usize;
+        let height = frame_header.num_lines as usize;
+        if width > self.max_width {
+            return Err(format!("Image width {} exceeds the limit of {}", width, self.max_width));
+        }
+        if height > self.max_height {
+            return Err(format!("Image height {} exceeds the limit of {}",
+                               height,
+                               self.max_height));
+        }
+        if width.saturating_mul(height) > self.max_pixels {
+            return Err(format!("Image of {} pixels exceeds the limit of {}",
+                               width * height,
+                               self.max_pixels));
+        }
+        let allocation = |lines: usize| decode_allocation(frame_header, lines, bytes_per_pixel);
+        if height > 0 {
+            let allocation = allocation(height);
+            if allocation > self.max_allocation {
+                return Err(format!("Decoding needs about {} bytes, which exceeds the limit of {}",
+                                   allocation,
+                                   self.max_allocation));
+            }
+            return Ok(height);
+        }
+
+        // The memory needed grows with the height, by whole MCU rows.
+        let mcu_lines = 8 * frame_header.frame_components
+            .iter()
+            .map(|c| c.vertical_sampling_factor as usize)
+            .max()
+            .unwrap_or(1);
+        let mcu_rows = self.max_allocation / allocation(mcu_lines).max(1);
+        let max_lines = (mcu_rows * mcu_lines)
+            .min(self.max_height)
+            .min(self.max_pixels / width.max(1))
+            .min(0xffff);
+        if max_lines == 0 {
+            return Err(format!("Decoding a line of {} pixels exceeds the limits", width));
+        }
+        Ok(max_lines)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::new()
+    }
+}
+
+/// The number of blocks of each component of a frame, including the blocks
+/// padding it to a whole number of MCUs. See JPEG A.2.
+fn frame_blocks(frame_header: &FrameHeader) -> Vec<usize> {
+    let components = &frame_header.frame_components;
+    let max_h = components.iter().map(|c| c.horizontal_sampling_factor as usize).max();
+    let max_v = components.iter().map(|c| c.vertical_sampling_factor as usize).max();
+    let mcus_x = div_ceil(frame_header.samples_per_line as usize, 8 * max_h.unwrap_or(1));
+    let mcus_y = div_ceil(frame_header.num_lines as usize, 8 * max_v.unwrap_or(1));
+    components.iter()
+        .map(|c| {
+            mcus_x * c.horizontal_sampling_factor as usize * mcus_y *
+            c.vertical_sampling_factor as usize
+        })
+        .collect()
+}
+
+/// An estimate of the memory needed to decode `lines` lines of a frame: the
+/// coefficients and samples of all components, as the scans hold them, the
+/// decoded planes, and the pixel data with a copy, for color conversions
+/// and orientation. The coefficients of a progressive frame are counted
+/// twice, as a truncated scan is decoded into a copy of them.
+fn decode_allocation(frame_header: &FrameHeader, lines: usize, bytes_per_pixel: usize) -> usize {
+    let components = &frame_header.frame_components;
+    let max_h = components.iter().map(|c| c.horizontal_sampling_factor as usize).max();
+    let max_v = components.iter().map(|c| c.vertical_sampling_factor as usize).max();
+    let width = frame_header.samples_per_line as usize;
+    let mcus = div_ceil(width, 8 * max_h.unwrap_or(1)) * div_ceil(lines, 8 * max_v.unwrap_or(1));
+    let blocks: usize = components.iter()
+        .map(|c| c.horizontal_sampling_factor as usize * c.vertical_sampling_factor as usize)
+        .sum();
+    // The coefficients, the vector holding them, and the samples.
+    let coefficients = 64 * mem::size_of::<i32>() + mem::size_of::<Block>();
+    let block_size = match frame_header.frame_type {
+        FrameType::ProgressiveDct => 2 * coefficients + 64,
+        _ => coefficients + 64,
+    };
+    let pixels = width * lines;
+    mcus.saturating_mul(blocks)
+        .saturating_mul(block_size)
+        .saturating_add(pixels.saturating_mul(components.len() + 2 * bytes_per_pixel))
+}
+
+/// Options for how `JPEGImage::parse_with_options` decodes an image.
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    /// Layout of the decoded pixel data
+    pixel_format: PixelFormat,
+    /// Rotate and mirror the pixels as described by the Exif orientation
+    apply_orientation: bool,
+    /// Convert the pixels using the embedded ICC profile
+    color_management: ColorManagement,
+    /// Skip corrupt entropy coded data instead of failing
+    lenient: bool,
+    /// How to fill what is skipped
+    concealment: Concealment,
+    limits: Limits,
+    /// Parse the segments without decoding the scans
+    headers_only: bool,
+}
+
+impl DecodeOptions {
+    pub fn new() -> DecodeOptions {
+        DecodeOptions {
+            pixel_format: PixelFormat::Rgb,
+            apply_orientation: false,
+            color_management: ColorManagement::None,
+            lenient: false,
+            concealment: Concealment::Gray,
+            limits: Limits::new(),
+            headers_only: false,
+        }
+    }
+
+    pub fn pixel_format(mut self, pixel_format: PixelFormat) -> DecodeOptions {
+        self.pixel_format = pixel_format;
+        self
+    }
+
+    /// Return the pixels as they should be displayed, according to the
+    /// Exif orientation, instead of as they are stored.
+    pub fn apply_orientation(mut self, apply_orientation: bool) -> DecodeOptions {
+        self.apply_orientation = apply_orientation;
+        self
+    }
+
+    /// Convert RGB and grayscale images with a matrix/TRC ICC profile to
+    /// sRGB, or linear sRGB. Images without such a profile are left as is.
+    pub fn color_management(mut self, color_management: ColorManagement) -> DecodeOptions {
+        self.color_management = color_management;
+        self
+    }
+
+    /// Decode what can be decoded of corrupt entropy coded data. On an error,
+    /// the decoder skips to the next restart marker, or to the end of the scan,
+    /// fills the MCUs in between as `concealment` says, and carries on.
+    /// Each of these is reported by `JPEGImage::incidents`.
+    ///
+    /// Other markers in the entropy coded data of the last scan are taken as
+    /// corrupt data too, and skipped to the next restart marker or EOI.
+    ///
+    /// In a progressive frame, a scan ends at corrupt data instead, and the
+    /// blocks after it keep what the earlier scans gave them.
+    pub fn lenient(mut self, lenient: bool) -> DecodeOptions {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn concealment(mut self, concealment: Concealment) -> DecodeOptions {
+        self.concealment = concealment;
+        self
+    }
+
+    /// Limit the resources used to decode, including those of embedded
+    /// JPEG thumbnails. `Limits::new()` is used by default.
+    pub fn limits(mut self, limits: Limits) -> DecodeOptions {
+        self.limits = limits;
+        self
+    }
+
+    /// Only parse the segments, for the metadata, tables and headers, and
+    /// leave `image_data` empty. Any frame type is accepted then, and the
+    /// size of the frame is not checked against the limits.
+    pub fn headers_only(mut self, headers_only: bool) -> DecodeOptions {
+        self.headers_only = headers_only;
+        self
+    }
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions::new()
+    }
+}
+
 type JPEGDimensions = (u16, u16);
-type ThumbnailDimensions = (u8, u8);
 
 /// Struct used to represent an image parsed by the library.
 /// This should contain everything one would want to know
 /// about the image.
 #[derive(Debug)]
 pub struct JPEGImage {
-    /// JFIF version the image is compliant to
-    version: JFIFVersion,
-    /// TODO:
+    /// JFIF version the image is compliant to, if it has a JFIF APP0 segment
+    version: Option<JFIFVersion>,
+    /// Units of `pixel_density`
     units: JFIFUnits,
-    /// TODO:
+    /// Horizontal and vertical pixel density
     pixel_density: (u16, u16),
     /// Image dimensions
     dimensions: JPEGDimensions,
-    /// Dimensions of the thumbnail, if present
-    /// TODO: add thumbnail.
-    /// Maybe join image data and dimensions to one struct?
-    thumbnail_dimensions: ThumbnailDimensions,
-    /// Optional comment
-    comment: Option<String>,
+    /// Thumbnail, if present
+    thumbnail: Option<Thumbnail>,
+    /// Text of the COM segments
+    comments: Vec<String>,
+    /// Exif data from APP1, if present
+    exif: Option<Exif>,
+    /// Main XMP packet from APP1, if present
+    xmp: Option<String>,
+    /// Extended XMP, reassembled from the APP1 chunks
+    extended_xmp: Option<String>,
+    /// ICC profile, reassembled from the APP2 chunks
+    icc_profile: Option<Vec<u8>>,
+    /// Adobe APP14 segment, if present
+    adobe: Option<AdobeSegment>,
+    /// Photoshop image resources, from the APP13 segments
+    image_resources: Vec<ImageResource>,
+    /// IPTC-IIM datasets, from the IPTC-NAA image resource
+    iptc: Option<Iptc>,
+    /// JPEG quality from the "Save for Web" Ducky APP12 segment, from 0 to 100
+    ducky_quality: Option<u32>,
+    /// Application segments that are not interpreted
+    application_segments: Vec<ApplicationSegment>,
+    /// Individual images listed in the MPF APP2 segment
+    mp_images: Vec<MpImage>,
     /// huffman tables for AC coefficients
     huffman_ac_tables: [Option<huffman::HuffmanTable>; 4],
     /// huffman tables for DC coefficients
@@ -78,14 +479,54 @@ pub struct JPEGImage {
     /// Frame header data
     frame_header: Option<FrameHeader>,
     scan_headers: Option<Vec<ScanHeader>>,
-    /// Actual image data.
+    /// Number of MCUs in each restart interval, or 0 without restart markers
+    restart_interval: u16,
+    /// Corrupt entropy coded data that was concealed by a lenient decode
+    incidents: Vec<Incident>,
+    /// Whether the file ended before all of the image data
+    incomplete: bool,
+    /// Layout of `image_data`
+    pixel_format: PixelFormat,
+    /// The Exif orientation applied to `image_data`, if any
+    applied_orientation: Option<u16>,
+    /// Actual image data, packed as described by `pixel_format`.
     /// NOTE: only support 8-bit precision
     /// TODO: Add support for other precisions
-    image_data: Option<Vec<(u8, u8, u8)>>,
+    image_data: Option<Vec<u8>>,
+}
+
+/// The coding process of a frame, from its SOFn marker. See JPEG B.1.1.3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    /// SOF0
+    BaselineDct,
+    /// SOF1
+    ExtendedSequentialDct,
+    /// SOF2
+    ProgressiveDct,
+    /// SOF3
+    Lossless,
+    /// Any other SOFn, for differential or arithmetic coding, with its marker
+    Other(u8),
+}
+
+impl FrameType {
+    pub fn from_marker(marker: u8) -> FrameType {
+        match marker {
+            0xc0 => FrameType::BaselineDct,
+            0xc1 => FrameType::ExtendedSequentialDct,
+            0xc2 => FrameType::ProgressiveDct,
+            0xc3 => FrameType::Lossless,
+            n => FrameType::Other(n),
+        }
+    }
 }
 
+/// A frame header. See JPEG B.2.2.
 #[derive(Debug, Clone)]
 pub struct FrameHeader {
+    /// The SOFn marker
+    frame_type: FrameType,
     /// Bits per sample of each component in the frame
     sample_precision: u8,
     /// The maximum number of lines in the source image
@@ -98,6 +539,76 @@ pub struct FrameHeader {
     pub frame_components: Vec<FrameComponentHeader>,
 }
 
+impl FrameHeader {
+    /// Parse the payload of an SOFn segment. See JPEG B.2.2.
+    ///
+    ///  X’FF’, SOFn, length, precision, lines, samples per line, components,
+    ///  (component id, sampling factors, quantization table selector)n
+    pub(crate) fn parse(frame_type: FrameType, data: &[u8]) -> Result<FrameHeader, String> {
+        if data.len() < 6 || data.len() < 6 + 3 * data[5] as usize {
+            return Err("Frame header is truncated".to_string());
+        }
+        let frame_components: Vec<FrameComponentHeader> = data[6..6 + 3 * data[5] as usize]
+            .chunks(3)
+            .map(|component| {
+                FrameComponentHeader {
+                    component_id: component[0],
+                    horizontal_sampling_factor: (component[1] & 0xf0) >> 4,
+                    vertical_sampling_factor: component[1] & 0x0f,
+                    quantization_selector: component[2],
+                }
+            })
+            .collect();
+        // Sampling factors are 1 to 4.
+        let valid_sampling = |factor: u8| (1..=4).contains(&factor);
+        if !frame_components.iter().all(|c| {
+            valid_sampling(c.horizontal_sampling_factor) &&
+            valid_sampling(c.vertical_sampling_factor)
+        }) {
+            return Err("Sampling factor out of range".to_string());
+        }
+        if let Some(component) = frame_components.iter().find(|c| c.quantization_selector > 3) {
+            return Err(format!("Quantization table selector {} is out of range",
+                               component.quantization_selector));
+        }
+        Ok(FrameHeader {
+            frame_type,
+            sample_precision: data[0],
+            num_lines: u8s_to_u16(&data[1..]),
+            samples_per_line: u8s_to_u16(&data[3..]),
+            image_components: data[5],
+            frame_components,
+        })
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        self.frame_type
+    }
+
+    /// Bits per sample, 8 for baseline frames
+    pub fn sample_precision(&self) -> u8 {
+        self.sample_precision
+    }
+
+    /// The height as given in the header, which is 0 if a DNL segment
+    /// gives it instead
+    pub fn num_lines(&self) -> u16 {
+        self.num_lines
+    }
+
+    pub fn samples_per_line(&self) -> u16 {
+        self.samples_per_line
+    }
+
+    pub fn image_components(&self) -> u8 {
+        self.image_components
+    }
+
+    pub fn frame_components(&self) -> &[FrameComponentHeader] {
+        &self.frame_components
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FrameComponentHeader {
     /// Component id
@@ -110,22 +621,44 @@ pub struct FrameComponentHeader {
     pub quantization_selector: u8,
 }
 
+/// A scan header. See JPEG B.2.3.
 #[derive(Debug, Clone)]
 pub struct ScanHeader {
     /// Number of components in the scan.
     num_components: u8,
     /// Headers for each component
     pub scan_components: Vec<ScanComponentHeader>,
-    /// (?) Should be zero for seq. DCT
+    /// First DCT coefficient in the scan, in zigzag order. Zero for sequential DCT
     start_spectral_selection: u8,
-    /// (?) Should be 63 for seq. DCT
+    /// Last DCT coefficient in the scan, in zigzag order. 63 for sequential DCT
     end_spectral_selection: u8,
-    /// Something something point transform
+    /// Point transform of the previous scan of these coefficients, in
+    /// successive approximation. Zero for sequential DCT
     successive_approximation_bit_pos_high: u8,
-    /// Something something point transform
+    /// Point transform of this scan. Zero for sequential DCT
     successive_approximation_bit_pos_low: u8,
 }
 
+impl ScanHeader {
+    pub fn num_components(&self) -> u8 {
+        self.num_components
+    }
+
+    pub fn scan_components(&self) -> &[ScanComponentHeader] {
+        &self.scan_components
+    }
+
+    /// First and last DCT coefficient in the scan, from 0 to 63
+    pub fn spectral_selection(&self) -> (u8, u8) {
+        (self.start_spectral_selection, self.end_spectral_selection)
+    }
+
+    /// Successive approximation bit position high and low, Ah and Al
+    pub fn successive_approximation(&self) -> (u8, u8) {
+        (self.successive_approximation_bit_pos_high, self.successive_approximation_bit_pos_low)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ScanComponentHeader {
     /// Component id
@@ -144,34 +677,40 @@ enum Marker {
     Comment,
     QuantizationTable,
     BaselineDCT,
+    ProgressiveDCT,
     RestartIntervalDefinition,
+    DefineNumberOfLines,
     ApplicationSegment0,
+    ApplicationSegment1,
+    ApplicationSegment2,
     ApplicationSegment12,
+    ApplicationSegment13,
     ApplicationSegment14,
+    /// Any other APPn, with its marker
+    ApplicationSegment(u8),
     StartOfImage,
     EndOfImage,
 }
 
-fn bytes_to_marker(data: &[u8]) -> Option<Marker> {
-    if data[0] != 0xff {
-        return None;
-    }
-    let mut n = data[1];
-    if n == 0 {
-        n = data[2];
-    }
+fn byte_to_marker(n: u8) -> Option<Marker> {
     use self::Marker::*;
     let marker = match n {
         0xc0 => BaselineDCT,
+        0xc2 => ProgressiveDCT,
         0xc4 => DefineHuffmanTable,
         0xd8 => StartOfImage,
         0xd9 => EndOfImage,
         0xda => StartOfScan,
         0xdb => QuantizationTable,
+        0xdc => DefineNumberOfLines,
         0xdd => RestartIntervalDefinition,
         0xe0 => ApplicationSegment0,
+        0xe1 => ApplicationSegment1,
+        0xe2 => ApplicationSegment2,
         0xec => ApplicationSegment12,
+        0xed => ApplicationSegment13,
         0xee => ApplicationSegment14,
+        0xe3..=0xef => ApplicationSegment(n),
         0xfe => Comment,
         _ => return None,
     };
@@ -182,113 +721,179 @@ fn bytes_to_marker(data: &[u8]) -> Option<Marker> {
 impl JPEGImage {
     fn new() -> JPEGImage {
         JPEGImage {
-            version: JFIFVersion::Unknown(0, 0),
-            units: JFIFUnits::Unknown(0),
-            pixel_density: (0, 0),
+            version: None,
+            // JFIF B.2: without units, the density gives the aspect ratio.
+            units: JFIFUnits::NoUnits,
+            pixel_density: (1, 1),
             dimensions: (0, 0),
-            thumbnail_dimensions: (0, 0),
-            comment: None,
+            thumbnail: None,
+            comments: Vec::new(),
+            exif: None,
+            xmp: None,
+            extended_xmp: None,
+            icc_profile: None,
+            adobe: None,
+            image_resources: Vec::new(),
+            iptc: None,
+            ducky_quality: None,
+            application_segments: Vec::new(),
+            mp_images: Vec::new(),
             huffman_ac_tables: [None, None, None, None],
             huffman_dc_tables: [None, None, None, None],
             quantization_tables: [None, None, None, None],
             frame_header: None,
             scan_headers: None,
+            restart_interval: 0,
+            incidents: Vec::new(),
+            incomplete: false,
+            pixel_format: PixelFormat::Rgb,
+            applied_orientation: None,
             image_data: None,
         }
     }
 
     pub fn parse(vec: Vec<u8>) -> Result<JPEGImage, String> {
+        JPEGImage::parse_with_options(vec, &DecodeOptions::new())
+    }
+
+    pub fn parse_with_options(vec: Vec<u8>, options: &DecodeOptions) -> Result<JPEGImage, String> {
+        JPEGImage::parse_nested(vec, options, 0)
+    }
+
+    /// Parse an image embedded `depth` levels deep in the metadata of another.
+    fn parse_nested(vec: Vec<u8>,
+                    options: &DecodeOptions,
+                    depth: usize)
+                    -> Result<JPEGImage, String> {
         let mut image = JPEGImage::new();
+        image.pixel_format = options.pixel_format;
 
-        let mut i = 0;
-        while i < vec.len() {
-            if let Some(marker) = bytes_to_marker(&vec[i..]) {
-                if marker == Marker::EndOfImage || marker == Marker::StartOfImage {
-                    // These markers doesn't have length bytes, so they must be
-                    // handled separately, in order to to avoid out-of-bounds indexes,
-                    // or reading nonsense lengths.
-                    i += 2;
-                    continue;
-                }
+        // Samples of each frame component, as they are decoded by the scans.
+        let mut planes: Vec<Vec<u8>> = Vec::new();
+        // The coefficients of each frame component of a progressive frame, which
+        // its scans add to, and whether a scan of the component was read.
+        let mut coefficients: Vec<Vec<Block>> = Vec::new();
+        let mut scanned: Vec<bool> = Vec::new();
+        // ICC profile and extended XMP chunks, which may come in any order.
+        let mut icc_chunks: Vec<IccChunk> = Vec::new();
+        let mut extended_xmp_chunks: Vec<ExtendedXmpChunk> = Vec::new();
+        // Photoshop image resources, which may be split over several APP13 segments.
+        let mut photoshop_data: Vec<u8> = Vec::new();
+        // Offset of the MP header, which the MP entries are relative to, and the entries.
+        let mut mp_index: Option<(usize, Vec<MpEntry>)> = None;
 
+        // What is counted against the limits.
+        let limits = &options.limits;
+        let mut num_segments = 0;
+        let mut num_scans = 0;
+        let mut metadata_size = 0;
+        // Number of lines the scans may decode.
+        let mut max_lines = 0;
+
+        let mut end_of_image = false;
+        let mut segments = segments::segments(&vec);
+        while let Some(segment) = segments.next() {
+            // A file cut off after its frame header is shown as far as it goes.
+            let segment = match segment {
+                Ok(segment) => segment,
+                Err(_) if image.frame_header.is_some() => {
+                    image.incomplete = true;
+                    break;
+                }
+                Err(error) => return Err(error),
+            };
+            let code = match segment.kind {
+                SegmentKind::Marker(code) => code,
+                // Anything after EOI, like the other images of an MPF file,
+                // is not part of this image.
+                SegmentKind::StandaloneMarker(0xd9) => {
+                    end_of_image = true;
+                    break;
+                }
+                // Other markers without length bytes carry nothing to parse,
+                // and the scans read their entropy coded data themselves.
+                _ => continue,
+            };
+            num_segments += 1;
+            if num_segments > limits.max_segments {
+                return Err(format!("More than {} segments", limits.max_segments));
+            }
+            if (0xe0..=0xef).contains(&code) || code == 0xfe {
+                metadata_size += segment.payload.len();
+                if metadata_size > limits.max_metadata {
+                    return Err(format!("More than {} bytes of metadata", limits.max_metadata));
+                }
+            }
+            if let Some(marker) = byte_to_marker(code) {
                 // NOTE: this does not count the length bytes anymore!
                 // TODO: Maybe do count them? In order to make it less confusing
-                let data_length = (u8s_to_u16(&vec[i + 2..]) - 2) as usize;
-                i += 4;
+                let data_length = segment.payload.len();
+                let mut i = segment.offset + 4;
 
                 match marker {
                     Marker::Comment => {
-                        let comment = str::from_utf8(&vec[i..i + data_length])
-                            .map(|s| s.to_string())
-                            .ok();
-                        image.comment = comment;
+                        // JPEG B.2.4.5. The encoding is not specified,
+                        // but it is usually ASCII or UTF-8.
+                        let comment = String::from_utf8_lossy(&vec[i..i + data_length]);
+                        image.comments.push(comment.into_owned());
                     }
                     Marker::QuantizationTable => {
                         // JPEG B.2.4.1
+                        let segment_end = i + data_length;
                         let mut index = i;
-                        while index < i + data_length {
+                        while index < segment_end {
                             let precision = (vec[index] & 0xf0) >> 4;
                             let identifier = vec[index] & 0x0f;
+                            if identifier > 3 {
+                                return Err(format!("Quantization table id {} is out of range",
+                                                   identifier));
+                            }
                             // Although precision == 0 is "guaranteed" by the standard,
                             // images with 16-bit precision to exist.
-                            if precision == 0 {
+                            let table_length = match precision {
+                                0 => 64,
+                                1 => 128,
+                                _ => return Err(format!("Unknown DQT precision {}", precision)),
+                            };
+                            if index + 1 + table_length > segment_end {
+                                return Err("DQT segment is truncated".to_string());
+                            }
+                            let bytes = &vec[index + 1..index + 1 + table_length];
+                            let table: Vec<u16> = if precision == 0 {
                                 // 8-bit
-                                let table: Vec<u16> = vec[index + 1..index + 65]
-                                    .iter()
-                                    .map(|b| *b as u16)
-                                    .collect();
-
-                                image.quantization_tables[identifier as usize] = Some(table);
-                                index += 65; // 64 entries + one header byte
-                            } else if precision == 1 {
-                                let bytes = &vec[index + 1..index + 129];
-                                let mut i = 0;
-                                let mut table = Vec::with_capacity(64);
-                                while i < 128 {
-                                    let msb = bytes[i] as u16;
-                                    let lsb = bytes[i + 1] as u16;
-                                    table.push(msb << 8 | lsb);
-                                    i += 2;
-                                }
-                                image.quantization_tables[identifier as usize] = Some(table);
-                                index += 129;
+                                bytes.iter().map(|b| *b as u16).collect()
                             } else {
-                                panic!("Unknown precision of quantization table: {}", precision);
-                            }
+                                bytes.chunks(2).map(u8s_to_u16).collect()
+                            };
+                            image.quantization_tables[identifier as usize] = Some(table);
+                            index += 1 + table_length; // entries + one header byte
                         }
                     }
-                    Marker::BaselineDCT => {
-                        // JPEG B.2.2
-                        let sample_precision = vec[i];
-                        let num_lines = u8s_to_u16(&vec[i + 1..]);
-                        let samples_per_line = u8s_to_u16(&vec[i + 3..]);
-                        let image_components = vec[i + 5];
-
-                        let mut frame_components = Vec::with_capacity(image_components as usize);
-                        let mut index = i + 6;
-                        for component in 0..image_components {
-                            let component_id = vec[index];
-                            let horizontal_sampling_factor = (vec[index + 1] & 0xf0) >> 4;
-                            let vertical_sampling_factor = vec[index + 1] & 0x0f;
-                            let quantization_selector = vec[index + 2];
-
-                            frame_components.push(FrameComponentHeader {
-                                component_id: component_id,
-                                horizontal_sampling_factor: horizontal_sampling_factor,
-                                vertical_sampling_factor: vertical_sampling_factor,
-                                quantization_selector: quantization_selector,
-                            });
-                            index += 3;
+                    Marker::BaselineDCT |
+                    Marker::ProgressiveDCT => {
+                        let frame_header = FrameHeader::parse(FrameType::from_marker(code),
+                                                              &vec[i..i + data_length])?;
+                        let progressive = marker == Marker::ProgressiveDCT;
+                        if !options.headers_only {
+                            // The coefficients are kept for the whole frame, so its
+                            // height must be known up front.
+                            if progressive && frame_header.num_lines == 0 {
+                                return Err("Progressive frame without a height".to_string());
+                            }
+                            max_lines =
+                                limits.check_frame(&frame_header,
+                                                   options.pixel_format.bytes_per_pixel())?;
                         }
-                        let frame_header = FrameHeader {
-                            sample_precision: sample_precision,
-                            num_lines: num_lines,
-                            samples_per_line: samples_per_line,
-                            image_components: image_components,
-                            frame_components: frame_components,
-                        };
-                        image.dimensions = (samples_per_line, num_lines);
-                        image.frame_header = Some(frame_header)
+                        image.dimensions = (frame_header.samples_per_line, frame_header.num_lines);
+                        planes = vec![Vec::new(); frame_header.frame_components.len()];
+                        if progressive && !options.headers_only {
+                            coefficients = frame_blocks(&frame_header)
+                                .iter()
+                                .map(|&blocks| vec![vec![0; 64]; blocks])
+                                .collect();
+                            scanned = vec![false; coefficients.len()];
+                        }
+                        image.frame_header = Some(frame_header);
                     }
                     Marker::DefineHuffmanTable => {
                         // JPEG B.2.4.2
@@ -302,6 +907,14 @@ impl JPEGImage {
                             let table_class = (vec[huffman_index] & 0xf0) >> 4;
                             let table_dest_id = vec[huffman_index] & 0x0f;
                             huffman_index += 1;
+                            if table_class > 1 || table_dest_id > 3 {
+                                return Err(format!("DHT class {} or id {} is out of range",
+                                                   table_class,
+                                                   table_dest_id));
+                            }
+                            if huffman_index + 16 > segment_end {
+                                return Err("DHT segment is truncated".to_string());
+                            }
 
                             // There are `size_area[i]` number of codes of length `i + 1`.
                             let size_area: &[u8] = &vec[huffman_index..huffman_index + 16];
@@ -310,6 +923,11 @@ impl JPEGImage {
                             // TODO: replace with `.sum` as of Rust 1.11
                             let number_of_codes = size_area.iter()
                                 .fold(0, |a, b| a + (*b as usize));
+                            let values_end = huffman_index + number_of_codes;
+                            if number_of_codes == 0 || values_end > segment_end {
+                                return Err(format!("DHT segment has {} codes",
+                                                   number_of_codes));
+                            }
 
                             // Code `i` has value `data_area[i]`
                             let data_area: &[u8] = &vec[huffman_index..huffman_index +
@@ -330,7 +948,14 @@ impl JPEGImage {
                     }
                     Marker::StartOfScan => {
                         // JPEG B.2.3
+                        num_scans += 1;
+                        if num_scans > limits.max_scans {
+                            return Err(format!("More than {} scans", limits.max_scans));
+                        }
                         let num_components = vec[i];
+                        if data_length < 1 + 2 * num_components as usize + 3 {
+                            return Err("Scan header is truncated".to_string());
+                        }
                         let mut scan_components = Vec::new();
                         for component in 0..num_components {
                             scan_components.push(ScanComponentHeader {
@@ -345,8 +970,8 @@ impl JPEGImage {
                         // We don't need it for simple decoding, but it might be useful
                         // if we want to print info (eg, all headers) for an image.
                         let scan_header = ScanHeader {
-                            num_components: num_components,
-                            scan_components: scan_components,
+                            num_components,
+                            scan_components,
                             start_spectral_selection: vec[i + 1],
                             end_spectral_selection: vec[i + 2],
                             successive_approximation_bit_pos_high: (vec[i + 3] & 0xf0) >> 4,
@@ -358,114 +983,1187 @@ impl JPEGImage {
                         if image.scan_headers.is_none() {
                             image.scan_headers = Some(Vec::new());
                         }
-                        image.scan_headers
-                            .as_mut()
-                            .map(|v| v.push(scan_header.clone()));
-
-                        // Copy data, and replace 0xff00 with 0xff.
-                        let mut bytes_skipped = 0;
-                        let mut encoded_data = Vec::new();
-                        {
-                            let mut i = i;
-                            while i < vec.len() {
-                                encoded_data.push(vec[i]);
-                                if vec[i] == 0xff && vec[i + 1] == 0x00 {
-                                    // Skip the 0x00 part here.
-                                    i += 1;
-                                    bytes_skipped += 1;
-                                }
-                                i += 1;
-                            }
+                        if let Some(v) = image.scan_headers.as_mut() {
+                            v.push(scan_header.clone());
+                        }
+                        if options.headers_only {
+                            continue;
                         }
 
+                        // The entropy coded data ends at the first marker that is
+                        // not RSTn, not counting fill bytes.
+                        let data_start = i.min(vec.len());
+                        let data_end = (data_start..vec.len().saturating_sub(1))
+                            .find(|&i| {
+                                vec[i] == 0xff && !matches!(vec[i + 1], 0x00 | 0xff | 0xd0..=0xd7)
+                            })
+                            .unwrap_or(vec.len());
 
-                        let frame_header = image.frame_header.clone().unwrap();
-                        let mut jpeg_decoder = JPEGDecoder::new(encoded_data.as_slice())
-                            .frame_header(frame_header)
+                        let frame_header = image.frame_header
+                            .clone()
+                            .ok_or("Scan before the frame header".to_string())?;
+                        // A lenient decode skips markers in corrupt data. Scans that
+                        // are not interleaved are followed by the markers of the next
+                        // one, so only the scan with the last component does that,
+                        // and only in sequential frames, where it is the last scan.
+                        let progressive = frame_header.frame_type == FrameType::ProgressiveDct;
+                        let last_component = frame_header.frame_components
+                            .last()
+                            .map(|component| component.component_id);
+                        let is_last =
+                            |c: &ScanComponentHeader| Some(c.component_id) == last_component;
+                        let resync = options.lenient && !progressive &&
+                                     scan_header.scan_components.iter().any(is_last);
+                        let encoded_data = if resync {
+                            &vec[data_start..]
+                        } else {
+                            &vec[data_start..data_end]
+                        };
+                        // The decoder allocates for the components of the frame only.
+                        if let Some(component) = scan_header.scan_components.iter().find(|c| {
+                            !frame_header.frame_components
+                                .iter()
+                                .any(|f| f.component_id == c.component_id)
+                        }) {
+                            return Err(format!("Scan component {} is not in the frame",
+                                               component.component_id));
+                        }
+                        let mut jpeg_decoder = JPEGDecoder::new(encoded_data)
+                            .frame_header(frame_header.clone())
                             // need `.clone()`, or we hit some LLVM bug??
                             .scan_header(scan_header.clone())
                             .dimensions((image.dimensions.0 as usize,
-                                         image.dimensions.1 as usize));
+                                         image.dimensions.1 as usize))
+                            .luma_only(image.is_luma_only(options))
+                            .restart_interval(image.restart_interval)
+                            .concealment(Some(options.concealment).filter(|_| options.lenient))
+                            .resync(resync)
+                            .offset(data_start)
+                            .truncated(data_end == vec.len())
+                            .max_lines(max_lines);
 
                         // Add tables to `jpeg_decoder`
                         for (i, table) in image.huffman_ac_tables.iter().enumerate() {
-                            if let &Some(ref table) = table {
+                            if let Some(table) = table {
                                 jpeg_decoder.huffman_ac_tables(i as u8, table.clone());
                             }
                         }
 
                         for (i, table) in image.huffman_dc_tables.iter().enumerate() {
-                            if let &Some(ref table) = table {
+                            if let Some(table) = table {
                                 jpeg_decoder.huffman_dc_tables(i as u8, table.clone());
                             }
                         }
 
                         for (i, table) in image.quantization_tables.iter().enumerate() {
-                            if let &Some(ref table) = table {
+                            if let Some(table) = table {
                                 jpeg_decoder.quantization_table(i as u8, table.clone());
                             }
                         }
 
-                        let (image_data, bytes_read) = jpeg_decoder.decode();
-                        image.image_data = Some(image_data);
+                        let scan_planes = if progressive {
+                            let mut indices = Vec::new();
+                            for component in &scan_header.scan_components {
+                                let index = frame_header.frame_components
+                                    .iter()
+                                    .position(|c| c.component_id == component.component_id)
+                                    .ok_or(format!("Scan component {} is not in the frame",
+                                                   component.component_id))?;
+                                if indices.contains(&index) {
+                                    return Err(format!("Scan component {} is in the scan twice",
+                                                       component.component_id));
+                                }
+                                indices.push(index);
+                            }
+                            // A truncated scan is decoded into a copy, which is dropped
+                            // if the scan is incomplete. The image is then shown as the
+                            // scans before it left it.
+                            let truncated = data_end == vec.len();
+                            let mut scan_blocks: Vec<Vec<Block>> = indices.iter()
+                                .map(|&index| if truncated {
+                                    coefficients[index].clone()
+                                } else {
+                                    mem::take(&mut coefficients[index])
+                                })
+                                .collect();
+                            jpeg_decoder.decode_progressive(&mut scan_blocks)?;
+                            if !jpeg_decoder.is_incomplete() {
+                                for (&index, blocks) in indices.iter().zip(scan_blocks) {
+                                    coefficients[index] = blocks;
+                                    scanned[index] = true;
+                                }
+                            }
+                            Vec::new()
+                        } else {
+                            jpeg_decoder.decode()?
+                        };
+                        image.incidents.extend_from_slice(jpeg_decoder.incidents());
+                        image.incomplete |= jpeg_decoder.is_incomplete();
+                        if resync {
+                            segments.skip_to(data_start + jpeg_decoder.end());
+                        }
+                        // Until the DNL segment, the height is what the first scan holds.
+                        if image.dimensions.1 == 0 {
+                            image.dimensions.1 = jpeg_decoder.height() as u16;
+                        }
+                        for (scan_component, plane) in scan_header.scan_components
+                            .iter()
+                            .zip(scan_planes) {
+                            let frame_component_i = frame_header.frame_components
+                                .iter()
+                                .position(|c| c.component_id == scan_component.component_id)
+                                .ok_or(format!("Scan component {} is not in the frame",
+                                               scan_component.component_id))?;
+                            if !plane.is_empty() {
+                                planes[frame_component_i] = plane;
+                            }
+                        }
 
-                        // Since we are calculating how much data there is in this segment,
-                        // we update `i` manually, and `continue` the `while` loop.
-                        i += bytes_read + bytes_skipped;
-                        continue;
+                    }
+                    Marker::DefineNumberOfLines => {
+                        // JPEG B.2.5: the height of a frame with 0 lines in its header,
+                        // which follows the first scan.
+                        //
+                        //  X’FF’, DNL, length, number of lines
+                        let height_unknown = image.frame_header
+                            .as_ref()
+                            .map(|frame_header| frame_header.num_lines == 0)
+                            .unwrap_or(false);
+                        if height_unknown && data_length >= 2 {
+                            let num_lines = u8s_to_u16(&vec[i..]);
+                            // Without decoding, the number of lines in the scan is not known.
+                            let scanned = if options.headers_only {
+                                num_lines
+                            } else {
+                                image.dimensions.1
+                            };
+                            if num_lines == 0 || num_lines > scanned {
+                                return Err(format!("DNL gives {} lines, but the first scan has {}",
+                                                   num_lines,
+                                                   image.dimensions.1));
+                            }
+                            if !options.headers_only && num_lines as usize > max_lines {
+                                return Err(format!("Image height {} exceeds the limits",
+                                                   num_lines));
+                            }
+                            // The scan is padded to whole MCUs, which are cut off here.
+                            image.dimensions.1 = num_lines;
+                            let samples = image.dimensions.0 as usize * num_lines as usize;
+                            for plane in &mut planes {
+                                plane.truncate(samples);
+                            }
+                        }
                     }
                     Marker::RestartIntervalDefinition => {
                         // JPEG B.2.4.4
-                        // TODO: support this
-                        panic!("got to restart interval def")
+                        //
+                        //  X’FF’, DRI, length, restart interval
+                        if data_length >= 2 {
+                            image.restart_interval = u8s_to_u16(&vec[i..]);
+                        }
                     }
                     Marker::ApplicationSegment0 => {
                         // JFIF puts stuff here.
                         //
                         //
-                        //  X’FF’, APP0, length, identifier, version, units,
+                        //  X’FF’, APP0, length, "JFIF\0", version, units,
                         //  Xdensity, Ydensity, Xthumbnail, Ythumbnail, (RGB)n
+                        if data_length >= 14 && &vec[i..i + 5] == b"JFIF\0" {
+                            image.version = Some(JFIFVersion::from_bytes(vec[i + 5], vec[i + 6]));
+                            image.units = JFIFUnits::from_u8(vec[i + 7]);
+                            image.pixel_density = (u8s_to_u16(&vec[i + 8..]),
+                                                   u8s_to_u16(&vec[i + 10..]));
 
-                        let identifier = &vec[i..i + 6];
-                        let version = JFIFVersion::from_bytes(vec[7], vec[8]);
-                        let units = JFIFUnits::from_u8(vec[13]);
+                            // A thumbnail that does not fit in the segment is ignored.
+                            let width = vec[i + 12] as usize;
+                            let height = vec[i + 13] as usize;
+                            let thumbnail_length = 3 * width * height;
+                            if width > 0 && height > 0 && 14 + thumbnail_length <= data_length {
+                                image.thumbnail = Some(Thumbnail {
+                                    width,
+                                    height,
+                                    data: vec[i + 14..i + 14 + thumbnail_length].to_vec(),
+                                });
+                            }
+                        }
 
-                        let x_density = u8s_to_u16(&vec[10..12]);
-                        let y_density = u8s_to_u16(&vec[12..14]);
+                        // The JFXX extension puts a thumbnail here, in another APP0.
+                        //
+                        //  X’FF’, APP0, length, "JFXX\0", extension code, extension data
+                        if data_length >= 6 && &vec[i..i + 5] == b"JFXX\0" {
+                            if let Some(thumbnail) =
+                                   JPEGImage::parse_jfxx_thumbnail(&vec[i + 5..i + data_length],
+                                                                   options,
+                                                                   depth) {
+                                image.thumbnail = Some(thumbnail);
+                            }
+                        }
+                    }
+                    Marker::ApplicationSegment1 => {
+                        // Exif puts stuff here. See Exif 2.3, section 4.5.4.
+                        //
+                        //  X’FF’, APP1, length, "Exif\0\0", TIFF header, IFDs
+                        //
+                        // Broken Exif data is ignored, as it does not prevent decoding.
+                        //
+                        // XMP puts stuff here too, as a namespace URI followed by
+                        // the packet, or by a part of the extended XMP.
+                        let segment = &vec[i..i + data_length];
+                        if segment.starts_with(b"Exif\0\0") {
+                            image.exif = Exif::parse(&segment[6..]).ok();
+                        } else if segment.starts_with(xmp::XMP_IDENTIFIER) {
+                            let packet = &segment[xmp::XMP_IDENTIFIER.len()..];
+                            image.xmp = Some(String::from_utf8_lossy(packet).into_owned());
+                        } else if let Some(chunk) = ExtendedXmpChunk::parse(segment) {
+                            extended_xmp_chunks.push(chunk);
+                        }
+                    }
+                    Marker::ApplicationSegment2 => {
+                        // ICC profiles are split into chunks here.
+                        //
+                        //  X’FF’, APP2, length, "ICC_PROFILE\0", sequence number,
+                        //  number of chunks, profile data
+                        if let Some(chunk) = IccChunk::parse(&vec[i..i + data_length]) {
+                            icc_chunks.push(chunk);
+                        }
 
-                        let thumbnail_dimensions = (vec[14], vec[15]);
+                        // The Multi-Picture Format puts its index here.
+                        //
+                        //  X’FF’, APP2, length, "MPF\0", MP header (TIFF header, MP index IFD)
+                        let segment = &vec[i..i + data_length];
+                        if mp_index.is_none() && segment.starts_with(mpf::MPF_IDENTIFIER) {
+                            let header = &segment[mpf::MPF_IDENTIFIER.len()..];
+                            if let Ok(entries) = mpf::parse_index(header) {
+                                mp_index = Some((i + mpf::MPF_IDENTIFIER.len(), entries));
+                            }
+                        }
                     }
                     Marker::ApplicationSegment12 => {
-                        panic!("got {:?}", marker);
+                        // Photoshop "Save for Web" puts stuff here, as blocks ending
+                        // with a zero tag. Block 1 holds the quality.
+                        //
+                        //  X’FF’, APP12, length, "Ducky", (tag, length, data)n, X’0000’
+                        let segment = &vec[i..i + data_length];
+                        if segment.starts_with(b"Ducky") {
+                            let mut index = 5;
+                            while index + 4 <= segment.len() {
+                                let tag = u8s_to_u16(&segment[index..]);
+                                let length = u8s_to_u16(&segment[index + 2..]) as usize;
+                                let block = match segment.get(index + 4..index + 4 + length) {
+                                    Some(block) if tag != 0 => block,
+                                    _ => break,
+                                };
+                                if tag == 1 && length == 4 {
+                                    image.ducky_quality = Some((u8s_to_u16(block) as u32) << 16 |
+                                                               u8s_to_u16(&block[2..]) as u32);
+                                }
+                                index += 4 + length;
+                            }
+                        } else {
+                            image.application_segments.push(ApplicationSegment {
+                                marker: 0xec,
+                                data: segment.to_vec(),
+                            });
+                        }
+                    }
+                    Marker::ApplicationSegment13 => {
+                        // Photoshop puts its image resources here, which hold the IPTC data.
+                        //
+                        //  X’FF’, APP13, length, "Photoshop 3.0\0", image resource blocks
+                        let segment = &vec[i..i + data_length];
+                        if segment.starts_with(photoshop::PHOTOSHOP_IDENTIFIER) {
+                            let resources = &segment[photoshop::PHOTOSHOP_IDENTIFIER.len()..];
+                            photoshop_data.extend_from_slice(resources);
+                        } else {
+                            image.application_segments.push(ApplicationSegment {
+                                marker: 0xed,
+                                data: segment.to_vec(),
+                            });
+                        }
+                    }
+                    Marker::ApplicationSegment(n) => {
+                        image.application_segments.push(ApplicationSegment {
+                            marker: n,
+                            data: vec[i..i + data_length].to_vec(),
+                        });
                     }
                     Marker::ApplicationSegment14 => {
-                        panic!("got {:?}", marker);
+                        // Adobe puts stuff here.
+                        //
+                        //  X’FF’, APP14, length, "Adobe", version, flags0, flags1, transform
+                        if data_length >= 12 && &vec[i..i + 5] == b"Adobe" {
+                            image.adobe = Some(AdobeSegment {
+                                version: u8s_to_u16(&vec[i + 5..]),
+                                flags0: u8s_to_u16(&vec[i + 7..]),
+                                flags1: u8s_to_u16(&vec[i + 9..]),
+                                transform: AdobeColorTransform::from_u8(vec[i + 11]),
+                            });
+                        }
                     }
                     // Already handled
                     Marker::StartOfImage => {}
                     Marker::EndOfImage => {}
                 }
-                i += data_length;
-            } else {
-                panic!("Unhandled byte marker: {:02x} {:02x} (i={}/{})",
-                       vec[i],
-                       vec[i + 1],
-                       i,
-                       vec.len());
+            } else if (0xc1..=0xcf).contains(&code) && code != 0xc4 && code != 0xc8 &&
+                      code != 0xcc {
+                if !options.headers_only {
+                    return Err(format!("Unsupported frame type: {}",
+                                       segments::marker_name(code)));
+                }
+                let frame_header = FrameHeader::parse(FrameType::from_marker(code),
+                                                      segment.payload)?;
+                image.dimensions = (frame_header.samples_per_line, frame_header.num_lines);
+                image.frame_header = Some(frame_header);
+            }
+            // Other segments are not needed for decoding, and are skipped.
+        }
+
+        // A profile with missing or inconsistent chunks is ignored.
+        if !icc_chunks.is_empty() {
+            image.icc_profile = icc::assemble_profile(&icc_chunks).ok();
+        }
+        // Likewise for extended XMP, which must match the GUID in the main packet.
+        let extended_guid = image.xmp.as_ref().and_then(|packet| xmp::extended_guid(packet));
+        if let Some(guid) = extended_guid {
+            image.extended_xmp = xmp::assemble_extended(&guid, &extended_xmp_chunks).ok();
+        }
+
+        // Broken image resources and IPTC data are ignored.
+        if let Ok(resources) = photoshop::parse_image_resources(&photoshop_data) {
+            image.iptc = resources.iter()
+                .find(|resource| resource.id == photoshop::IPTC_RESOURCE_ID)
+                .and_then(|resource| Iptc::parse(&resource.data).ok());
+            image.image_resources = resources;
+        }
+
+        // Individual images that are not in the file are ignored.
+        if let Some((header_offset, entries)) = mp_index {
+            for entry in entries {
+                let start = match entry.offset {
+                    0 => 0,
+                    offset => header_offset + offset as usize,
+                };
+                if let Some(data) = vec.get(start..start + entry.size as usize) {
+                    image.mp_images.push(MpImage {
+                        entry,
+                        data: data.to_vec(),
+                    });
+                }
+            }
+        }
+
+        if options.headers_only {
+            return Ok(image);
+        }
+
+        // Pack the pixels if every component needed is decoded.
+        let luma_only = image.is_luma_only(options);
+
+        // The planes of a progressive frame come from the coefficients of all
+        // of its scans. Without EOI, there may have been more of them.
+        let progressive_frame = image.frame_header
+            .clone()
+            .filter(|frame_header| frame_header.frame_type == FrameType::ProgressiveDct);
+        if let Some(frame_header) = progressive_frame {
+            image.incomplete |= !end_of_image;
+            if scanned.contains(&true) {
+                let mut jpeg_decoder = JPEGDecoder::new(&[])
+                    .frame_header(frame_header)
+                    .dimensions((image.dimensions.0 as usize, image.dimensions.1 as usize))
+                    .luma_only(luma_only);
+                for (i, table) in image.quantization_tables.iter().enumerate() {
+                    if let Some(table) = table {
+                        jpeg_decoder.quantization_table(i as u8, table.clone());
+                    }
+                }
+                let transformed = jpeg_decoder.transform(&coefficients)?;
+                for ((plane, samples), &scanned) in planes.iter_mut()
+                    .zip(transformed)
+                    .zip(&scanned) {
+                    if scanned {
+                        *plane = samples;
+                    }
+                }
             }
         }
+        let needed = if luma_only { planes.len().min(1) } else { planes.len() };
+
+        // A file that ends before the scans of all components is incomplete,
+        // and the components without a scan are mid-gray.
+        if !end_of_image && planes[..needed].iter().any(|plane| plane.is_empty()) {
+            image.incomplete = true;
+        }
+        if image.incomplete {
+            let samples = image.dimensions.0 as usize * image.dimensions.1 as usize;
+            for plane in planes[..needed].iter_mut().filter(|plane| plane.is_empty()) {
+                *plane = vec![128; samples];
+            }
+        }
+
+        let decoded = needed > 0 && planes[..needed].iter().all(|plane| !plane.is_empty());
+        if decoded {
+            let data = image.pack_pixels(planes, luma_only, options)?;
+            image.image_data = Some(data);
+        }
         Ok(image)
     }
 
+    /// Whether only the luma needs to be decoded. Grayscale output of a
+    /// YCbCr image is just the Y component.
+    fn is_luma_only(&self, options: &DecodeOptions) -> bool {
+        options.pixel_format == PixelFormat::Gray && self.color_space() == Some(ColorSpace::YCbCr)
+    }
+
+    /// Convert the decoded planes of the frame components to pixel data,
+    /// as described by `options`.
+    fn pack_pixels(&mut self,
+                   mut planes: Vec<Vec<u8>>,
+                   luma_only: bool,
+                   options: &DecodeOptions)
+                   -> Result<Vec<u8>, String> {
+        let mut color_space = self.color_space()
+            .ok_or(format!("Unsupported number of components: {}", planes.len()))?;
+
+        let target = options.color_management;
+        let profile = self.icc_profile
+            .as_ref()
+            .filter(|_| target != ColorManagement::None)
+            .and_then(|profile| IccProfile::parse(profile).ok());
+        if let Some(profile) = profile {
+            if color_space == ColorSpace::Gray {
+                if let Some(transform) = ColorTransform::gray(&profile, target) {
+                    transform.apply(&mut planes[0]);
+                }
+            } else if !luma_only &&
+                      (color_space == ColorSpace::YCbCr || color_space == ColorSpace::Rgb) {
+                if let Some(transform) = ColorTransform::rgb(&profile, target) {
+                    let mut rgb = color::pack_pixels(&planes, color_space, false, PixelFormat::Rgb);
+                    transform.apply(&mut rgb);
+                    planes = (0..3)
+                        .map(|c| rgb.iter().skip(c).step_by(3).cloned().collect())
+                        .collect();
+                    color_space = ColorSpace::Rgb;
+                }
+            }
+        }
+
+        let mut data = color::pack_pixels(&planes,
+                                          color_space,
+                                          self.adobe.is_some(),
+                                          options.pixel_format);
+
+        let exif_orientation = self.orientation().filter(|_| options.apply_orientation);
+        if let Some(exif_orientation) = exif_orientation {
+            data = orientation::apply_orientation(&data,
+                                                  self.dimensions.0 as usize,
+                                                  self.dimensions.1 as usize,
+                                                  options.pixel_format.bytes_per_pixel(),
+                                                  exif_orientation);
+            self.applied_orientation = Some(exif_orientation);
+        }
+        Ok(data)
+    }
+
+    /// Width of `image_data`. This is the displayed width if the
+    /// Exif orientation is applied, and the stored width otherwise.
     pub fn width(&self) -> usize {
-        self.dimensions.0 as usize
+        if self.is_transposed() {
+            self.dimensions.1 as usize
+        } else {
+            self.dimensions.0 as usize
+        }
     }
 
+    /// Height of `image_data`. This is the displayed height if the
+    /// Exif orientation is applied, and the stored height otherwise.
     pub fn height(&self) -> usize {
-        self.dimensions.1 as usize
+        if self.is_transposed() {
+            self.dimensions.0 as usize
+        } else {
+            self.dimensions.1 as usize
+        }
+    }
+
+    fn is_transposed(&self) -> bool {
+        self.applied_orientation.map(orientation::is_transposed).unwrap_or(false)
+    }
+
+    /// The orientation stored in the Exif data, from 1 to 8,
+    /// whether or not it is applied to `image_data`.
+    pub fn orientation(&self) -> Option<u16> {
+        self.exif.as_ref().and_then(|exif| exif.orientation())
+    }
+
+    /// The orientation applied to `image_data`, if
+    /// `DecodeOptions::apply_orientation` was set.
+    pub fn applied_orientation(&self) -> Option<u16> {
+        self.applied_orientation
+    }
+
+    /// The frame header, from the SOFn segment.
+    pub fn frame_header(&self) -> Option<&FrameHeader> {
+        self.frame_header.as_ref()
+    }
+
+    /// The scan headers, in the order of the scans.
+    pub fn scan_headers(&self) -> &[ScanHeader] {
+        self.scan_headers.as_deref().unwrap_or(&[])
+    }
+
+    /// Quantization table `id`, from 0 to 3, in zigzag order as in the DQT
+    /// segment. Tables may be redefined between scans, and this is the last one.
+    pub fn quantization_table(&self, id: u8) -> Option<&[u16]> {
+        self.quantization_tables.get(id as usize)?.as_deref()
+    }
+
+    /// Huffman table `id` for DC coefficients, from 0 to 3. Tables may be
+    /// redefined between scans, and this is the last one.
+    pub fn huffman_dc_table(&self, id: u8) -> Option<&huffman::HuffmanTable> {
+        self.huffman_dc_tables.get(id as usize)?.as_ref()
+    }
+
+    /// Huffman table `id` for AC coefficients, from 0 to 3. Tables may be
+    /// redefined between scans, and this is the last one.
+    pub fn huffman_ac_table(&self, id: u8) -> Option<&huffman::HuffmanTable> {
+        self.huffman_ac_tables.get(id as usize)?.as_ref()
+    }
+
+    /// Number of MCUs in each restart interval, or 0 without restart markers.
+    pub fn restart_interval(&self) -> u16 {
+        self.restart_interval
+    }
+
+    /// The text of the COM segments, in order.
+    pub fn comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    /// The text of the first COM segment.
+    pub fn comment(&self) -> Option<&str> {
+        self.comments.first().map(|comment| comment.as_str())
+    }
+
+    /// Decode the thumbnail of a JFXX APP0 segment, starting at the extension code.
+    /// See JFIF 1.02, "JFIF Extension APP0 Marker Segment".
+    /// Broken thumbnails are ignored, as they do not prevent decoding the image.
+    fn parse_jfxx_thumbnail(extension: &[u8],
+                            options: &DecodeOptions,
+                            depth: usize)
+                            -> Option<Thumbnail> {
+        let data = &extension[1..];
+        match extension[0] {
+            // JPEG, as a full stream from SOI to EOI.
+            0x10 => {
+                if depth >= MAX_NESTING_DEPTH {
+                    return None;
+                }
+                let options = DecodeOptions::new().limits(options.limits.clone());
+                let thumbnail = JPEGImage::parse_nested(data.to_vec(), &options, depth + 1).ok()?;
+                Some(Thumbnail {
+                    width: thumbnail.width(),
+                    height: thumbnail.height(),
+                    data: thumbnail.image_data?,
+                })
+            }
+            // 1 byte per pixel, indexing a palette of 256 RGB values.
+            //
+            //  Xthumbnail, Ythumbnail, palette, (index)n
+            0x11 => {
+                let (width, height) = (*data.first()? as usize, *data.get(1)? as usize);
+                let palette = data.get(2..2 + 768)?;
+                let indices = data.get(2 + 768..2 + 768 + width * height)?;
+                let rgb = indices.iter()
+                    .flat_map(|&index| &palette[3 * index as usize..3 * index as usize + 3])
+                    .cloned()
+                    .collect();
+                Some(Thumbnail {
+                    width,
+                    height,
+                    data: rgb,
+                })
+            }
+            // 3 bytes per pixel, like the JFIF thumbnail.
+            //
+            //  Xthumbnail, Ythumbnail, (RGB)n
+            0x13 => {
+                let (width, height) = (*data.first()? as usize, *data.get(1)? as usize);
+                let rgb = data.get(2..2 + 3 * width * height)?;
+                Some(Thumbnail {
+                    width,
+                    height,
+                    data: rgb.to_vec(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The JFIF version, if the image has a JFIF APP0 segment.
+    pub fn jfif_version(&self) -> Option<JFIFVersion> {
+        self.version
+    }
+
+    /// The units of `pixel_density`.
+    pub fn density_units(&self) -> JFIFUnits {
+        self.units
+    }
+
+    /// The horizontal and vertical pixel density.
+    pub fn pixel_density(&self) -> (u16, u16) {
+        self.pixel_density
+    }
+
+    /// The horizontal and vertical resolution in dots per inch,
+    /// if the density has units.
+    pub fn dpi(&self) -> Option<(f32, f32)> {
+        let (x, y) = (self.pixel_density.0 as f32, self.pixel_density.1 as f32);
+        match self.units {
+            JFIFUnits::DotsPerInch => Some((x, y)),
+            JFIFUnits::DotsPerCm => Some((x * 2.54, y * 2.54)),
+            _ => None,
+        }
+    }
+
+    /// The pixel aspect ratio, width over height. This is 1 for square pixels,
+    /// and also when the density is missing.
+    pub fn aspect_ratio(&self) -> f32 {
+        match self.pixel_density {
+            (0, _) | (_, 0) => 1.0,
+            (x, y) => x as f32 / y as f32,
+        }
+    }
+
+    /// The embedded thumbnail, if present.
+    pub fn thumbnail(&self) -> Option<&Thumbnail> {
+        self.thumbnail.as_ref()
+    }
+
+    pub fn exif(&self) -> Option<&Exif> {
+        self.exif.as_ref()
+    }
+
+    /// The individual images of a Multi-Picture Format file,
+    /// in the order of the MP index.
+    pub fn mp_images(&self) -> &[MpImage] {
+        &self.mp_images
+    }
+
+    /// Find and decode the gain map of an Ultra HDR image, which is the
+    /// individual image with `hdrgm` metadata in its XMP. Only images with
+    /// such XMP are decoded, within the limits of `options`, and if one
+    /// fails, the next one is tried.
+    pub fn decode_gain_map(&self, options: &DecodeOptions) -> Result<GainMap, String> {
+        // The gain map is RGB, and stored like the base image.
+        let options = DecodeOptions::new().limits(options.limits.clone());
+        let mut error = "No gain map".to_string();
+        for mp_image in self.mp_images.iter().skip(1) {
+            let params = match mp_image.xmp().as_deref().and_then(GainMapParams::parse) {
+                Some(params) => params,
+                None => continue,
+            };
+            let gain_map = match mp_image.decode(&options) {
+                Ok(gain_map) => gain_map,
+                Err(decode_error) => {
+                    error = format!("Gain map: {}", decode_error);
+                    continue;
+                }
+            };
+            match gain_map.image_data {
+                Some(data) => {
+                    return Ok(GainMap {
+                        params,
+                        width: gain_map.dimensions.0 as usize,
+                        height: gain_map.dimensions.1 as usize,
+                        data,
+                    })
+                }
+                None => error = "Gain map has no image data".to_string(),
+            }
+        }
+        Err(error)
+    }
+
+    /// Decode the HDR rendition of an Ultra HDR image, for a display that can
+    /// show `display_boost` times the SDR white. The image must be decoded as RGB.
+    /// The gain map is decoded within the limits of `options`.
+    ///
+    /// Returns linear light RGB, 3 floats per pixel, where 1 is the SDR white.
+    pub fn decode_hdr(&self,
+                      display_boost: f32,
+                      options: &DecodeOptions)
+                      -> Result<Vec<f32>, String> {
+        if self.pixel_format != PixelFormat::Rgb {
+            return Err(format!("HDR needs RGB pixels, not {:?}", self.pixel_format));
+        }
+        let base = self.image_data().ok_or("No image data")?;
+        let mut gain_map = self.decode_gain_map(options)?;
+        // The gain map is stored like the base image, so it is oriented the same way.
+        if let Some(applied_orientation) = self.applied_orientation {
+            gain_map.data = orientation::apply_orientation(&gain_map.data,
+                                                           gain_map.width,
+                                                           gain_map.height,
+                                                           3,
+                                                           applied_orientation);
+            if orientation::is_transposed(applied_orientation) {
+                std::mem::swap(&mut gain_map.width, &mut gain_map.height);
+            }
+        }
+        Ok(gain_map.apply(base, self.width(), self.height(), display_boost))
+    }
+
+    /// The Photoshop image resources.
+    pub fn image_resources(&self) -> &[ImageResource] {
+        &self.image_resources
+    }
+
+    /// The IPTC-IIM datasets, like caption, headline and keywords.
+    pub fn iptc(&self) -> Option<&Iptc> {
+        self.iptc.as_ref()
+    }
+
+    /// The JPEG quality, from 0 to 100, written by Photoshop "Save for Web".
+    pub fn ducky_quality(&self) -> Option<u32> {
+        self.ducky_quality
+    }
+
+    /// Application segments that are not interpreted, in the order of the file.
+    pub fn application_segments(&self) -> &[ApplicationSegment] {
+        &self.application_segments
+    }
+
+    /// The JPEG thumbnail in the Exif data, as stored.
+    pub fn exif_thumbnail(&self) -> Option<&[u8]> {
+        self.exif.as_ref().and_then(|exif| exif.thumbnail())
+    }
+
+    /// Decode the JPEG thumbnail in the Exif data to RGB, within the limits of `options`.
+    pub fn decode_exif_thumbnail(&self, options: &DecodeOptions) -> Result<Thumbnail, String> {
+        let data = self.exif_thumbnail().ok_or("No Exif thumbnail")?;
+        let options = DecodeOptions::new().limits(options.limits.clone());
+        let thumbnail = JPEGImage::parse_nested(data.to_vec(), &options, 1)?;
+        Ok(Thumbnail {
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            data: thumbnail.image_data.ok_or("Exif thumbnail has no image data")?,
+        })
+    }
+
+    /// The main XMP packet.
+    pub fn xmp(&self) -> Option<&str> {
+        self.xmp.as_deref()
+    }
+
+    /// The extended XMP, if the main packet refers to it, and all of
+    /// its chunks are present with the right MD5 digest.
+    pub fn extended_xmp(&self) -> Option<&str> {
+        self.extended_xmp.as_deref()
+    }
+
+    /// Corrupt entropy coded data that was skipped and filled in, in the order
+    /// it was found. Always empty unless `DecodeOptions::lenient` is set.
+    pub fn incidents(&self) -> &[Incident] {
+        &self.incidents
+    }
+
+    /// Whether the file ended before all of the image data, like an upload
+    /// that was cut off. The rows that were decoded completely are in
+    /// `image_data`, and the rest is mid-gray, or filled in as
+    /// `DecodeOptions::concealment` says in a lenient decode.
+    ///
+    /// A progressive frame is shown as its last complete scan left it, a
+    /// lower quality version of the image. A scan that was cut off is dropped.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
+    /// The embedded ICC profile, if all of its chunks are present.
+    pub fn icc_profile(&self) -> Option<&[u8]> {
+        self.icc_profile.as_deref()
+    }
+
+    pub fn adobe(&self) -> Option<&AdobeSegment> {
+        self.adobe.as_ref()
+    }
+
+    /// The color space of the components of the frame.
+    ///
+    /// Three component images are YCbCr, unless the Adobe segment says
+    /// otherwise, or the components are named 'R', 'G' and 'B'.
+    /// Four component images are CMYK, or YCCK if the Adobe segment says so.
+    pub fn color_space(&self) -> Option<ColorSpace> {
+        let frame_header = match self.frame_header {
+            Some(ref frame_header) => frame_header,
+            None => return None,
+        };
+        let transform = self.adobe.as_ref().map(|adobe| adobe.transform);
+        match frame_header.image_components {
+            1 => Some(ColorSpace::Gray),
+            3 => {
+                let ids: Vec<u8> = frame_header.frame_components
+                    .iter()
+                    .map(|c| c.component_id)
+                    .collect();
+                match transform {
+                    Some(AdobeColorTransform::RgbOrCmyk) => Some(ColorSpace::Rgb),
+                    Some(_) => Some(ColorSpace::YCbCr),
+                    None if ids == b"RGB" => Some(ColorSpace::Rgb),
+                    None => Some(ColorSpace::YCbCr),
+                }
+            }
+            4 => {
+                match transform {
+                    Some(AdobeColorTransform::Ycck) => Some(ColorSpace::Ycck),
+                    _ => Some(ColorSpace::Cmyk),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// The decoded pixels, packed as described by `pixel_format()`.
+    pub fn image_data(&self) -> Option<&[u8]> {
+        self.image_data.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter::repeat;
+
+    const SIMPLE: &[u8] = include_bytes!("../../working-jpegs/huff_simple0.jpg");
+    const LENA: &[u8] = include_bytes!("../../lena.jpeg");
+
+    /// A 24x8 gray image of three MCUs, with a restart marker after each.
+    /// Its Huffman tables have a single code, `00`, so `0x0f` is one MCU
+    /// without AC coefficients, padded with 1-bits.
+    fn restart_file(intervals: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xdb, 0, 67, 0];
+        data.extend_from_slice(&[1; 64]);
+        data.extend_from_slice(&[0xff, 0xc0, 0, 11, 8, 0, 8, 0, 24, 1, 1, 0x11, 0]);
+        data.extend_from_slice(&[0xff, 0xc4, 0, 38]);
+        for &class in &[0x00, 0x10] {
+            data.extend_from_slice(&[class, 0, 1]);
+            data.extend_from_slice(&[0; 15]);
+        }
+        data.extend_from_slice(&[0xff, 0xdd, 0, 4, 0, 1]);
+        data.extend_from_slice(&[0xff, 0xda, 0, 8, 1, 1, 0, 0, 63, 0]);
+        for (n, interval) in intervals.iter().enumerate() {
+            if n > 0 {
+                data.extend_from_slice(&[0xff, 0xd0 + (n as u8 - 1) % 8]);
+            }
+            data.extend_from_slice(interval);
+        }
+        data.extend_from_slice(&[0xff, 0xd9]);
+        data
+    }
+
+    /// An 8x8 gray image of a single block, with all quantization steps 1.
+    /// The Huffman tables are given as class and id, and values, with codes
+    /// of 2 bits. The scans are given as table selectors, Ss, Se, Ah and Al,
+    /// and entropy coded data.
+    fn single_block_file(sof: u8,
+                         tables: &[(u8, &[u8])],
+                         scans: &[(u8, u8, u8, u8, &[u8])])
+                         -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xdb, 0, 67, 0];
+        data.extend_from_slice(&[1; 64]);
+        data.extend_from_slice(&[0xff, sof, 0, 11, 8, 0, 8, 0, 8, 1, 1, 0x11, 0]);
+        for &(table, values) in tables {
+            data.extend_from_slice(&[0xff, 0xc4, 0, 19 + values.len() as u8, table, 0]);
+            data.push(values.len() as u8);
+            data.extend_from_slice(&[0; 14]);
+            data.extend_from_slice(values);
+        }
+        for &(selectors, start, end, approximation, scan) in scans {
+            data.extend_from_slice(&[0xff, 0xda, 0, 8, 1, 1, selectors, start, end, approximation]);
+            data.extend_from_slice(scan);
+        }
+        data.extend_from_slice(&[0xff, 0xd9]);
+        data
+    }
+
+    /// A block with the coefficients 65, 13, -7, 0 and -1 in zigzag order,
+    /// and 0 after that, in a progressive file. The DC and AC coefficients
+    /// are sent without their last bit first, and then refined.
+    const PROGRESSIVE_TABLES: [(u8, &[u8]); 3] = [(0x00, &[6]),
+                                                  (0x10, &[0x03, 0x02, 0x00]),
+                                                  (0x11, &[0x11, 0x00])];
+    const PROGRESSIVE_SCANS: [(u8, u8, u8, u8, &[u8]); 4] = [(0x00, 0, 0, 0x01, &[0x20]),
+                                                             (0x00, 1, 63, 0x01, &[0x32, 0x5f]),
+                                                             (0x00, 0, 0, 0x10, &[0xff, 0x00]),
+                                                             (0x01, 1, 63, 0x10, &[0x1b])];
+
+    /// The block of `PROGRESSIVE_SCANS` in a baseline file.
+    fn baseline_block_file() -> Vec<u8> {
+        single_block_file(0xc0,
+                          &[(0x00, &[7]), (0x10, &[0x04, 0x03, 0x11, 0x00])],
+                          &[(0x00, 0, 63, 0x00, &[0x20, 0x9a, 0x89, 0xff, 0x00])])
+    }
+
+    /// `jpeg` with a segment inserted after SOI.
+    fn with_segment(jpeg: &[u8], marker: u8, payload: &[u8]) -> Vec<u8> {
+        let length = payload.len() + 2;
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xff, marker, (length >> 8) as u8, length as u8]);
+        data.extend_from_slice(payload);
+        data.extend_from_slice(&jpeg[2..]);
+        data
+    }
+
+    /// An MPF file of `SIMPLE` followed by `images`.
+    fn mpf_file(images: &[Vec<u8>]) -> Vec<u8> {
+        // The MP header follows SOI, the APP2 marker and length, and "MPF\0".
+        let header_offset = 10;
+        let index_length = 8 + 2 + 2 * 12 + 4 + 16 * (images.len() + 1);
+        let base_length = SIMPLE.len() + 4 + mpf::MPF_IDENTIFIER.len() + index_length;
+
+        let mut index = mpf::MPF_IDENTIFIER.to_vec();
+        index.extend_from_slice(b"MM\0\x2a\0\0\0\x08\0\x02");
+        index.extend_from_slice(&[0xb0, 0x01, 0, 4, 0, 0, 0, 1]);
+        index.extend_from_slice(&(images.len() as u32 + 1).to_be_bytes());
+        index.extend_from_slice(&[0xb0, 0x02, 0, 7, 0, 0, 0, 16 * (images.len() as u8 + 1)]);
+        index.extend_from_slice(&38u32.to_be_bytes());
+        index.extend_from_slice(&[0; 4]);
+        index.extend_from_slice(&[0x20, 0x03, 0, 0]);
+        index.extend_from_slice(&(base_length as u32).to_be_bytes());
+        index.extend_from_slice(&[0; 8]);
+        let mut offset = base_length;
+        for image in images {
+            index.extend_from_slice(&[0; 4]);
+            index.extend_from_slice(&(image.len() as u32).to_be_bytes());
+            index.extend_from_slice(&((offset - header_offset) as u32).to_be_bytes());
+            index.extend_from_slice(&[0; 4]);
+            offset += image.len();
+        }
+
+        let mut data = with_segment(SIMPLE, 0xe2, &index);
+        assert_eq!(data.len(), base_length);
+        for image in images {
+            data.extend_from_slice(image);
+        }
+        data
+    }
+
+    fn gain_map_image() -> Vec<u8> {
+        let mut xmp = xmp::XMP_IDENTIFIER.to_vec();
+        xmp.extend_from_slice(b"<x:xmpmeta><rdf:Description hdrgm:Version=\"1.0\" \
+                                hdrgm:GainMapMax=\"2\" hdrgm:HDRCapacityMax=\"2\"/></x:xmpmeta>");
+        with_segment(SIMPLE, 0xe1, &xmp)
+    }
+
+    #[test]
+    fn exif_thumbnail() {
+        // IFD0 without entries, and IFD1 pointing to the thumbnail after it.
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\0\0\0\0\x0e\0\x02".to_vec();
+        exif.extend_from_slice(&[0x02, 0x01, 0, 4, 0, 0, 0, 1, 0, 0, 0, 44]);
+        exif.extend_from_slice(&[0x02, 0x02, 0, 4, 0, 0, 0, 1]);
+        exif.extend_from_slice(&(SIMPLE.len() as u32).to_be_bytes());
+        exif.extend_from_slice(&[0; 4]);
+        exif.extend_from_slice(SIMPLE);
+        let image = JPEGImage::parse(with_segment(SIMPLE, 0xe1, &exif)).unwrap();
+        assert_eq!(image.exif_thumbnail(), Some(SIMPLE));
+
+        let thumbnail = image.decode_exif_thumbnail(&DecodeOptions::new()).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (16, 8));
+        assert_eq!(thumbnail.data.len(), 16 * 8 * 3);
+        let options = DecodeOptions::new().limits(Limits::new().max_width(8));
+        assert!(image.decode_exif_thumbnail(&options).is_err());
+    }
+
+    #[test]
+    fn gain_map() {
+        let mut broken = gain_map_image();
+        let sof = broken.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        broken[sof + 1] = 0xc3;
+        let image = JPEGImage::parse(mpf_file(&[broken, gain_map_image()])).unwrap();
+        assert_eq!(image.mp_images().len(), 3);
+        assert!(image.mp_images()[0].xmp().is_none());
+        assert!(image.mp_images()[2].xmp().unwrap().contains("hdrgm:Version"));
+
+        // The broken image has gain map XMP too, so the next one is tried.
+        let gain_map = image.decode_gain_map(&DecodeOptions::new()).unwrap();
+        assert_eq!((gain_map.width, gain_map.height), (16, 8));
+        assert_eq!(gain_map.params.gain_map_max, [2.0; 3]);
+        assert_eq!(gain_map.data.len(), 16 * 8 * 3);
+    }
+
+    #[test]
+    fn lenient_resyncs_at_restart_marker() {
+        let image = JPEGImage::parse(restart_file(&[&[0x0f], &[0x0f], &[0x0f]])).unwrap();
+        assert!(image.incidents().is_empty());
+
+        // A marker in the second interval, and garbage up to the next RSTn
+        let data = restart_file(&[&[0x0f], &[0xff, 0xc4, 0x12, 0x34], &[0x0f]]);
+        assert!(JPEGImage::parse(data.clone()).is_err());
+        let options = DecodeOptions::new().lenient(true);
+        let image = JPEGImage::parse_with_options(data, &options).unwrap();
+        assert_eq!(image.incidents().len(), 1);
+        assert_eq!((image.incidents()[0].mcu, image.incidents()[0].lost_mcus), (1, 1));
+        assert_eq!(image.image_data().unwrap().len(), 24 * 8 * 3);
+        assert!(!image.is_incomplete());
+    }
+
+    #[test]
+    fn lenient_skips_markers_to_eoi() {
+        let options = DecodeOptions::new().lenient(true);
+        for &marker in &[0xc0, 0xc2, 0xda, 0xdb] {
+            let mut data = LENA.to_vec();
+            data[40000..40002].copy_from_slice(&[0xff, marker]);
+            assert!(JPEGImage::parse(data.clone()).is_err());
+
+            let image = JPEGImage::parse_with_options(data, &options).unwrap();
+            assert_eq!(image.incidents().len(), 1, "marker {:#04x}", marker);
+            assert_eq!(image.incidents()[0].offset, 40000);
+            assert_eq!(image.image_data().unwrap().len(), 512 * 512 * 3);
+            assert!(!image.is_incomplete());
+        }
+    }
+
+    #[test]
+    fn truncated_baseline_is_incomplete() {
+        let image = JPEGImage::parse(LENA[..40000].to_vec()).unwrap();
+        assert!(image.is_incomplete());
+        assert!(image.incidents().is_empty());
+        assert_eq!(image.image_data().unwrap().len(), 512 * 512 * 3);
+
+        // The rows that were decoded are kept, the rest is mid-gray.
+        let complete = JPEGImage::parse(LENA.to_vec()).unwrap();
+        let row = 512 * 3;
+        let data = image.image_data().unwrap();
+        assert_eq!(data[..16 * row], complete.image_data().unwrap()[..16 * row]);
+        assert!(data[500 * row..].iter().all(|&sample| sample == 128));
+
+        assert!(!complete.is_incomplete());
+    }
+
+    #[test]
+    fn progressive() {
+        let options = DecodeOptions::new().pixel_format(PixelFormat::Gray);
+        let decode = |data: Vec<u8>| JPEGImage::parse_with_options(data, &options).unwrap();
+        let image = decode(single_block_file(0xc2, &PROGRESSIVE_TABLES, &PROGRESSIVE_SCANS));
+        let baseline = decode(baseline_block_file());
+        assert_eq!(image.image_data(), baseline.image_data());
+        assert!(!image.is_incomplete());
+        let data = image.image_data().unwrap();
+        assert!(data.iter().any(|&sample| sample != data[0]));
+
+        // Only the DC coefficient, without its last bit
+        let dc = decode(single_block_file(0xc2, &PROGRESSIVE_TABLES, &PROGRESSIVE_SCANS[..1]));
+        assert_eq!(dc.image_data().unwrap(), &[136; 64][..]);
+
+        // A sequential scan does not fit a progressive frame.
+        let mut data = baseline_block_file();
+        let sof = data.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        data[sof + 1] = 0xc2;
+        assert!(JPEGImage::parse(data).is_err());
+    }
+
+    #[test]
+    fn truncated_progressive_shows_last_complete_scan() {
+        let options = DecodeOptions::new().pixel_format(PixelFormat::Gray);
+        let decode = |data: &[u8]| JPEGImage::parse_with_options(data.to_vec(), &options).unwrap();
+        let full = single_block_file(0xc2, &PROGRESSIVE_TABLES, &PROGRESSIVE_SCANS);
+        let three = decode(&single_block_file(0xc2, &PROGRESSIVE_TABLES, &PROGRESSIVE_SCANS[..3]));
+        let one = decode(&single_block_file(0xc2, &PROGRESSIVE_TABLES, &PROGRESSIVE_SCANS[..1]));
+        assert!(three.image_data() != decode(&full).image_data());
+
+        // Cut off in the header and in the data of the last scan
+        for &cut in &[6, 3] {
+            let image = decode(&full[..full.len() - cut]);
+            assert!(image.is_incomplete());
+            assert_eq!(image.image_data(), three.image_data());
+        }
+
+        // Cut off in the data of the second scan
+        let second = full.windows(2).position(|w| w == [0x32, 0x5f]).unwrap();
+        let image = decode(&full[..second + 1]);
+        assert!(image.is_incomplete());
+        assert_eq!(image.image_data(), one.image_data());
+
+        // Cut off after the data of the last scan, which is complete
+        let image = decode(&full[..full.len() - 2]);
+        assert!(image.is_incomplete());
+        assert_eq!(image.image_data(), decode(&full).image_data());
+    }
+
+    #[test]
+    fn malformed_tables_are_errors() {
+        let table = |header: u8, length: usize| {
+            let mut payload = vec![header];
+            payload.extend(repeat(1).take(length));
+            payload
+        };
+        let dqt = [table(0x04, 64), table(0x20, 64), table(0x00, 63), table(0x10, 64)];
+        for payload in dqt.iter() {
+            assert!(JPEGImage::parse(with_segment(SIMPLE, 0xdb, payload)).is_err());
+        }
+
+        let mut no_values = vec![0x00, 0, 2];
+        no_values.extend_from_slice(&[0; 14]);
+        no_values.push(0);
+        let dht = [table(0x04, 17), table(0x20, 17), table(0x00, 15), vec![0x00; 17], no_values];
+        for payload in dht.iter() {
+            assert!(JPEGImage::parse(with_segment(SIMPLE, 0xc4, payload)).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_errors() {
+        let sof = SIMPLE.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        let mut data = SIMPLE.to_vec();
+        // Quantization table selector of the first component
+        data[sof + 12] = 4;
+        assert!(JPEGImage::parse(data).is_err());
+
+        let sos = SIMPLE.windows(2).position(|w| w == [0xff, 0xda]).unwrap();
+        let mut data = SIMPLE.to_vec();
+        // More components than the header has bytes for
+        data[sos + 4] = 5;
+        assert!(JPEGImage::parse(data).is_err());
+    }
+
+    #[test]
+    fn headers_only() {
+        let mut data = SIMPLE.to_vec();
+        let sof = data.windows(2).position(|w| w == [0xff, 0xc0]).unwrap();
+        data[sof + 1] = 0xc3;
+        assert!(JPEGImage::parse(data.clone()).is_err());
+
+        let options = DecodeOptions::new().headers_only(true).limits(Limits::new().max_width(8));
+        let image = JPEGImage::parse_with_options(data, &options).unwrap();
+        assert_eq!(image.frame_header().unwrap().frame_type(), FrameType::Lossless);
+        assert_eq!((image.width(), image.height()), (16, 8));
+        assert_eq!(image.scan_headers().len(), 1);
+        assert!(image.quantization_table(0).is_some());
+        assert!(image.image_data().is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let parse = |limits: Limits| {
+            JPEGImage::parse_with_options(SIMPLE.to_vec(), &DecodeOptions::new().limits(limits))
+        };
+        assert!(parse(Limits::new().max_width(16).max_height(8).max_pixels(128)).is_ok());
+        assert!(parse(Limits::new().max_width(15)).is_err());
+        assert!(parse(Limits::new().max_height(7)).is_err());
+        assert!(parse(Limits::new().max_pixels(127)).is_err());
+        assert!(parse(Limits::new().max_allocation(100)).is_err());
+        assert!(parse(Limits::new().max_scans(0)).is_err());
+        assert!(parse(Limits::new().max_segments(3)).is_err());
+        assert!(parse(Limits::new().max_metadata(10)).is_err());
     }
 
-    pub fn image_data(&self) -> Option<&Vec<(u8, u8, u8)>> {
-        self.image_data.as_ref()
+    #[test]
+    fn gain_map_limits() {
+        let image = JPEGImage::parse(mpf_file(&[gain_map_image()])).unwrap();
+        let options = DecodeOptions::new().limits(Limits::new().max_pixels(100));
+        assert!(image.decode_gain_map(&options).is_err());
+        assert!(image.decode_hdr(1.0, &options).is_err());
+        assert_eq!(image.decode_hdr(4.0, &DecodeOptions::new()).unwrap().len(), 16 * 8 * 3);
     }
 }
@@ -1,3 +1,17 @@
+#[allow(clippy::module_inception)]
 pub mod jpeg;
 pub mod huffman;
 pub mod decoder;
+pub mod color;
+pub mod exif;
+pub mod orientation;
+pub mod icc;
+pub mod xmp;
+pub mod mpf;
+pub mod gainmap;
+pub mod photoshop;
+pub mod iptc;
+pub mod segments;
+pub mod writer;
+pub mod probe;
+pub mod pnm;
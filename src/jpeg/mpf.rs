@@ -0,0 +1,154 @@
+use jpeg::exif::{Exif, Ifd, Value};
+
+/// Identifier of the APP2 segment holding the MP index.
+pub const MPF_IDENTIFIER: &[u8] = b"MPF\0";
+
+/// Tags of the MP index IFD. See CIPA DC-007, section 5.2.3.
+pub mod tags {
+    pub const MP_FORMAT_VERSION: u16 = 0xb000;
+    pub const NUMBER_OF_IMAGES: u16 = 0xb001;
+    pub const MP_ENTRY: u16 = 0xb002;
+}
+
+/// What an individual image is. See CIPA DC-007, table 4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MpImageType {
+    /// The primary image of a Baseline MP file
+    BaselinePrimary,
+    /// Large thumbnail, about the size of VGA
+    LargeThumbnailVga,
+    /// Large thumbnail, about the size of Full HD
+    LargeThumbnailFullHd,
+    /// One frame of a panorama
+    Panorama,
+    /// One view of a stereo image
+    Disparity,
+    /// One view of an object seen from several angles
+    MultiAngle,
+    Undefined,
+    Unknown(u32),
+}
+
+impl MpImageType {
+    pub fn from_u32(code: u32) -> MpImageType {
+        match code {
+            0x030000 => MpImageType::BaselinePrimary,
+            0x010001 => MpImageType::LargeThumbnailVga,
+            0x010002 => MpImageType::LargeThumbnailFullHd,
+            0x020001 => MpImageType::Panorama,
+            0x020002 => MpImageType::Disparity,
+            0x020003 => MpImageType::MultiAngle,
+            0x000000 => MpImageType::Undefined,
+            _ => MpImageType::Unknown(code),
+        }
+    }
+}
+
+/// An entry of the MP index, describing one individual image.
+/// See CIPA DC-007, section 5.2.3.3.
+#[derive(Debug, Clone)]
+pub struct MpEntry {
+    /// Individual image attribute, with flags, format and type
+    pub attribute: u32,
+    pub image_type: MpImageType,
+    /// Size of the image in bytes
+    pub size: u32,
+    /// Offset of the image from the MP header, or 0 for the first image
+    pub offset: u32,
+    /// Entry numbers of dependent images, or 0 if there are none
+    pub dependent_images: (u16, u16),
+}
+
+impl MpEntry {
+    /// Whether the image is the one to show, if only one is shown.
+    pub fn is_representative(&self) -> bool {
+        self.attribute & 0x2000_0000 != 0
+    }
+
+    /// Whether the image is JPEG. No other format is defined.
+    pub fn is_jpeg(&self) -> bool {
+        (self.attribute >> 24) & 0x7 == 0
+    }
+}
+
+/// Parse the MP index IFD in the MP header of an APP2 segment, which starts
+/// at the byte order mark, like the Exif data does.
+pub fn parse_index(data: &[u8]) -> Result<Vec<MpEntry>, String> {
+    let tiff = Exif::parse(data)?;
+    let byte_order = tiff.byte_order();
+
+    let num_images = tiff.get(Ifd::Primary, tags::NUMBER_OF_IMAGES)
+        .and_then(|entry| entry.value.get_u32(0))
+        .ok_or("MP index has no number of images")? as usize;
+    let entries = match tiff.get(Ifd::Primary, tags::MP_ENTRY).map(|entry| &entry.value) {
+        Some(Value::Undefined(bytes)) => bytes,
+        _ => return Err("MP index has no MP entries".to_string()),
+    };
+    if entries.len() != 16 * num_images {
+        return Err(format!("MP index has {} bytes of entries for {} images",
+                           entries.len(),
+                           num_images));
+    }
+
+    Ok(entries.chunks(16)
+        .map(|entry| {
+            let attribute = byte_order.u32(entry);
+            MpEntry {
+                attribute,
+                image_type: MpImageType::from_u32(attribute & 0x00ff_ffff),
+                size: byte_order.u32(&entry[4..]),
+                offset: byte_order.u32(&entry[8..]),
+                dependent_images: (byte_order.u16(&entry[12..]), byte_order.u16(&entry[14..])),
+            }
+        })
+        .collect())
+}
+
+/// Change the size and offset of each MP entry in the MP header, in place,
+/// to what `relocate` returns for the index, size and offset of the entry.
+pub fn relocate_entries<F>(data: &mut [u8], mut relocate: F) -> Result<(), String>
+    where F: FnMut(usize, u32, u32) -> (u32, u32)
+{
+    let num_images = parse_index(data)?.len();
+    // The index IFD and the MP entry values are known to be in bounds now.
+    let byte_order = Exif::parse(data)?.byte_order();
+    let ifd = byte_order.u32(&data[4..]) as usize;
+    let num_fields = byte_order.u16(&data[ifd..]) as usize;
+    let field = (0..num_fields)
+        .map(|i| ifd + 2 + 12 * i)
+        .find(|&field| byte_order.u16(&data[field..]) == tags::MP_ENTRY)
+        .ok_or("MP index has no MP entries")?;
+    let entries = byte_order.u32(&data[field + 8..]) as usize;
+
+    for i in 0..num_images {
+        let entry = entries + 16 * i;
+        let size = byte_order.u32(&data[entry + 4..]);
+        let offset = byte_order.u32(&data[entry + 8..]);
+        let (size, offset) = relocate(i, size, offset);
+        data[entry + 4..entry + 8].copy_from_slice(&byte_order.u32_bytes(size));
+        data[entry + 8..entry + 12].copy_from_slice(&byte_order.u32_bytes(offset));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_attribute_ifd_is_ignored() {
+        let mut data = b"MM\0\x2a\0\0\0\x08".to_vec();
+        data.extend_from_slice(&[0, 2]);
+        data.extend_from_slice(&[0xb0, 0x01, 0, 4, 0, 0, 0, 1, 0, 0, 0, 1]);
+        data.extend_from_slice(&[0xb0, 0x02, 0, 7, 0, 0, 0, 16, 0, 0, 0, 38]);
+        // The MP attribute IFD is out of bounds.
+        data.extend_from_slice(&[0, 0, 0x27, 0x0f]);
+        data.extend_from_slice(&[0x20, 0x03, 0, 0, 0, 0, 0x03, 0xe8, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let entries = parse_index(&data).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].image_type, MpImageType::BaselinePrimary);
+        assert!(entries[0].is_representative());
+        assert_eq!(entries[0].size, 1000);
+    }
+}
@@ -0,0 +1,88 @@
+/// Whether the Exif orientation swaps width and height.
+pub fn is_transposed(orientation: u16) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// Rotate and/or mirror packed pixel data from the stored orientation
+/// to the displayed one. See the Exif specification, 4.6.4, for the
+/// meaning of `orientation`. Unknown orientations are treated as 1.
+///
+/// `width` and `height` are the stored dimensions.
+pub fn apply_orientation(data: &[u8],
+                         width: usize,
+                         height: usize,
+                         bytes_per_pixel: usize,
+                         orientation: u16)
+                         -> Vec<u8> {
+    if !(2..=8).contains(&orientation) {
+        return data.to_vec();
+    }
+    let (display_width, display_height) = if is_transposed(orientation) {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    let mut oriented = Vec::with_capacity(data.len());
+    for y in 0..display_height {
+        for x in 0..display_width {
+            // The stored pixel shown at (x, y)
+            let (stored_x, stored_y) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                8 => (width - 1 - y, x),
+                _ => unreachable!(),
+            };
+            let index = (stored_y * width + stored_x) * bytes_per_pixel;
+            oriented.extend_from_slice(&data[index..index + bytes_per_pixel]);
+        }
+    }
+    oriented
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stored as
+    ///
+    ///  1 2 3
+    ///  4 5 6
+    const STORED: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    #[test]
+    fn orientations() {
+        let displayed: [(u16, [u8; 6]); 8] = [(1, [1, 2, 3, 4, 5, 6]),
+                                              (2, [3, 2, 1, 6, 5, 4]),
+                                              (3, [6, 5, 4, 3, 2, 1]),
+                                              (4, [4, 5, 6, 1, 2, 3]),
+                                              (5, [1, 4, 2, 5, 3, 6]),
+                                              (6, [4, 1, 5, 2, 6, 3]),
+                                              (7, [6, 3, 5, 2, 4, 1]),
+                                              (8, [3, 6, 2, 5, 1, 4])];
+        for &(orientation, ref expected) in displayed.iter() {
+            assert_eq!(apply_orientation(&STORED, 3, 2, 1, orientation),
+                       expected.to_vec(),
+                       "orientation {}",
+                       orientation);
+        }
+    }
+
+    #[test]
+    fn whole_pixels_are_moved() {
+        let stored = [1, 10, 2, 20];
+        assert_eq!(apply_orientation(&stored, 2, 1, 2, 2), vec![2, 20, 1, 10]);
+        assert_eq!(apply_orientation(&stored, 2, 1, 2, 6), vec![1, 10, 2, 20]);
+    }
+
+    #[test]
+    fn unknown_orientation_is_kept() {
+        assert_eq!(apply_orientation(&STORED, 3, 2, 1, 9), STORED.to_vec());
+        assert!(!is_transposed(9));
+        assert!(is_transposed(6));
+    }
+}
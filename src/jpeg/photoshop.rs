@@ -0,0 +1,52 @@
+/// Identifier of the APP13 segments holding Photoshop image resources.
+pub const PHOTOSHOP_IDENTIFIER: &[u8] = b"Photoshop 3.0\0";
+
+/// Resource ID of the IPTC-NAA record.
+pub const IPTC_RESOURCE_ID: u16 = 0x0404;
+
+/// A Photoshop image resource block.
+/// See the Adobe Photoshop File Formats Specification, "Image Resource Blocks".
+#[derive(Debug, Clone)]
+pub struct ImageResource {
+    pub id: u16,
+    /// Usually empty
+    pub name: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// Parse the image resource blocks, as concatenated from the APP13 segments
+/// after their identifier.
+///
+///  "8BIM", resource ID, name (Pascal string, padded to even size), size,
+///  data (padded to even size)
+pub fn parse_image_resources(data: &[u8]) -> Result<Vec<ImageResource>, String> {
+    let mut resources = Vec::new();
+    let mut i = 0;
+    while i + 4 <= data.len() {
+        if &data[i..i + 4] != b"8BIM" {
+            return Err(format!("Image resource at {} has no 8BIM signature", i));
+        }
+        let header = data.get(i + 4..i + 7)
+            .ok_or(format!("Image resource at {} is truncated", i))?;
+        let id = (header[0] as u16) << 8 | header[1] as u16;
+        let name_length = header[2] as usize;
+        let name_start = i + 7;
+        // The length byte and the name are padded to an even size together.
+        let size_start = name_start + name_length + (name_length + 1) % 2;
+        let size = data.get(size_start..size_start + 4)
+            .map(|b| {
+                (b[0] as usize) << 24 | (b[1] as usize) << 16 | (b[2] as usize) << 8 | b[3] as usize
+            })
+            .ok_or(format!("Image resource {:#06x} is truncated", id))?;
+        let data_start = size_start + 4;
+        let resource_data = data.get(data_start..data_start + size)
+            .ok_or(format!("Image resource {:#06x} is truncated", id))?;
+        resources.push(ImageResource {
+            id,
+            name: data[name_start..name_start + name_length].to_vec(),
+            data: resource_data.to_vec(),
+        });
+        i = data_start + size + size % 2;
+    }
+    Ok(resources)
+}
@@ -0,0 +1,240 @@
+use jpeg::color::PixelFormat;
+
+use std::io::{self, Write};
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn check_length(width: usize, height: usize, channels: usize, length: usize) -> io::Result<()> {
+    if width == 0 || height == 0 {
+        return Err(invalid_input(format!("Image of {}x{} pixels is empty", width, height)));
+    }
+    let expected = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(channels));
+    if expected != Some(length) {
+        return Err(invalid_input(format!("{} samples for {}x{} pixels of {} channels",
+                                         length,
+                                         width,
+                                         height,
+                                         channels)));
+    }
+    Ok(())
+}
+
+/// Write `data`, in `format`, with the first `channels` samples of each
+/// pixel, in RGB order for the BGR formats.
+fn write_lines<W: Write>(writer: &mut W,
+                         width: usize,
+                         format: PixelFormat,
+                         channels: usize,
+                         data: &[u8])
+                         -> io::Result<()> {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let swap = matches!(format, PixelFormat::Bgr | PixelFormat::Bgra);
+    if !swap && channels == bytes_per_pixel {
+        return writer.write_all(data);
+    }
+    let mut line = Vec::with_capacity(width * channels);
+    for pixels in data.chunks(width * bytes_per_pixel) {
+        line.clear();
+        for pixel in pixels.chunks(bytes_per_pixel) {
+            let start = line.len();
+            line.extend_from_slice(&pixel[..channels]);
+            if swap {
+                line.swap(start, start + 2);
+            }
+        }
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
+
+/// Write 8-bit pixels as binary PNM: P5 for `PixelFormat::Gray`, and P6
+/// for the RGB formats, without alpha. CMYK and YCbCr pixels are
+/// rejected, as PNM has no such colors.
+///
+/// The samples are written in a few large writes, but the header in
+/// several small ones, so `writer` should be buffered, like a `BufWriter`.
+pub fn write_pnm<W: Write>(writer: &mut W,
+                           width: usize,
+                           height: usize,
+                           format: PixelFormat,
+                           data: &[u8])
+                           -> io::Result<()> {
+    let (magic, channels) = match format {
+        PixelFormat::Gray => ("P5", 1),
+        PixelFormat::Rgb | PixelFormat::Rgba | PixelFormat::Bgr | PixelFormat::Bgra => ("P6", 3),
+        _ => return Err(invalid_input(format!("PNM cannot hold {:?} pixels", format))),
+    };
+    check_length(width, height, format.bytes_per_pixel(), data.len())?;
+    write!(writer, "{}\n{} {}\n255\n", magic, width, height)?;
+    write_lines(writer, width, format, channels, data)
+}
+
+/// Write samples of up to 16 bits as binary PNM, P5 for one channel and P6
+/// for three, with the samples in big endian order when `max_value` is
+/// more than 255.
+pub fn write_pnm_16<W: Write>(writer: &mut W,
+                              width: usize,
+                              height: usize,
+                              channels: usize,
+                              max_value: u16,
+                              data: &[u16])
+                              -> io::Result<()> {
+    let magic = match channels {
+        1 => "P5",
+        3 => "P6",
+        _ => return Err(invalid_input(format!("PNM cannot hold {} channels", channels))),
+    };
+    if max_value == 0 {
+        return Err(invalid_input("Maximum sample value of 0".to_string()));
+    }
+    check_length(width, height, channels, data.len())?;
+    if let Some(sample) = data.iter().find(|&&sample| sample > max_value) {
+        return Err(invalid_input(format!("Sample {} is more than {}", sample, max_value)));
+    }
+    write!(writer, "{}\n{} {}\n{}\n", magic, width, height, max_value)?;
+    // Samples of at most 255 take one byte. See netpbm's pgm(5).
+    let bytes_per_sample = if max_value > 255 { 2 } else { 1 };
+    let mut line = Vec::with_capacity(width * channels * bytes_per_sample);
+    for samples in data.chunks(width * channels) {
+        line.clear();
+        for &sample in samples {
+            if bytes_per_sample == 2 {
+                line.push((sample >> 8) as u8);
+            }
+            line.push(sample as u8);
+        }
+        writer.write_all(&line)?;
+    }
+    Ok(())
+}
+
+/// Write 8-bit pixels as PAM, P7, with the tuple type `GRAYSCALE`, `RGB`,
+/// `RGB_ALPHA` or `CMYK`. Unlike PNM, PAM keeps the alpha channel and
+/// CMYK pixels, which are written with 0 as no ink. YCbCr is rejected.
+///
+/// As for `write_pnm`, `writer` should be buffered.
+pub fn write_pam<W: Write>(writer: &mut W,
+                           width: usize,
+                           height: usize,
+                           format: PixelFormat,
+                           data: &[u8])
+                           -> io::Result<()> {
+    let tuple_type = match format {
+        PixelFormat::Gray => "GRAYSCALE",
+        PixelFormat::Rgb | PixelFormat::Bgr => "RGB",
+        PixelFormat::Rgba | PixelFormat::Bgra => "RGB_ALPHA",
+        PixelFormat::Cmyk => "CMYK",
+        _ => return Err(invalid_input(format!("PAM cannot hold {:?} pixels", format))),
+    };
+    let channels = format.bytes_per_pixel();
+    check_length(width, height, channels, data.len())?;
+    write!(writer,
+           "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {}\nENDHDR\n",
+           width,
+           height,
+           channels,
+           tuple_type)?;
+    write_lines(writer, width, format, channels, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pnm(width: usize, height: usize, format: PixelFormat, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        write_pnm(&mut out, width, height, format, data)?;
+        Ok(out)
+    }
+
+    fn pam(width: usize, height: usize, format: PixelFormat, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        write_pam(&mut out, width, height, format, data)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn gray_is_p5() {
+        let out = pnm(2, 1, PixelFormat::Gray, &[0, 255]).unwrap();
+        assert_eq!(out, b"P5\n2 1\n255\n\x00\xff");
+    }
+
+    #[test]
+    fn rgb_is_p6_without_alpha() {
+        let expected = b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06";
+        let rgb = [1, 2, 3, 4, 5, 6];
+        assert_eq!(pnm(2, 1, PixelFormat::Rgb, &rgb).unwrap(), expected);
+        let rgba = [1, 2, 3, 255, 4, 5, 6, 255];
+        assert_eq!(pnm(2, 1, PixelFormat::Rgba, &rgba).unwrap(), expected);
+        let bgr = [3, 2, 1, 6, 5, 4];
+        assert_eq!(pnm(2, 1, PixelFormat::Bgr, &bgr).unwrap(), expected);
+        let bgra = [3, 2, 1, 255, 6, 5, 4, 255];
+        assert_eq!(pnm(2, 1, PixelFormat::Bgra, &bgra).unwrap(), expected);
+    }
+
+    #[test]
+    fn pam_headers() {
+        let header = |depth: usize, tuple_type: &str| {
+            format!("P7\nWIDTH 1\nHEIGHT 1\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {}\nENDHDR\n",
+                    depth,
+                    tuple_type)
+                .into_bytes()
+        };
+        let mut expected = header(1, "GRAYSCALE");
+        expected.push(7);
+        assert_eq!(pam(1, 1, PixelFormat::Gray, &[7]).unwrap(), expected);
+
+        let mut expected = header(3, "RGB");
+        expected.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(pam(1, 1, PixelFormat::Rgb, &[1, 2, 3]).unwrap(), expected);
+        assert_eq!(pam(1, 1, PixelFormat::Bgr, &[3, 2, 1]).unwrap(), expected);
+
+        let mut expected = header(4, "RGB_ALPHA");
+        expected.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(pam(1, 1, PixelFormat::Rgba, &[1, 2, 3, 4]).unwrap(), expected);
+        assert_eq!(pam(1, 1, PixelFormat::Bgra, &[3, 2, 1, 4]).unwrap(), expected);
+
+        let mut expected = header(4, "CMYK");
+        expected.extend_from_slice(&[0, 64, 128, 255]);
+        assert_eq!(pam(1, 1, PixelFormat::Cmyk, &[0, 64, 128, 255]).unwrap(), expected);
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let error = pnm(1, 1, PixelFormat::Cmyk, &[0; 4]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(pnm(1, 1, PixelFormat::YCbCr, &[0; 3]).is_err());
+        assert!(pam(1, 1, PixelFormat::YCbCr, &[0; 3]).is_err());
+    }
+
+    #[test]
+    fn sizes_are_checked() {
+        assert!(pnm(0, 1, PixelFormat::Gray, &[]).is_err());
+        assert!(pnm(1, 0, PixelFormat::Gray, &[]).is_err());
+        assert!(pam(0, 2, PixelFormat::Rgb, &[]).is_err());
+        assert!(pnm(2, 2, PixelFormat::Gray, &[0; 3]).is_err());
+        assert!(pnm(2, 2, PixelFormat::Rgba, &[0; 12]).is_err());
+        assert!(pam(2, 2, PixelFormat::Rgb, &[0; 13]).is_err());
+        assert!(pnm(usize::MAX, 2, PixelFormat::Gray, &[0; 2]).is_err());
+    }
+
+    #[test]
+    fn samples_of_16_bits() {
+        let mut out = Vec::new();
+        write_pnm_16(&mut out, 1, 1, 3, 65535, &[0x0102, 0xff00, 65535]).unwrap();
+        assert_eq!(out, b"P6\n1 1\n65535\n\x01\x02\xff\x00\xff\xff");
+
+        let mut out = Vec::new();
+        write_pnm_16(&mut out, 2, 1, 1, 255, &[1, 255]).unwrap();
+        assert_eq!(out, b"P5\n2 1\n255\n\x01\xff");
+
+        let mut out = Vec::new();
+        assert!(write_pnm_16(&mut out, 1, 1, 1, 1000, &[1001]).is_err());
+        assert!(write_pnm_16(&mut out, 1, 1, 1, 0, &[0]).is_err());
+        assert!(write_pnm_16(&mut out, 1, 1, 2, 255, &[0, 0]).is_err());
+        assert!(write_pnm_16(&mut out, 1, 2, 1, 255, &[0]).is_err());
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+use jpeg::icc;
+use jpeg::jpeg::{FrameComponentHeader, FrameHeader, FrameType};
+use jpeg::mpf;
+use jpeg::photoshop;
+use jpeg::segments;
+use jpeg::xmp;
+
+use std::io::{self, Read};
+
+/// What `probe` finds in the segments before the first scan.
+#[derive(Debug, Clone)]
+pub struct ProbeInfo {
+    pub frame_header: FrameHeader,
+    pub metadata: Metadata,
+}
+
+/// Which metadata segments a file has before the first scan.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// JFIF APP0
+    pub has_jfif: bool,
+    /// Exif APP1
+    pub has_exif: bool,
+    /// XMP APP1, main or extended
+    pub has_xmp: bool,
+    /// ICC profile APP2
+    pub has_icc_profile: bool,
+    /// MPF APP2, listing other images after EOI
+    pub has_mpf: bool,
+    /// Photoshop APP13, which usually holds IPTC data
+    pub has_photoshop: bool,
+    /// Adobe APP14, describing the color transform
+    pub has_adobe: bool,
+    /// COM
+    pub has_comment: bool,
+}
+
+impl ProbeInfo {
+    pub fn width(&self) -> u16 {
+        self.frame_header.samples_per_line()
+    }
+
+    /// The height, or 0 if a DNL segment after the first scan gives it.
+    pub fn height(&self) -> u16 {
+        self.frame_header.num_lines()
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        self.frame_header.frame_type()
+    }
+
+    pub fn components(&self) -> &[FrameComponentHeader] {
+        self.frame_header.frame_components()
+    }
+
+    /// Whether the frame is baseline DCT, the only type `JPEGImage` decodes.
+    pub fn is_baseline(&self) -> bool {
+        self.frame_type() == FrameType::BaselineDct
+    }
+
+    /// Whether the frame is progressive DCT, with Huffman or arithmetic coding.
+    pub fn is_progressive(&self) -> bool {
+        match self.frame_type() {
+            FrameType::ProgressiveDct => true,
+            FrameType::Other(marker) => marker == 0xc6 || marker == 0xca || marker == 0xce,
+            _ => false,
+        }
+    }
+}
+
+/// Bytes of an APPn segment needed to tell what it holds, which is
+/// the longest identifier, that of extended XMP.
+const IDENTIFIER_LENGTH: usize = 35;
+
+fn read_bytes<R: Read>(reader: &mut R, n: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0; n];
+    reader.read_exact(&mut bytes).map_err(|error| match error.kind() {
+        io::ErrorKind::UnexpectedEof => "File ends before the first scan".to_string(),
+        _ => error.to_string(),
+    })?;
+    Ok(bytes)
+}
+
+fn skip_bytes<R: Read>(reader: &mut R, n: usize) -> Result<(), String> {
+    let skipped = io::copy(&mut reader.take(n as u64), &mut io::sink())
+        .map_err(|error| error.to_string())?;
+    if skipped < n as u64 {
+        return Err("File ends before the first scan".to_string());
+    }
+    Ok(())
+}
+
+/// Read the segments of a JPEG file up to the first SOS, without decoding
+/// anything, to find the frame header and which metadata there is.
+///
+/// Nothing after the length of the SOS segment is read, and metadata
+/// segments are skipped after their identifier. Reads are small, so
+/// `reader` should be buffered if reads are slow.
+pub fn probe<R: Read>(reader: &mut R) -> Result<ProbeInfo, String> {
+    if read_bytes(reader, 2)? != [0xff, 0xd8] {
+        return Err("Not a JPEG file: no SOI marker".to_string());
+    }
+    let mut frame_header = None;
+    let mut info = Metadata::default();
+    loop {
+        let first = read_bytes(reader, 1)?[0];
+        if first != 0xff {
+            return Err(format!("Expected a marker, found {:#04x}", first));
+        }
+        // Any X’FF’ before the marker code is a fill byte. See JPEG B.1.1.2.
+        let mut marker = 0xff;
+        while marker == 0xff {
+            marker = read_bytes(reader, 1)?[0];
+        }
+        if segments::is_standalone(marker) {
+            if marker == 0xd9 {
+                break;
+            }
+            continue;
+        }
+        let length = read_bytes(reader, 2)?;
+        let length = ((length[0] as usize) << 8 | length[1] as usize)
+            .checked_sub(2)
+            .ok_or(format!("{} segment has a bad length", segments::marker_name(marker)))?;
+        match marker {
+            0xda => break,
+            0xc4 | 0xc8 | 0xcc => skip_bytes(reader, length)?,
+            0xc0..=0xcf => {
+                let data = read_bytes(reader, length)?;
+                frame_header = Some(FrameHeader::parse(FrameType::from_marker(marker), &data)?);
+            }
+            0xe0..=0xef | 0xfe => {
+                let data = read_bytes(reader, length.min(IDENTIFIER_LENGTH))?;
+                skip_bytes(reader, length - data.len())?;
+                match marker {
+                    0xe0 => info.has_jfif |= data.starts_with(b"JFIF\0"),
+                    0xe1 => {
+                        info.has_exif |= data.starts_with(b"Exif\0\0");
+                        info.has_xmp |= data.starts_with(xmp::XMP_IDENTIFIER) ||
+                                        data.starts_with(xmp::EXTENDED_XMP_IDENTIFIER);
+                    }
+                    0xe2 => {
+                        info.has_icc_profile |= data.starts_with(icc::ICC_IDENTIFIER);
+                        info.has_mpf |= data.starts_with(mpf::MPF_IDENTIFIER);
+                    }
+                    0xed => {
+                        info.has_photoshop |= data.starts_with(photoshop::PHOTOSHOP_IDENTIFIER);
+                    }
+                    0xee => info.has_adobe |= data.starts_with(b"Adobe"),
+                    0xfe => info.has_comment = true,
+                    _ => {}
+                }
+            }
+            _ => skip_bytes(reader, length)?,
+        }
+    }
+    Ok(ProbeInfo {
+        frame_header: frame_header.ok_or("No frame header before the first scan".to_string())?,
+        metadata: info,
+    })
+}
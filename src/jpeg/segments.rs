@@ -0,0 +1,224 @@
+/// What a part of a JPEG file is. See JPEG B.1.1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    /// A marker followed by length bytes and a payload, like APPn, DQT or SOS
+    Marker(u8),
+    /// A marker without length bytes: SOI, EOI, RSTn or TEM
+    StandaloneMarker(u8),
+    /// X’FF’ bytes before a marker. See JPEG B.1.1.2.
+    Fill,
+    /// Entropy coded data following SOS or RSTn, with the stuffed bytes still in it
+    EntropyCodedData,
+    /// Bytes where a marker should be, like trailing data after EOI
+    Garbage,
+}
+
+/// A part of a JPEG file, borrowed from the file data.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    pub kind: SegmentKind,
+    /// Offset of the first byte in the file
+    pub offset: usize,
+    /// Number of bytes in the file, including the marker and length bytes
+    pub length: usize,
+    /// The bytes following the marker and length bytes, or all bytes of
+    /// segments without a marker
+    pub payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    /// The marker code, like 0xe0 for APP0, if the segment is a marker.
+    pub fn marker(&self) -> Option<u8> {
+        match self.kind {
+            SegmentKind::Marker(n) | SegmentKind::StandaloneMarker(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+/// Whether the marker has no length bytes. See JPEG B.1.1.3.
+pub fn is_standalone(marker: u8) -> bool {
+    marker == 0x01 || (0xd0..=0xd9).contains(&marker)
+}
+
+/// The name of a marker, as used in the JPEG specification, table B.1.
+pub fn marker_name(marker: u8) -> String {
+    match marker {
+        0xc4 => "DHT".to_string(),
+        0xc8 => "JPG".to_string(),
+        0xcc => "DAC".to_string(),
+        0xc0..=0xcf => format!("SOF{}", marker - 0xc0),
+        0xd0..=0xd7 => format!("RST{}", marker - 0xd0),
+        0xd8 => "SOI".to_string(),
+        0xd9 => "EOI".to_string(),
+        0xda => "SOS".to_string(),
+        0xdb => "DQT".to_string(),
+        0xdc => "DNL".to_string(),
+        0xdd => "DRI".to_string(),
+        0xde => "DHP".to_string(),
+        0xdf => "EXP".to_string(),
+        0xe0..=0xef => format!("APP{}", marker - 0xe0),
+        0xf0..=0xfd => format!("JPG{}", marker - 0xf0),
+        0xfe => "COM".to_string(),
+        0x01 => "TEM".to_string(),
+        _ => format!("RES{:02X}", marker),
+    }
+}
+
+/// Iterator over the segments of a JPEG file, created by `segments`.
+pub struct Segments<'a> {
+    data: &'a [u8],
+    position: usize,
+    /// Whether the bytes at `position` may be entropy coded data
+    in_scan: bool,
+    failed: bool,
+}
+
+/// Split JPEG file data into its segments, in order. Together, the segments
+/// cover every byte of the data, and nothing is interpreted beyond the
+/// marker codes and lengths, so unknown segments can be inspected or skipped.
+///
+/// The iterator stops after an error, which is a marker with a length
+/// that does not fit in the data.
+pub fn segments(data: &[u8]) -> Segments<'_> {
+    Segments {
+        data,
+        position: 0,
+        in_scan: false,
+        failed: false,
+    }
+}
+
+impl<'a> Segments<'a> {
+    /// Continue at `position`, still in the current scan if there is one.
+    /// A lenient decode uses this to skip markers in corrupt entropy coded data.
+    pub fn skip_to(&mut self, position: usize) {
+        self.position = position.max(self.position);
+    }
+
+    fn segment(&mut self, kind: SegmentKind, length: usize, header: usize) -> Segment<'a> {
+        let offset = self.position;
+        self.position += length;
+        Segment {
+            kind,
+            offset,
+            length,
+            payload: &self.data[offset + header..offset + length],
+        }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Result<Segment<'a>, String>;
+
+    fn next(&mut self) -> Option<Result<Segment<'a>, String>> {
+        let data = self.data;
+        let start = self.position;
+        if start >= data.len() || self.failed {
+            return None;
+        }
+
+        // Anything up to a marker is entropy coded data in a scan, and garbage
+        // elsewhere. Stuffed X’FF00’ is not a marker.
+        let is_marker = |i: usize| data[i] == 0xff && i + 1 < data.len() && data[i + 1] != 0x00;
+        let end = if self.in_scan {
+            let mut i = start;
+            while i < data.len() && !is_marker(i) {
+                i += if data[i] == 0xff { 2 } else { 1 };
+            }
+            i.min(data.len())
+        } else {
+            (start..data.len()).find(|&i| is_marker(i)).unwrap_or(data.len())
+        };
+        if end > start {
+            let kind = if self.in_scan {
+                SegmentKind::EntropyCodedData
+            } else {
+                SegmentKind::Garbage
+            };
+            return Some(Ok(self.segment(kind, end - start, 0)));
+        }
+
+        // Any X’FF’ followed by another is a fill byte.
+        let run = data[start..].iter().take_while(|&&b| b == 0xff).count();
+        if start + run == data.len() {
+            return Some(Ok(self.segment(SegmentKind::Garbage, run, 0)));
+        }
+        if run > 1 {
+            return Some(Ok(self.segment(SegmentKind::Fill, run - 1, 0)));
+        }
+
+        let marker = data[start + 1];
+        if is_standalone(marker) {
+            // Entropy coded data continues after RSTn.
+            self.in_scan = (0xd0..=0xd7).contains(&marker) && self.in_scan;
+            return Some(Ok(self.segment(SegmentKind::StandaloneMarker(marker), 2, 2)));
+        }
+
+        let length = match data.get(start + 2..start + 4) {
+            Some(bytes) => (bytes[0] as usize) << 8 | bytes[1] as usize,
+            None => 0,
+        };
+        if length < 2 || start + 2 + length > data.len() {
+            self.failed = true;
+            return Some(Err(format!("{} marker at {} has length {}, but {} bytes are left",
+                                    marker_name(marker),
+                                    start,
+                                    length,
+                                    data.len() - start - 2)));
+        }
+        self.in_scan = marker == 0xda;
+        Some(Ok(self.segment(SegmentKind::Marker(marker), 2 + length, 4)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SOI, fill bytes before APP0, a scan with stuffed bytes and RST0,
+    /// EOI, and trailing bytes.
+    const FILE: &[u8] = &[0xff, 0xd8, 0xff, 0xff, 0xff, 0xe0, 0x00, 0x04, 0x01, 0x02, 0xff, 0xda,
+                          0x00, 0x02, 0x12, 0xff, 0x00, 0x34, 0xff, 0xd0, 0x56, 0xff, 0xd9, 0x61,
+                          0x62];
+
+    #[test]
+    fn segments_cover_every_byte() {
+        let segments: Vec<Segment> = segments(FILE).map(|s| s.unwrap()).collect();
+        let kinds: Vec<SegmentKind> = segments.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds,
+                   vec![SegmentKind::StandaloneMarker(0xd8),
+                        SegmentKind::Fill,
+                        SegmentKind::Marker(0xe0),
+                        SegmentKind::Marker(0xda),
+                        SegmentKind::EntropyCodedData,
+                        SegmentKind::StandaloneMarker(0xd0),
+                        SegmentKind::EntropyCodedData,
+                        SegmentKind::StandaloneMarker(0xd9),
+                        SegmentKind::Garbage]);
+        let mut offset = 0;
+        for segment in &segments {
+            assert_eq!(segment.offset, offset);
+            offset += segment.length;
+        }
+        assert_eq!(offset, FILE.len());
+        assert_eq!(segments[2].payload, &[0x01, 0x02]);
+        assert_eq!(segments[4].payload, &[0x12, 0xff, 0x00, 0x34]);
+    }
+
+    #[test]
+    fn bad_length_stops() {
+        let mut segments = segments(&[0xff, 0xd8, 0xff, 0xe1, 0x00, 0x10, 0x00]);
+        assert!(segments.next().unwrap().is_ok());
+        assert!(segments.next().unwrap().is_err());
+        assert!(segments.next().is_none());
+    }
+
+    #[test]
+    fn marker_names() {
+        assert_eq!(marker_name(0xc0), "SOF0");
+        assert_eq!(marker_name(0xc4), "DHT");
+        assert_eq!(marker_name(0xd3), "RST3");
+        assert_eq!(marker_name(0xed), "APP13");
+    }
+}
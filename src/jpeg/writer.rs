@@ -0,0 +1,459 @@
+use jpeg::exif::Exif;
+use jpeg::icc;
+use jpeg::mpf;
+use jpeg::photoshop;
+use jpeg::segments::{self, SegmentKind};
+use jpeg::xmp;
+
+use std::io::{self, Write};
+
+/// A segment of a JPEG file, owning its bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedSegment {
+    kind: SegmentKind,
+    /// The bytes as they are in the file, including marker and length bytes
+    bytes: Vec<u8>,
+}
+
+impl OwnedSegment {
+    /// A marker segment with length bytes, like APPn or COM.
+    pub fn new(marker: u8, payload: &[u8]) -> Result<OwnedSegment, String> {
+        if segments::is_standalone(marker) {
+            return Err(format!("{} has no payload", segments::marker_name(marker)));
+        }
+        // The length counts itself, and is 16 bits.
+        let length = payload.len() + 2;
+        if length > 0xffff {
+            return Err(format!("{} payload of {} bytes is too long",
+                               segments::marker_name(marker),
+                               payload.len()));
+        }
+        let mut bytes = vec![0xff, marker, (length >> 8) as u8, length as u8];
+        bytes.extend_from_slice(payload);
+        Ok(OwnedSegment {
+            kind: SegmentKind::Marker(marker),
+            bytes,
+        })
+    }
+
+    /// A marker without length bytes, like SOI or RSTn.
+    pub fn standalone(marker: u8) -> Result<OwnedSegment, String> {
+        if !segments::is_standalone(marker) {
+            return Err(format!("{} needs a payload", segments::marker_name(marker)));
+        }
+        Ok(OwnedSegment {
+            kind: SegmentKind::StandaloneMarker(marker),
+            bytes: vec![0xff, marker],
+        })
+    }
+
+    /// Bytes that are not a marker, like entropy coded data. They are
+    /// written as given, so they should not contain anything like a marker.
+    pub fn raw(kind: SegmentKind, bytes: Vec<u8>) -> OwnedSegment {
+        OwnedSegment { kind, bytes }
+    }
+
+    pub fn kind(&self) -> SegmentKind {
+        self.kind
+    }
+
+    /// The marker code, like 0xe0 for APP0, if the segment is a marker.
+    pub fn marker(&self) -> Option<u8> {
+        match self.kind {
+            SegmentKind::Marker(n) | SegmentKind::StandaloneMarker(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The bytes following the marker and length bytes, or all bytes of
+    /// segments without a marker.
+    pub fn payload(&self) -> &[u8] {
+        match self.kind {
+            SegmentKind::Marker(_) => &self.bytes[4..],
+            SegmentKind::StandaloneMarker(_) => &[],
+            _ => &self.bytes,
+        }
+    }
+
+    /// The bytes as they are written, including marker and length bytes.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Which metadata `JPEGFile::strip_metadata` removes. Segments needed
+/// to decode the image, like JFIF APP0 and Adobe APP14, are always kept.
+#[derive(Debug, Clone)]
+pub struct StripOptions {
+    /// Exif APP1, with GPS, maker notes and the thumbnail
+    exif: bool,
+    /// XMP APP1, main and extended
+    xmp: bool,
+    /// COM
+    comments: bool,
+    /// ICC profile APP2
+    icc: bool,
+    /// Photoshop APP13, with IPTC
+    iptc: bool,
+    /// MPF APP2, and anything after EOI, like the other images of an MPF file
+    mpf: bool,
+    /// Any other application segment, like JFXX thumbnails
+    other: bool,
+}
+
+impl StripOptions {
+    /// Remove all metadata, except the ICC profile.
+    pub fn new() -> StripOptions {
+        StripOptions {
+            exif: true,
+            xmp: true,
+            comments: true,
+            icc: false,
+            iptc: true,
+            mpf: true,
+            other: true,
+        }
+    }
+
+    pub fn exif(mut self, strip: bool) -> StripOptions {
+        self.exif = strip;
+        self
+    }
+
+    pub fn xmp(mut self, strip: bool) -> StripOptions {
+        self.xmp = strip;
+        self
+    }
+
+    pub fn comments(mut self, strip: bool) -> StripOptions {
+        self.comments = strip;
+        self
+    }
+
+    /// Removing the ICC profile changes how the colors are shown.
+    pub fn icc(mut self, strip: bool) -> StripOptions {
+        self.icc = strip;
+        self
+    }
+
+    pub fn iptc(mut self, strip: bool) -> StripOptions {
+        self.iptc = strip;
+        self
+    }
+
+    /// The MPF index is only valid with the images after EOI,
+    /// so both are kept or removed together.
+    pub fn mpf(mut self, strip: bool) -> StripOptions {
+        self.mpf = strip;
+        self
+    }
+
+    pub fn other(mut self, strip: bool) -> StripOptions {
+        self.other = strip;
+        self
+    }
+
+    /// Whether to remove a segment of the first image.
+    fn strips(&self, segment: &OwnedSegment) -> bool {
+        let payload = segment.payload();
+        match segment.marker() {
+            Some(0xe0) => !payload.starts_with(b"JFIF\0") && self.other,
+            Some(0xe1) if payload.starts_with(b"Exif\0\0") => self.exif,
+            Some(0xe1) if payload.starts_with(xmp::XMP_IDENTIFIER) ||
+                          payload.starts_with(xmp::EXTENDED_XMP_IDENTIFIER) => self.xmp,
+            Some(0xe2) if payload.starts_with(icc::ICC_IDENTIFIER) => self.icc,
+            Some(0xe2) if payload.starts_with(mpf::MPF_IDENTIFIER) => self.mpf,
+            Some(0xed) if payload.starts_with(photoshop::PHOTOSHOP_IDENTIFIER) => self.iptc,
+            Some(0xee) if payload.starts_with(b"Adobe") => false,
+            Some(0xe0..=0xef) => self.other,
+            Some(0xfe) => self.comments,
+            _ => false,
+        }
+    }
+}
+
+impl Default for StripOptions {
+    fn default() -> StripOptions {
+        StripOptions::new()
+    }
+}
+
+/// A JPEG file as an ordered list of segments. Writing a parsed file without
+/// changes gives back the exact same bytes, as every byte is kept, including
+/// fill bytes, entropy coded data and anything after EOI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JPEGFile {
+    segments: Vec<OwnedSegment>,
+}
+
+impl JPEGFile {
+    pub fn parse(data: &[u8]) -> Result<JPEGFile, String> {
+        let mut segments = Vec::new();
+        for segment in segments::segments(data) {
+            let segment = segment?;
+            segments.push(OwnedSegment {
+                kind: segment.kind,
+                bytes: data[segment.offset..segment.offset + segment.length].to_vec(),
+            });
+        }
+        Ok(JPEGFile { segments })
+    }
+
+    pub fn segments(&self) -> &[OwnedSegment] {
+        &self.segments
+    }
+
+    /// Index of the first segment with `marker`.
+    pub fn position(&self, marker: u8) -> Option<usize> {
+        self.segments.iter().position(|segment| segment.marker() == Some(marker))
+    }
+
+    pub fn insert(&mut self, index: usize, segment: OwnedSegment) {
+        self.segments.insert(index, segment);
+    }
+
+    pub fn remove(&mut self, index: usize) -> OwnedSegment {
+        self.segments.remove(index)
+    }
+
+    /// Replace the segment at `index`, and return the old one.
+    pub fn replace(&mut self, index: usize, segment: OwnedSegment) -> OwnedSegment {
+        std::mem::replace(&mut self.segments[index], segment)
+    }
+
+    /// Keep only the segments for which `keep` returns true.
+    pub fn retain<F>(&mut self, keep: F)
+        where F: FnMut(&OwnedSegment) -> bool
+    {
+        self.segments.retain(keep);
+    }
+
+    /// Index after SOI and the application and comment segments following it,
+    /// which is where new metadata segments go.
+    pub fn header_end(&self) -> usize {
+        let start = match self.segments.first().and_then(|segment| segment.marker()) {
+            Some(0xd8) => 1,
+            _ => 0,
+        };
+        start +
+        self.segments[start..]
+            .iter()
+            .take_while(|segment| matches!(segment.marker(), Some(0xe0..=0xef) | Some(0xfe)))
+            .count()
+    }
+
+    /// Index of the Exif APP1 segment.
+    fn exif_position(&self) -> Option<usize> {
+        self.segments.iter().position(|segment| {
+            segment.marker() == Some(0xe1) && segment.payload().starts_with(b"Exif\0\0")
+        })
+    }
+
+    /// The Exif data of the first image, if present and valid.
+    pub fn exif(&self) -> Option<Exif> {
+        let index = self.exif_position()?;
+        Exif::parse(&self.segments[index].payload()[6..]).ok()
+    }
+
+    /// Replace the Exif APP1 segment with `exif`, or add one after SOI and
+    /// JFIF APP0 if there is none.
+    pub fn set_exif(&mut self, exif: &Exif) -> Result<(), String> {
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend_from_slice(&exif.to_bytes()?);
+        let segment = OwnedSegment::new(0xe1, &payload)?;
+        match self.exif_position() {
+            Some(index) => {
+                self.replace(index, segment);
+            }
+            None => {
+                let index = self.segments
+                    .iter()
+                    .position(|segment| match segment.marker() {
+                        Some(0xd8) => false,
+                        Some(0xe0) => !segment.payload().starts_with(b"JFIF\0"),
+                        _ => true,
+                    })
+                    .unwrap_or(self.segments.len());
+                self.insert(index, segment);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove metadata segments, as chosen by `options`. The segments
+    /// needed to decode the image are copied as they are.
+    ///
+    /// Only the first image is changed. If the MPF index is kept, the sizes
+    /// and offsets in it are updated, as the other images move with the end
+    /// of the first one.
+    pub fn strip_metadata(&mut self, options: &StripOptions) {
+        let end_of_image = self.position(0xd9).map(|i| i + 1).unwrap_or(self.segments.len());
+        let mut trailing = self.segments.split_off(end_of_image);
+        let old_length = self.length();
+        let old_header = self.mp_header();
+        self.segments.retain(|segment| !options.strips(segment));
+        if !options.mpf {
+            if let Some((_, old_header)) = old_header {
+                self.relocate_mp_images(old_length, old_header);
+            }
+            self.segments.append(&mut trailing);
+        }
+    }
+
+    fn length(&self) -> usize {
+        self.segments.iter().map(|segment| segment.bytes.len()).sum()
+    }
+
+    /// Index of the MPF APP2 segment, and offset of its MP header in the file,
+    /// which the offsets of the MP entries are relative to.
+    fn mp_header(&self) -> Option<(usize, usize)> {
+        let mut offset = 0;
+        for (index, segment) in self.segments.iter().enumerate() {
+            if segment.marker() == Some(0xe2) &&
+               segment.payload().starts_with(mpf::MPF_IDENTIFIER) {
+                return Some((index, offset + 4 + mpf::MPF_IDENTIFIER.len()));
+            }
+            offset += segment.bytes.len();
+        }
+        None
+    }
+
+    /// Update the MP index for the segments of the first image that were
+    /// removed, given its old length and the old offset of the MP header.
+    /// A broken MP index is left as is.
+    fn relocate_mp_images(&mut self, old_length: usize, old_header: usize) {
+        let (index, header) = match self.mp_header() {
+            Some(mp_header) => mp_header,
+            None => return,
+        };
+        let length = self.length();
+        let data = &mut self.segments[index].bytes[4 + mpf::MPF_IDENTIFIER.len()..];
+        let _ = mpf::relocate_entries(data, |_, size, offset| {
+            let position = old_header + offset as usize;
+            match offset {
+                // The first image
+                0 => (length as u32, 0),
+                // Images after the first one
+                _ if position >= old_length => {
+                    (size, (position - old_length + length - header) as u32)
+                }
+                _ => (size, offset),
+            }
+        });
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for segment in &self.segments {
+            writer.write_all(&segment.bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.segments.iter().map(|s| s.bytes.len()).sum());
+        for segment in &self.segments {
+            bytes.extend_from_slice(&segment.bytes);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LENA: &[u8] = include_bytes!("../../lena.jpeg");
+    const SIMPLE: &[u8] = include_bytes!("../../working-jpegs/huff_simple0.jpg");
+
+    #[test]
+    fn round_trip_is_byte_exact() {
+        // Fill bytes, RST0 in the scan, and trailing bytes after EOI
+        let odd = [0xff, 0xd8, 0xff, 0xff, 0xfe, 0x00, 0x03, 0x61, 0xff, 0xda, 0x00, 0x02, 0x12,
+                   0xff, 0x00, 0xff, 0xd0, 0x34, 0xff, 0xd9, 0x00, 0x01];
+        for &data in &[LENA, SIMPLE, &odd[..]] {
+            let file = JPEGFile::parse(data).unwrap();
+            assert_eq!(file.to_bytes(), data);
+            let mut written = Vec::new();
+            file.write_to(&mut written).unwrap();
+            assert_eq!(written, data);
+        }
+    }
+
+    #[test]
+    fn owned_segments() {
+        let comment = OwnedSegment::new(0xfe, b"hi").unwrap();
+        assert_eq!(comment.bytes(), &[0xff, 0xfe, 0x00, 0x04, b'h', b'i']);
+        assert_eq!(comment.payload(), b"hi");
+        assert!(OwnedSegment::new(0xd9, b"").is_err());
+        assert!(OwnedSegment::new(0xfe, &[0; 0xfffe]).is_err());
+        assert_eq!(OwnedSegment::standalone(0xd9).unwrap().bytes(), &[0xff, 0xd9]);
+        assert!(OwnedSegment::standalone(0xfe).is_err());
+    }
+
+    /// `SIMPLE` with XMP before the MPF APP2 and a comment after it,
+    /// followed by `second`.
+    fn mpf_file(second: &[u8]) -> Vec<u8> {
+        let segment = |marker: u8, payload: &[u8]| OwnedSegment::new(marker, payload).unwrap();
+        let xmp = segment(0xe1, &[xmp::XMP_IDENTIFIER, b"<x:xmpmeta/>"].concat());
+        let comment = segment(0xfe, b"after the MP index");
+        let index_length = 4 + 8 + 2 + 2 * 12 + 4 + 2 * 16;
+        let header = 2 + xmp.bytes().len() + 4 + 4;
+        let length = SIMPLE.len() + xmp.bytes().len() + 4 + index_length + comment.bytes().len();
+
+        let mut index = mpf::MPF_IDENTIFIER.to_vec();
+        index.extend_from_slice(b"MM\0\x2a\0\0\0\x08\0\x02");
+        index.extend_from_slice(&[0xb0, 0x01, 0, 4, 0, 0, 0, 1, 0, 0, 0, 2]);
+        index.extend_from_slice(&[0xb0, 0x02, 0, 7, 0, 0, 0, 32, 0, 0, 0, 38, 0, 0, 0, 0]);
+        index.extend_from_slice(&[0x20, 0x03, 0, 0]);
+        index.extend_from_slice(&(length as u32).to_be_bytes());
+        index.extend_from_slice(&[0; 12]);
+        index.extend_from_slice(&(second.len() as u32).to_be_bytes());
+        index.extend_from_slice(&((length - header) as u32).to_be_bytes());
+        index.extend_from_slice(&[0; 4]);
+
+        let mut file = JPEGFile::parse(SIMPLE).unwrap();
+        file.insert(1, comment);
+        file.insert(1, segment(0xe2, &index));
+        file.insert(1, xmp);
+        let mut data = file.to_bytes();
+        assert_eq!(data.len(), length);
+        data.extend_from_slice(second);
+        data
+    }
+
+    #[test]
+    fn strip_keeps_mpf_offsets() {
+        let second = SIMPLE;
+        let mut file = JPEGFile::parse(&mpf_file(second)).unwrap();
+        file.strip_metadata(&StripOptions::new().mpf(false));
+        let data = file.to_bytes();
+        let first_length = data.len() - second.len();
+
+        let image = ::jpeg::jpeg::JPEGImage::parse(data).unwrap();
+        assert!(image.xmp().is_none());
+        assert!(image.comment().is_none());
+        let mp_images = image.mp_images();
+        assert_eq!(mp_images.len(), 2);
+        assert_eq!(mp_images[0].entry.size as usize, first_length);
+        assert_eq!(mp_images[1].data(), second);
+    }
+
+    #[test]
+    fn strip_mpf_drops_other_images() {
+        let mut file = JPEGFile::parse(&mpf_file(SIMPLE)).unwrap();
+        file.strip_metadata(&StripOptions::new());
+        let mut simple = JPEGFile::parse(SIMPLE).unwrap();
+        simple.strip_metadata(&StripOptions::new());
+        assert_eq!(file.to_bytes(), simple.to_bytes());
+    }
+
+    #[test]
+    fn insert_comment_after_header() {
+        let mut file = JPEGFile::parse(SIMPLE).unwrap();
+        let index = file.header_end();
+        assert_eq!(file.segments()[index].marker(), Some(0xdb));
+        file.insert(index, OwnedSegment::new(0xfe, b"hi").unwrap());
+        let image = ::jpeg::jpeg::JPEGImage::parse(file.to_bytes()).unwrap();
+        assert_eq!(image.comment(), Some("hi"));
+    }
+}
@@ -0,0 +1,198 @@
+/// Identifier of the APP1 segment holding the main XMP packet.
+pub const XMP_IDENTIFIER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Identifier of APP1 segments holding extended XMP chunks.
+pub const EXTENDED_XMP_IDENTIFIER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+
+/// A part of the extended XMP, as found in an APP1 segment.
+/// See XMP Specification Part 3, section 1.1.3.1.
+#[derive(Debug, Clone)]
+pub struct ExtendedXmpChunk {
+    /// MD5 digest of the full extended XMP, as 32 uppercase hex digits
+    pub guid: String,
+    /// Length of the full extended XMP
+    pub full_length: u32,
+    /// Offset of this chunk in the full extended XMP
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl ExtendedXmpChunk {
+    /// Parse the APP1 segment data following the length bytes.
+    /// Returns `None` if the segment is not an extended XMP chunk.
+    pub fn parse(segment: &[u8]) -> Option<ExtendedXmpChunk> {
+        let header_length = EXTENDED_XMP_IDENTIFIER.len() + 40;
+        if segment.len() < header_length || !segment.starts_with(EXTENDED_XMP_IDENTIFIER) {
+            return None;
+        }
+        let header = &segment[EXTENDED_XMP_IDENTIFIER.len()..];
+        let be_u32 = |b: &[u8]| {
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+        };
+        Some(ExtendedXmpChunk {
+            guid: String::from_utf8_lossy(&header[..32]).into_owned(),
+            full_length: be_u32(&header[32..]),
+            offset: be_u32(&header[36..]),
+            data: segment[header_length..].to_vec(),
+        })
+    }
+}
+
+/// Find the GUID of the extended XMP, given in the `xmpNote:HasExtendedXMP`
+/// property of the main packet, either as an attribute or as an element.
+pub fn extended_guid(xmp: &str) -> Option<String> {
+    let property = xmp.find("HasExtendedXMP")?;
+    let value = &xmp[property + "HasExtendedXMP".len()..];
+    let start = value.find(['"', '\'', '>'])? + 1;
+    let guid: String = value[start..].chars().take(32).collect();
+    if guid.len() == 32 && guid.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(guid)
+    } else {
+        None
+    }
+}
+
+/// Put the chunks with the given GUID together, and check that they
+/// cover the full extended XMP, and that its MD5 digest matches the GUID.
+pub fn assemble_extended(guid: &str, chunks: &[ExtendedXmpChunk]) -> Result<String, String> {
+    let chunks: Vec<&ExtendedXmpChunk> = chunks.iter().filter(|c| c.guid == guid).collect();
+    let full_length = match chunks.first() {
+        Some(chunk) => chunk.full_length as usize,
+        None => return Err(format!("No extended XMP chunks with GUID {}", guid)),
+    };
+
+    let mut xmp = vec![0; full_length];
+    let mut covered = 0;
+    for chunk in &chunks {
+        let start = chunk.offset as usize;
+        let end = start + chunk.data.len();
+        if chunk.full_length as usize != full_length || end > full_length {
+            return Err(format!("Extended XMP chunk at {} does not fit in {} bytes",
+                               start,
+                               full_length));
+        }
+        xmp[start..end].copy_from_slice(&chunk.data);
+        covered += chunk.data.len();
+    }
+    if covered != full_length {
+        return Err(format!("Extended XMP chunks cover {} of {} bytes", covered, full_length));
+    }
+
+    let digest: String = md5(&xmp).iter().map(|b| format!("{:02X}", b)).collect();
+    if !digest.eq_ignore_ascii_case(guid) {
+        return Err(format!("Extended XMP has MD5 digest {}, expected {}", digest, guid));
+    }
+    String::from_utf8(xmp).map_err(|e| format!("Extended XMP is not UTF-8: {}", e))
+}
+
+/// Per-round shift amounts of MD5. See RFC 1321.
+const MD5_SHIFTS: [u32; 64] = [7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9,
+                               14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23,
+                               4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15, 21, 6, 10,
+                               15, 21, 6, 10, 15, 21, 6, 10, 15, 21];
+
+/// MD5 digest of `data`. See RFC 1321.
+fn md5(data: &[u8]) -> [u8; 16] {
+    let constants: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bit_length = (data.len() as u64).wrapping_mul(8);
+    message.extend_from_slice(&bit_length.to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (s, n) in state.iter_mut().zip([a, b, c, d].iter()) {
+            *s = s.wrapping_add(*n);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, s) in digest.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&s.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENDED: &str = "<x:xmpmeta>extended properties</x:xmpmeta>";
+    const GUID: &str = "834FD4032D70B0C2452CC66F101E23F7";
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The chunk of `EXTENDED` from `start` to `end`, as an APP1 segment would hold it.
+    fn chunk(guid: &str, start: usize, end: usize) -> ExtendedXmpChunk {
+        let mut segment = EXTENDED_XMP_IDENTIFIER.to_vec();
+        segment.extend_from_slice(guid.as_bytes());
+        segment.extend_from_slice(&(EXTENDED.len() as u32).to_be_bytes());
+        segment.extend_from_slice(&(start as u32).to_be_bytes());
+        segment.extend_from_slice(&EXTENDED.as_bytes()[start..end]);
+        ExtendedXmpChunk::parse(&segment).unwrap()
+    }
+
+    #[test]
+    fn md5_digests() {
+        // RFC 1321, appendix A.5
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(md5(b"1234567890123456789012345678901234567890\
+                              1234567890123456789012345678901234567890")),
+                   "57edf4a22be3c955ac49da2e2107b67a");
+    }
+
+    #[test]
+    fn guid_as_attribute_or_element() {
+        let attribute = format!("<rdf:Description xmpNote:HasExtendedXMP=\"{}\"/>", GUID);
+        assert_eq!(extended_guid(&attribute), Some(GUID.to_string()));
+        let element = format!("<xmpNote:HasExtendedXMP>{}</xmpNote:HasExtendedXMP>", GUID);
+        assert_eq!(extended_guid(&element), Some(GUID.to_string()));
+        assert_eq!(extended_guid("<xmpNote:HasExtendedXMP>nothex</xmpNote:HasExtendedXMP>"),
+                   None);
+    }
+
+    #[test]
+    fn assemble_chunks_in_any_order() {
+        let other = chunk("00000000000000000000000000000000", 0, 10);
+        let chunks = [chunk(GUID, 20, EXTENDED.len()), other, chunk(GUID, 0, 20)];
+        assert_eq!(chunks[0].offset, 20);
+        assert_eq!(assemble_extended(GUID, &chunks).unwrap(), EXTENDED);
+    }
+
+    #[test]
+    fn assemble_broken() {
+        // Missing chunk
+        assert!(assemble_extended(GUID, &[chunk(GUID, 0, 20)]).is_err());
+        // Wrong digest
+        let guid = "834FD4032D70B0C2452CC66F101E23F8";
+        assert!(assemble_extended(guid, &[chunk(guid, 0, EXTENDED.len())]).is_err());
+    }
+}
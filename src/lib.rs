@@ -0,0 +1,4 @@
+#[allow(unused_variables)]
+#[allow(dead_code)]
+mod transform;
+pub mod jpeg;
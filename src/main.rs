@@ -1,38 +1,171 @@
-#[allow(unused_variables)]
-#[allow(dead_code)]
-mod transform;
-mod jpeg;
+extern crate jpeg_rust;
+
+mod info;
 
 use std::env;
+use std::fmt::Display;
 use std::fs::File;
-use std::io::Read;
-use std::io::Write;
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
+use std::process;
 
-use jpeg::jpeg::*;
+use jpeg_rust::jpeg::color::PixelFormat;
+use jpeg_rust::jpeg::jpeg::*;
+use jpeg_rust::jpeg::pnm;
+use jpeg_rust::jpeg::probe;
+use jpeg_rust::jpeg::writer::{JPEGFile, OwnedSegment, StripOptions};
 
 fn file_to_bytes(path: &Path) -> Result<Vec<u8>, std::io::Error> {
     File::open(path).and_then(|mut file| {
         let mut bytes = Vec::new();
-        try!(file.read_to_end(&mut bytes));
+        file.read_to_end(&mut bytes)?;
         Ok(bytes)
     })
 }
 
+/// Print `error` about the file at `path`, and exit with status 1.
+fn fail<E: Display>(path: &str, error: E) -> ! {
+    eprintln!("{}: {}", path, error);
+    process::exit(1);
+}
+
+/// Print `message` and how to run the program, and exit with status 1.
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("Usage: jpeg-rust [--lenient] [--icc FILE] INPUT OUTPUT");
+    eprintln!("       jpeg-rust [--strip-icc] [--comment TEXT] strip INPUT OUTPUT");
+    eprintln!("       jpeg-rust [--comment TEXT] set-meta INPUT OUTPUT");
+    eprintln!("       jpeg-rust [--json] info INPUT");
+    eprintln!("OUTPUT is written as binary PNM, or as PAM if it ends with .pam.");
+    process::exit(1);
+}
+
+/// Copy `input_file` to `output_file`, changing only the metadata segments:
+/// remove them as `strip` says, and replace the comments with `comment`.
+fn rewrite_metadata(input_file: &str,
+                    output_file: &str,
+                    strip: Option<StripOptions>,
+                    comment: Option<String>) {
+    let bytes = file_to_bytes(Path::new(input_file)).unwrap_or_else(|e| fail(input_file, e));
+    let mut jpeg_file = JPEGFile::parse(&bytes).unwrap_or_else(|e| fail(input_file, e));
+    if let Some(strip) = strip {
+        jpeg_file.strip_metadata(&strip);
+    }
+    if let Some(comment) = comment {
+        jpeg_file.retain(|segment| segment.marker() != Some(0xfe));
+        let index = jpeg_file.header_end();
+        let segment = OwnedSegment::new(0xfe, comment.as_bytes())
+            .unwrap_or_else(|e| fail(input_file, e));
+        jpeg_file.insert(index, segment);
+    }
+    let written = File::create(output_file).and_then(|mut file| jpeg_file.write_to(&mut file));
+    if let Err(error) = written {
+        fail(output_file, error);
+    }
+}
+
 fn main() {
     let mut args = env::args();
     args.next();
-    let input_file = args.next().expect("Must supply an input file");
-    let output_file = args.next().expect("Must supply an output file");
-
-    let bytes = file_to_bytes(Path::new(&input_file)).unwrap();
-    let image = JPEGImage::parse(bytes).unwrap();
-    // Show the image, somehow.
-
-    let mut file = File::create(output_file).unwrap();
-    let _ = file.write(format!("P3\n{} {}\n255\n", image.width(), image.height()).as_bytes());
-    for &(r, g, b) in image.image_data().unwrap() {
-        let s = format!("{} {} {}\n", r, g, b);
-        let _ = file.write(s.as_bytes());
+
+    // Positional arguments, INPUT OUTPUT to write the image as binary PNM,
+    // or as PAM if OUTPUT ends with `.pam`, and options:
+    //  `--icc FILE` to save the embedded ICC profile,
+    //  `--strip-icc` to also remove the ICC profile in `strip` mode,
+    //  `--comment TEXT` to set the comment in `strip` and `set-meta` modes,
+    //  `--lenient` to decode what can be decoded of corrupt data,
+    //  `--json` to print the `info` report as JSON.
+    let mut positional = Vec::new();
+    let mut icc_file = None;
+    let mut strip_icc = false;
+    let mut comment = None;
+    let mut lenient = false;
+    let mut json = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--icc" => icc_file = args.next().or_else(|| usage("Must supply a file for --icc")),
+            "--strip-icc" => strip_icc = true,
+            "--lenient" => lenient = true,
+            "--json" => json = true,
+            "--comment" => {
+                comment = args.next().or_else(|| usage("Must supply text for --comment"))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let mode = positional.next().unwrap_or_else(|| usage("Must supply an input file"));
+    if mode == "strip" || mode == "set-meta" {
+        let input_file = positional.next().unwrap_or_else(|| usage("Must supply an input file"));
+        let output_file = positional.next().unwrap_or_else(|| usage("Must supply an output file"));
+        let strip = if mode == "strip" {
+            Some(StripOptions::new().icc(strip_icc))
+        } else {
+            None
+        };
+        rewrite_metadata(&input_file, &output_file, strip, comment);
+        return;
+    }
+    if mode == "info" {
+        let input_file = positional.next().unwrap_or_else(|| usage("Must supply an input file"));
+        let bytes = file_to_bytes(Path::new(&input_file)).unwrap_or_else(|e| fail(&input_file, e));
+        let report = info::report(&input_file, bytes);
+        if json {
+            println!("{}", report.to_json());
+        } else {
+            print!("{}", report.to_text());
+        }
+        return;
+    }
+    let input_file = mode;
+    let output_file = positional.next().unwrap_or_else(|| usage("Must supply an output file"));
+
+    let bytes = file_to_bytes(Path::new(&input_file)).unwrap_or_else(|e| fail(&input_file, e));
+    // PAM keeps CMYK, which PNM cannot hold, and gray stays one channel.
+    let pam = output_file.ends_with(".pam");
+    let components = probe::probe(&mut &bytes[..]).map(|info| info.components().len());
+    let pixel_format = match components {
+        Ok(1) => PixelFormat::Gray,
+        Ok(4) if pam => PixelFormat::Cmyk,
+        _ => PixelFormat::Rgb,
+    };
+    let options = DecodeOptions::new().lenient(lenient).pixel_format(pixel_format);
+    let image = JPEGImage::parse_with_options(bytes, &options)
+        .unwrap_or_else(|e| fail(&input_file, e));
+    for incident in image.incidents() {
+        eprintln!("{}: {} at offset {}, lost {} MCUs from MCU {}",
+                  input_file,
+                  incident.message,
+                  incident.offset,
+                  incident.lost_mcus,
+                  incident.mcu);
+    }
+    if image.is_incomplete() {
+        eprintln!("{}: the file ends before all of the image data", input_file);
+    }
+    let data = image.image_data().unwrap_or_else(|| fail(&input_file, "The file has no image"));
+    let written = File::create(&output_file).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        if pam {
+            pnm::write_pam(&mut writer, image.width(), image.height(), pixel_format, data)?;
+        } else {
+            pnm::write_pnm(&mut writer, image.width(), image.height(), pixel_format, data)?;
+        }
+        writer.flush()
+    });
+    if let Err(error) = written {
+        fail(&output_file, error);
+    }
+
+    if let Some(icc_file) = icc_file {
+        match image.icc_profile() {
+            Some(profile) => {
+                let written = File::create(&icc_file).and_then(|mut file| file.write_all(profile));
+                if let Err(error) = written {
+                    fail(&icc_file, error);
+                }
+            }
+            None => eprintln!("{} has no ICC profile", input_file),
+        }
     }
 }
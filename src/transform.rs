@@ -13,9 +13,9 @@ fn usize_square(n: usize) -> Option<usize> {
 }
 
 #[allow(non_upper_case_globals)]
-const Pi: f32 = PI as f32;
+const Pi: f32 = PI;
 
-pub fn discrete_cosine_transform(input: &Vec<f32>) -> Vec<f32> {
+pub fn discrete_cosine_transform(input: &[f32]) -> Vec<f32> {
     let alpha = |u| {
         if u == 0 {
             1f32 / 2f32.sqrt()
@@ -35,7 +35,7 @@ pub fn discrete_cosine_transform(input: &Vec<f32>) -> Vec<f32> {
             for y in 0..d {
                 for x in 0..d {
                     let xy_index = y * d + x;
-                    let gxy = input[xy_index] as f32;
+                    let gxy = input[xy_index];
 
                     let yf = y as f32;
                     let xf = x as f32;
@@ -52,7 +52,7 @@ pub fn discrete_cosine_transform(input: &Vec<f32>) -> Vec<f32> {
     vec
 }
 
-pub fn discrete_cosine_transform_inverse(input: &Vec<f32>) -> Vec<f32> {
+pub fn discrete_cosine_transform_inverse(input: &[f32]) -> Vec<f32> {
     let alpha = |u| {
         if u == 0 {
             1f32 / 2f32.sqrt()